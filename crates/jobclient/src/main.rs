@@ -1,18 +1,34 @@
-use clap::{AppSettings, Parser, Subcommand};
+use anyhow::Context;
+use clap::{AppSettings, ArgEnum, Parser, Subcommand};
+use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use std::io::{self, Write};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::transport::{Channel, ClientTlsConfig};
+use tonic::Code;
 
 use grpc_jobruntime::job_runtime_client::JobRuntimeClient;
 use grpc_jobruntime::{
-    JobLogsRequest, JobResourceLimits, JobStartRequest, JobStatusRequest, JobStopRequest,
+    attach_job_request, job_event, job_status_response, schedule_info, schedule_job_request,
+    AttachJobRequest, BatchRequest, BatchStartRequest, BatchStartResponse, CertificateInfo,
+    DependencyCondition, GroupRequest, GroupStatusResponse, JobDeleteRequest, JobDependency,
+    JobLogsRequest, JobResourceLimits, JobStartRequest, JobStatusRequest, JobStdinRequest,
+    JobStopRequest, JobWaitRequest, ListGroupsRequest, ListSchedulesRequest, LogStream,
+    OverlapPolicy, PruneJobsRequest, QuotaUsageRequest, ResourceUsage, ScheduleInfo,
+    ScheduleJobRequest, ScheduleRequest, ServerInfoRequest, SignalGroupRequest, StepDependency,
+    TerminalSize, WatchJobsRequest, WorkflowRequest, WorkflowStep,
 };
 
 pub mod grpc_jobruntime {
     tonic::include_proto!("jobruntime");
 }
 
+mod terminal;
 mod tls;
 
 #[derive(Parser)]
@@ -26,6 +42,9 @@ struct Cli {
     ca_cert: PathBuf,
     #[clap(long)]
     addr: String,
+    /// Warn when the client certificate expires in less than that many days
+    #[clap(long, default_value = "30")]
+    cert_expiry_warning_days: u64,
 
     #[clap(subcommand)]
     command: Commands,
@@ -40,19 +59,386 @@ enum Commands {
 
         #[clap(flatten)]
         limits: ResourceLimits,
+
+        #[clap(flatten)]
+        scheduling: Scheduling,
+
+        #[clap(flatten)]
+        dependencies: Dependencies,
+
+        /// Forward local stdin to the job until EOF
+        #[clap(long)]
+        stdin: bool,
+
+        /// Run the job under a pseudo-terminal, use `attach` to interact with it
+        #[clap(long)]
+        tty: bool,
+
+        /// Label the job, can be repeated
+        #[clap(long = "label", value_name = "KEY=VALUE", parse(try_from_str = parse_key_value))]
+        labels: Vec<(String, String)>,
+
+        #[clap(flatten)]
+        env: Environment,
+
+        #[clap(flatten)]
+        group: GroupOptions,
     },
+    /// Connect the local terminal to a running job, press Ctrl-P Ctrl-Q to detach
+    Attach {
+        uuid: String,
+    },
+    /// Start a job, stream its output and exit with its exit code.
+    /// Ctrl-C stops the job.
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Run {
+        #[clap(required = true)]
+        args: Vec<String>,
+
+        #[clap(flatten)]
+        limits: ResourceLimits,
+
+        #[clap(flatten)]
+        scheduling: Scheduling,
+
+        #[clap(flatten)]
+        dependencies: Dependencies,
+
+        #[clap(flatten)]
+        env: Environment,
+
+        #[clap(flatten)]
+        group: GroupOptions,
+
+        /// How many times in a row to reconnect after the log stream breaks
+        #[clap(long, default_value = "10")]
+        max_retries: u32,
+    },
+    /// Start the steps of a workflow described in a TOML file, prints the job of every step.
+    /// Steps wait for the steps listed in their `after` and `after_finished`.
+    Workflow {
+        file: PathBuf,
+
+        /// Wait until all steps are done and exit with the first non-zero exit code
+        #[clap(long)]
+        wait: bool,
+    },
+    /// Wait until all jobs are done and exit with the first non-zero exit code
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Wait {
+        #[clap(required = true)]
+        uuids: Vec<String>,
+
+        /// Give up after that many seconds and exit with code 124
+        #[clap(long)]
+        timeout: Option<u64>,
+    },
+    /// Print lifecycle events of jobs as they happen
+    Watch {
+        /// Only events of this job, can be repeated
+        #[clap(long = "job", value_name = "UUID")]
+        uuids: Vec<String>,
+
+        /// Only events of jobs started by this user
+        #[clap(long)]
+        owner: Option<String>,
+
+        /// Only events of jobs with this label, can be repeated
+        #[clap(long = "label", value_name = "KEY=VALUE", parse(try_from_str = parse_key_value))]
+        labels: Vec<(String, String)>,
+    },
+    #[clap(alias = "logs")]
     FetchLogs {
         uuid: String,
+
+        /// How many times in a row to reconnect after the stream breaks
+        #[clap(long, default_value = "10")]
+        max_retries: u32,
     },
     Stop {
         uuid: String,
     },
+    /// Delete a job that has ended together with its logs
+    Rm {
+        uuid: String,
+    },
+    /// Delete jobs that have ended, by default all of the caller's
+    Prune {
+        /// Only jobs that ended at least that many seconds ago
+        #[clap(long)]
+        older_than: Option<u64>,
+
+        /// Jobs of all users, not only the caller's
+        #[clap(long)]
+        all: bool,
+    },
     Status {
         uuid: String,
     },
+    /// Show how long the server and the client certificates are valid
+    ServerInfo,
+    /// Show resources taken by unfinished jobs and the quota
+    Usage {
+        /// Another user than the caller
+        #[clap(long)]
+        user: Option<String>,
+    },
+    /// Run jobs at a given time or repeatedly on a cron schedule
+    #[clap(subcommand)]
+    Schedule(ScheduleCommands),
+    /// Start many jobs at once and stop or wait for them as a group
+    #[clap(subcommand)]
+    Batch(BatchCommands),
+    /// Manage jobs started with --group together
+    #[clap(subcommand)]
+    Group(GroupCommands),
+}
+
+#[derive(Subcommand)]
+enum GroupCommands {
+    /// Show groups and how many of their jobs are in each state
+    List,
+    /// Show the state of every job of the group
+    Status { name: String },
+    /// Stop all jobs of the group that haven't ended yet
+    Stop { name: String },
+    /// Send a signal to every running job of the group
+    Signal {
+        name: String,
+
+        /// Number or name of the signal, e.g. 15 or TERM
+        #[clap(parse(try_from_str = parse_signal))]
+        signal: i32,
+    },
+}
+
+// parsed once from the command line, the size of the variants doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum BatchCommands {
+    /// Start the jobs listed in a TOML file, prints the id of the batch and the jobs
+    Start {
+        file: PathBuf,
+
+        /// Start either all jobs or none of them
+        #[clap(long)]
+        atomic: bool,
+    },
+    /// Start `count` copies of a job, "{index}" in the arguments is replaced with 0..count
+    /// and JOB_ARRAY_INDEX is set in the environment
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Array {
+        #[clap(required = true)]
+        args: Vec<String>,
+
+        #[clap(long)]
+        count: u32,
+
+        /// Start either all jobs or none of them
+        #[clap(long)]
+        atomic: bool,
+
+        #[clap(flatten)]
+        limits: ResourceLimits,
+
+        #[clap(flatten)]
+        scheduling: Scheduling,
+
+        #[clap(flatten)]
+        env: Environment,
+
+        #[clap(flatten)]
+        group: GroupOptions,
+
+        /// Label the jobs, can be repeated
+        #[clap(long = "label", value_name = "KEY=VALUE", parse(try_from_str = parse_key_value))]
+        labels: Vec<(String, String)>,
+    },
+    /// Stop all jobs of the batch that haven't ended yet
+    Stop { id: String },
+    /// Wait until all jobs of the batch are done and exit with the first non-zero exit code
+    Wait {
+        id: String,
+
+        /// Give up after that many seconds and exit with code 124
+        #[clap(long)]
+        timeout: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ScheduleCommands {
+    /// Schedule a job, prints the id of the schedule
+    #[clap(setting(AppSettings::ArgRequiredElseHelp))]
+    Add {
+        #[clap(required = true)]
+        args: Vec<String>,
+
+        /// Five field cron expression in UTC, e.g. "*/15 9-17 * * 1-5", or @hourly, @daily, ...
+        #[clap(long, required_unless_present = "at", conflicts_with = "at")]
+        cron: Option<String>,
+
+        /// Run once at this time, RFC 3339 e.g. 2022-03-01T12:00:00Z
+        #[clap(long, parse(try_from_str = parse_time))]
+        at: Option<i64>,
+
+        /// What to do when a run is due while the previous run is still going
+        #[clap(long, arg_enum, default_value = "skip")]
+        overlap: Overlap,
+
+        #[clap(flatten)]
+        limits: ResourceLimits,
+
+        #[clap(flatten)]
+        scheduling: Scheduling,
+
+        /// Label the jobs, can be repeated
+        #[clap(long = "label", value_name = "KEY=VALUE", parse(try_from_str = parse_key_value))]
+        labels: Vec<(String, String)>,
+    },
+    List,
+    Pause {
+        id: String,
+    },
+    Resume {
+        id: String,
+    },
+    /// Delete the schedule, jobs it already started keep running
+    Delete {
+        id: String,
+    },
+}
+
+#[derive(ArgEnum, Clone)]
+enum Overlap {
+    Skip,
+    Queue,
+    Allow,
+}
+
+impl From<Overlap> for OverlapPolicy {
+    fn from(overlap: Overlap) -> Self {
+        match overlap {
+            Overlap::Skip => OverlapPolicy::Skip,
+            Overlap::Queue => OverlapPolicy::Queue,
+            Overlap::Allow => OverlapPolicy::Allow,
+        }
+    }
+}
+
+// Where the job goes when the daemon already runs as many jobs as it allows
+#[derive(Parser)]
+struct Scheduling {
+    /// Waiting jobs with a higher priority are started first
+    #[clap(long, default_value = "0", allow_hyphen_values = true)]
+    priority: i32,
+    /// Concurrency pool configured on the daemon
+    #[clap(long)]
+    pool: Option<String>,
+}
+
+#[derive(Parser)]
+struct Environment {
+    /// Set an environment variable of the job, can be repeated
+    #[clap(long = "env", value_name = "KEY=VALUE", number_of_values = 1, parse(try_from_str = parse_key_value))]
+    env: Vec<(String, String)>,
+}
+
+#[derive(Parser)]
+struct GroupOptions {
+    /// Join this group, it's created with its first job
+    #[clap(long)]
+    group: Option<String>,
+    /// Replace the limits shared by all jobs of the group
+    #[clap(name = "group_memory_high", long, requires = "group")]
+    group_memory_high: Option<u64>,
+    #[clap(name = "group_memory_max", long, requires = "group")]
+    group_memory_max: Option<u64>,
+    #[clap(name = "group_cpu_weight", long, requires = "group")]
+    group_cpu_weight: Option<u32>,
+    #[clap(name = "group_cpu_max", long, requires = "group")]
+    group_cpu_max: Option<u32>,
+    #[clap(name = "group_io_weight", long, requires = "group")]
+    group_io_weight: Option<u32>,
+}
+
+impl GroupOptions {
+    fn limits(&self) -> Option<JobResourceLimits> {
+        ResourceLimits {
+            memory_high: self.group_memory_high,
+            memory_max: self.group_memory_max,
+            cpu_weight: self.group_cpu_weight,
+            cpu_max: self.group_cpu_max,
+            io_weight: self.group_io_weight,
+        }
+        .into()
+    }
+}
+
+fn parse_signal(signal: &str) -> Result<i32, String> {
+    if let Ok(number) = signal.parse() {
+        return Ok(number);
+    }
+    let name = signal.to_uppercase();
+    match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => Ok(libc::SIGHUP),
+        "INT" => Ok(libc::SIGINT),
+        "QUIT" => Ok(libc::SIGQUIT),
+        "KILL" => Ok(libc::SIGKILL),
+        "USR1" => Ok(libc::SIGUSR1),
+        "USR2" => Ok(libc::SIGUSR2),
+        "TERM" => Ok(libc::SIGTERM),
+        "CONT" => Ok(libc::SIGCONT),
+        "STOP" => Ok(libc::SIGSTOP),
+        _ => Err(format!("unknown signal {}", signal)),
+    }
+}
+
+fn print_group(group: &GroupStatusResponse) {
+    let summary = group.summary.clone().unwrap_or_default();
+    println!(
+        "{} {} pending={} running={} succeeded={} failed={}",
+        group.name,
+        group.owner,
+        summary.pending,
+        summary.running,
+        summary.succeeded,
+        summary.failed
+    );
+    for job in &group.jobs {
+        println!("    {} - {:?}", job.uuid, job.status);
+    }
 }
 
+// Jobs that have to end before the job starts
 #[derive(Parser)]
+struct Dependencies {
+    /// Start after this job succeeds, can be repeated. The job is cancelled if it fails.
+    #[clap(long = "after", value_name = "UUID", number_of_values = 1)]
+    after: Vec<String>,
+    /// Start after this job ends in any way, can be repeated
+    #[clap(long = "after-finished", value_name = "UUID", number_of_values = 1)]
+    after_finished: Vec<String>,
+}
+
+impl From<Dependencies> for Vec<JobDependency> {
+    fn from(dependencies: Dependencies) -> Self {
+        let after = dependencies.after.into_iter().map(|uuid| JobDependency {
+            uuid,
+            condition: DependencyCondition::Success as i32,
+        });
+        let after_finished = dependencies
+            .after_finished
+            .into_iter()
+            .map(|uuid| JobDependency {
+                uuid,
+                condition: DependencyCondition::Finished as i32,
+            });
+        after.chain(after_finished).collect()
+    }
+}
+
+#[derive(Parser, Deserialize)]
 struct ResourceLimits {
     #[clap(name = "memory_high", long)]
     memory_high: Option<u64>,
@@ -87,15 +473,753 @@ impl From<ResourceLimits> for Option<JobResourceLimits> {
     }
 }
 
+// The server rejects expired certificates anyway, warning early gives time to renew it
+fn warn_if_certificate_expires(cert: &[u8], warning_days: u64) -> anyhow::Result<()> {
+    let certs = pki::read_certs(cert).context("unable to read certificate")?;
+    let validity = pki::CertificateValidity::of(&certs[0].0)?;
+    if let Err(err) = validity.ensure_valid() {
+        eprintln!("warning: {}", err);
+    } else if validity.expires_within(warning_days) {
+        eprintln!(
+            "warning: certificate {} expires in {} days on {}",
+            validity.subject,
+            validity.days_left(),
+            pki::format_timestamp(validity.not_after)
+        );
+    }
+    Ok(())
+}
+
+fn print_usage(name: &str, usage: Option<&ResourceUsage>) {
+    match usage {
+        Some(usage) if usage.limited => println!("{}: {} / {}", name, usage.used, usage.limit),
+        Some(usage) => println!("{}: {} / unlimited", name, usage.used),
+        None => println!("{}: unknown", name),
+    }
+}
+
+fn print_certificate(name: &str, info: Option<&CertificateInfo>) {
+    match info {
+        Some(info) => {
+            let validity = pki::CertificateValidity {
+                subject: info.subject.clone(),
+                not_before: info.not_before,
+                not_after: info.not_after,
+            };
+            println!(
+                "{} certificate: {}, valid from {} until {} ({} days left)",
+                name,
+                validity.subject,
+                pki::format_timestamp(validity.not_before),
+                pki::format_timestamp(validity.not_after),
+                validity.days_left()
+            );
+        }
+        None => println!("{} certificate: unknown", name),
+    }
+}
+
+fn parse_time(time: &str) -> Result<i64, String> {
+    chrono::DateTime::parse_from_rfc3339(time)
+        .map(|time| time.timestamp())
+        .map_err(|err| format!("expected RFC 3339 time, got {}: {}", time, err))
+}
+
+fn print_schedule(schedule: &ScheduleInfo) {
+    let when = match &schedule.when {
+        Some(schedule_info::When::Cron(cron)) => format!("cron \"{}\"", cron),
+        Some(schedule_info::When::RunAt(time)) => format!("at {}", pki::format_timestamp(*time)),
+        None => String::from("never"),
+    };
+    let next_run = match schedule.next_run {
+        0 => String::from("none"),
+        time => pki::format_timestamp(time),
+    };
+    println!(
+        "{} {} {} overlap={:?}{} next run: {}",
+        schedule.id,
+        schedule.owner,
+        when,
+        schedule.overlap(),
+        if schedule.paused { " paused" } else { "" },
+        next_run
+    );
+    println!("    {}", schedule.args.join(" "));
+    if !schedule.last_job.is_empty() {
+        println!("    last job: {}", schedule.last_job);
+    }
+    if !schedule.last_error.is_empty() {
+        println!("    last error: {}", schedule.last_error);
+    }
+}
+
+// A job in a batch file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileJob {
+    args: Vec<String>,
+    limits: Option<ResourceLimits>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(default)]
+    priority: i32,
+    pool: Option<String>,
+    group: Option<String>,
+}
+
+impl From<FileJob> for JobStartRequest {
+    fn from(job: FileJob) -> Self {
+        JobStartRequest {
+            args: job.args,
+            limits: job.limits.and_then(Into::into),
+            labels: job.labels,
+            env: job.env,
+            priority: job.priority,
+            pool: job.pool.unwrap_or_default(),
+            group: job.group.unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+}
+
+// A step of a workflow file, e.g.
+//
+// [[step]]
+// name = "test"
+// args = ["cargo", "test"]
+// after = ["build"]
+//
+// The fields of the job are repeated rather than flattened, serde can't deny unknown fields otherwise
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WorkflowFileStep {
+    name: String,
+    #[serde(default)]
+    after: Vec<String>,
+    #[serde(default)]
+    after_finished: Vec<String>,
+    args: Vec<String>,
+    limits: Option<ResourceLimits>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(default)]
+    priority: i32,
+    pool: Option<String>,
+    group: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WorkflowFile {
+    #[serde(rename = "step")]
+    steps: Vec<WorkflowFileStep>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchFile {
+    #[serde(rename = "job")]
+    jobs: Vec<FileJob>,
+}
+
+fn read_toml<T: serde::de::DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("unable to read {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("invalid file {}", path.display()))
+}
+
+fn read_workflow(path: &Path) -> anyhow::Result<Vec<WorkflowStep>> {
+    let file: WorkflowFile = read_toml(path)?;
+    let steps = file
+        .steps
+        .into_iter()
+        .map(|step| {
+            let after = step.after.into_iter().map(|name| StepDependency {
+                step: name,
+                condition: DependencyCondition::Success as i32,
+            });
+            let after_finished = step.after_finished.into_iter().map(|name| StepDependency {
+                step: name,
+                condition: DependencyCondition::Finished as i32,
+            });
+            let job = FileJob {
+                args: step.args,
+                limits: step.limits,
+                labels: step.labels,
+                env: step.env,
+                priority: step.priority,
+                pool: step.pool,
+                group: step.group,
+            };
+            WorkflowStep {
+                name: step.name,
+                job: Some(job.into()),
+                after: after.chain(after_finished).collect(),
+            }
+        })
+        .collect();
+    Ok(steps)
+}
+
+fn parse_key_value(pair: &str) -> Result<(String, String), String> {
+    match pair.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got {}", pair)),
+    }
+}
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+// Only errors caused by the connection itself or by the server dropping a slow reader
+// are worth retrying, everything else (e.g. unknown job, permission denied) won't go away after reconnect.
+fn is_retryable(status: &tonic::Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::Unknown | Code::Cancelled | Code::ResourceExhausted
+    )
+}
+
+// Writes a log record to the local stream matching the one the job used
+fn write_output(stream: LogStream, data: &[u8]) -> io::Result<()> {
+    match stream {
+        LogStream::Stdout => {
+            let current_stdout = io::stdout();
+            let mut handle = current_stdout.lock();
+            handle.write_all(data).and_then(|_| handle.flush())
+        }
+        LogStream::Stderr => {
+            let current_stderr = io::stderr();
+            let mut handle = current_stderr.lock();
+            handle.write_all(data).and_then(|_| handle.flush())
+        }
+    }
+}
+
+// Streams job logs to stdout and stderr. When the stream breaks it reconnects with exponential backoff
+// and resumes from the record after the last one received, so nothing is printed twice.
+async fn follow_logs(
+    client: &mut JobRuntimeClient<Channel>,
+    uuid: String,
+    max_retries: u32,
+) -> anyhow::Result<()> {
+    let mut next_seq = 0;
+    let mut retries = 0;
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        let request = JobLogsRequest {
+            uuid: uuid.clone(),
+            from_seq: next_seq,
+        };
+
+        let result = match client.fetch_job_logs(request).await {
+            Ok(response) => {
+                let mut stream = response.into_inner();
+                loop {
+                    match stream.message().await {
+                        Ok(Some(res)) => {
+                            // the connection works again, so start counting retries from scratch
+                            retries = 0;
+                            backoff = INITIAL_BACKOFF;
+                            if res.missed > 0 {
+                                eprintln!("log stream skipped {} records", res.missed);
+                                continue;
+                            }
+                            next_seq = res.seq + 1;
+                            if let Err(err) = write_output(res.stream(), &res.data) {
+                                eprintln!("error while writing job output: {}", err);
+                            }
+                        }
+                        Ok(None) => break Ok(()),
+                        Err(status) => break Err(status),
+                    }
+                }
+            }
+            Err(status) => Err(status),
+        };
+
+        match result {
+            Ok(()) => return Ok(()),
+            Err(status) if is_retryable(&status) && retries < max_retries => {
+                retries += 1;
+                eprintln!(
+                    "log stream interrupted ({}), reconnecting in {:?} [{}/{}]",
+                    status.message(),
+                    backoff,
+                    retries,
+                    max_retries
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+            Err(status) => return Err(status.into()),
+        }
+    }
+}
+
+const STDIN_CHUNK_SIZE: usize = 16 * 1024;
+
+// Sends local stdin to the job and closes stdin of the job once we reach EOF
+async fn forward_stdin(client: &mut JobRuntimeClient<Channel>, uuid: String) -> anyhow::Result<()> {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let mut uuid = Some(uuid);
+        loop {
+            let mut data = vec![0u8; STDIN_CHUNK_SIZE];
+            let close = match stdin.read(&mut data).await {
+                Ok(0) => true,
+                Ok(n) => {
+                    data.truncate(n);
+                    false
+                }
+                Err(err) => {
+                    eprintln!("error while reading stdin: {}", err);
+                    true
+                }
+            };
+            if close {
+                data.clear();
+            }
+            let request = JobStdinRequest {
+                uuid: uuid.take().unwrap_or_default(),
+                data,
+                close,
+            };
+            if tx.send(request).await.is_err() || close {
+                break;
+            }
+        }
+    });
+
+    client.write_job_stdin(ReceiverStream::new(rx)).await?;
+    Ok(())
+}
+
+async fn watch(
+    client: &mut JobRuntimeClient<Channel>,
+    request: WatchJobsRequest,
+) -> anyhow::Result<()> {
+    let mut events = client.watch_jobs(request).await?.into_inner();
+    while let Some(event) = events.message().await? {
+        if event.missed > 0 {
+            eprintln!("watch stream skipped {} events", event.missed);
+            continue;
+        }
+        let description = match event.event {
+            Some(job_event::Event::Created(_)) => String::from("created"),
+            Some(job_event::Event::Started(pid)) => format!("started pid={}", pid),
+            Some(job_event::Event::Exited(code)) => format!("exited code={}", code),
+            Some(job_event::Event::Killed(signal)) => format!("killed signal={}", signal),
+            Some(job_event::Event::Oom(_)) => String::from("oom"),
+            Some(job_event::Event::Cancelled(cancelled)) => {
+                format!("cancelled reason={}", cancelled.reason)
+            }
+            None => continue,
+        };
+        println!("{} {} {}", event.uuid, event.owner, description);
+    }
+    Ok(())
+}
+
+// Exit code of a shell for a process killed by a signal is 128 + signal number
+const SIGNAL_EXIT_CODE_BASE: i32 = 128;
+// Exit code used by `wait` when the jobs didn't finish in time, the same as timeout(1) uses
+const TIMEOUT_EXIT_CODE: i32 = 124;
+// Exit code of a job that never ran because a job it depended on failed
+const CANCELLED_EXIT_CODE: i32 = 125;
+
+// Waits until the time runs out and returns the exit code the way `wait` does
+async fn with_timeout(
+    timeout: Option<u64>,
+    wait: impl std::future::Future<Output = anyhow::Result<i32>>,
+) -> anyhow::Result<i32> {
+    match timeout {
+        Some(secs) => match tokio::time::timeout(Duration::from_secs(secs), wait).await {
+            Ok(result) => result,
+            Err(_) => {
+                eprintln!("timed out waiting for jobs");
+                Ok(TIMEOUT_EXIT_CODE)
+            }
+        },
+        None => wait.await,
+    }
+}
+
+// Prints the batch id and the jobs, returns whether all of them were started
+fn print_batch(response: &BatchStartResponse) -> bool {
+    println!("{}", response.batch);
+    let mut started = true;
+    for (index, (uuid, error)) in response.uuids.iter().zip(&response.errors).enumerate() {
+        if error.is_empty() {
+            println!("{}", uuid);
+        } else {
+            eprintln!("job {} was rejected: {}", index, error);
+            started = false;
+        }
+    }
+    started
+}
+
+async fn batch(
+    client: &mut JobRuntimeClient<Channel>,
+    command: BatchCommands,
+) -> anyhow::Result<()> {
+    let request = match command {
+        BatchCommands::Start { file, atomic } => {
+            let file: BatchFile = read_toml(&file)?;
+            BatchStartRequest {
+                jobs: file.jobs.into_iter().map(Into::into).collect(),
+                atomic,
+                ..Default::default()
+            }
+        }
+        BatchCommands::Array {
+            args,
+            count,
+            atomic,
+            limits,
+            scheduling,
+            env,
+            group,
+            labels,
+        } => BatchStartRequest {
+            template: Some(JobStartRequest {
+                args,
+                limits: limits.into(),
+                labels: labels.into_iter().collect(),
+                env: env.env.into_iter().collect(),
+                group_limits: group.limits(),
+                group: group.group.unwrap_or_default(),
+                priority: scheduling.priority,
+                pool: scheduling.pool.unwrap_or_default(),
+                ..Default::default()
+            }),
+            count,
+            atomic,
+            ..Default::default()
+        },
+        BatchCommands::Stop { id } => {
+            client.stop_batch(BatchRequest { id }).await?;
+            return Ok(());
+        }
+        BatchCommands::Wait { id, timeout } => {
+            let mut client = client.clone();
+            let wait = async move {
+                let response = client.wait_batch(BatchRequest { id }).await?.into_inner();
+                let mut result = 0;
+                for job in response.jobs {
+                    let code = exit_code(&job.uuid, job.status)?;
+                    if result == 0 {
+                        result = code;
+                    }
+                }
+                Ok(result)
+            };
+            std::process::exit(with_timeout(timeout, wait).await?);
+        }
+    };
+    let response = client.start_batch(request).await?.into_inner();
+    if !print_batch(&response) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn group(
+    client: &mut JobRuntimeClient<Channel>,
+    command: GroupCommands,
+) -> anyhow::Result<()> {
+    match command {
+        GroupCommands::List => {
+            let response = client.list_groups(ListGroupsRequest {}).await?;
+            for group in &response.get_ref().groups {
+                print_group(group);
+            }
+        }
+        GroupCommands::Status { name } => {
+            let response = client.fetch_group_status(GroupRequest { name }).await?;
+            print_group(response.get_ref());
+        }
+        GroupCommands::Stop { name } => {
+            client.stop_group(GroupRequest { name }).await?;
+        }
+        GroupCommands::Signal { name, signal } => {
+            client
+                .signal_group(SignalGroupRequest { name, signal })
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn schedule(
+    client: &mut JobRuntimeClient<Channel>,
+    command: ScheduleCommands,
+) -> anyhow::Result<()> {
+    match command {
+        ScheduleCommands::Add {
+            args,
+            cron,
+            at,
+            overlap,
+            limits,
+            scheduling,
+            labels,
+        } => {
+            let when = match (cron, at) {
+                (Some(cron), _) => schedule_job_request::When::Cron(cron),
+                (None, Some(at)) => schedule_job_request::When::RunAt(at),
+                (None, None) => anyhow::bail!("--cron or --at is required"),
+            };
+            let overlap: OverlapPolicy = overlap.into();
+            let request = ScheduleJobRequest {
+                job: Some(JobStartRequest {
+                    args,
+                    limits: limits.into(),
+                    labels: labels.into_iter().collect(),
+                    priority: scheduling.priority,
+                    pool: scheduling.pool.unwrap_or_default(),
+                    ..Default::default()
+                }),
+                when: Some(when),
+                overlap: overlap as i32,
+            };
+            let response = client.schedule_job(request).await?;
+            println!("{}", response.get_ref().id);
+        }
+        ScheduleCommands::List => {
+            let response = client.list_schedules(ListSchedulesRequest {}).await?;
+            for schedule in &response.get_ref().schedules {
+                print_schedule(schedule);
+            }
+        }
+        ScheduleCommands::Pause { id } => {
+            client.pause_schedule(ScheduleRequest { id }).await?;
+        }
+        ScheduleCommands::Resume { id } => {
+            client.resume_schedule(ScheduleRequest { id }).await?;
+        }
+        ScheduleCommands::Delete { id } => {
+            client.delete_schedule(ScheduleRequest { id }).await?;
+        }
+    }
+    Ok(())
+}
+
+// Waits until the job is done and returns the exit code the way a shell would report it
+async fn wait_for_exit_code(
+    client: &mut JobRuntimeClient<Channel>,
+    uuid: String,
+) -> anyhow::Result<i32> {
+    let response = client
+        .wait_job(JobWaitRequest { uuid: uuid.clone() })
+        .await?
+        .into_inner();
+    exit_code(&uuid, response.status)
+}
+
+fn exit_code(uuid: &str, status: Option<job_status_response::Status>) -> anyhow::Result<i32> {
+    match status {
+        Some(job_status_response::Status::ExitCode(code)) => Ok(code),
+        Some(job_status_response::Status::Signal(signal)) => Ok(SIGNAL_EXIT_CODE_BASE + signal),
+        Some(job_status_response::Status::Cancelled(reason)) => {
+            eprintln!("job {} was cancelled: {}", uuid, reason);
+            Ok(CANCELLED_EXIT_CODE)
+        }
+        Some(job_status_response::Status::Lost(pid)) => Err(anyhow::anyhow!(
+            "job {} was lost when the daemon restarted, its last known pid was {}",
+            uuid,
+            pid
+        )),
+        Some(job_status_response::Status::Pid(_))
+        | Some(job_status_response::Status::QueuePosition(_))
+        | None => Err(anyhow::anyhow!("job {} is still running", uuid)),
+    }
+}
+
+// Waits for all jobs at once and returns the first non-zero exit code in the order
+// the jobs were given, or 0 if all of them succeeded
+async fn wait_all(client: &JobRuntimeClient<Channel>, uuids: Vec<String>) -> anyhow::Result<i32> {
+    let waiters: Vec<_> = uuids
+        .into_iter()
+        .map(|uuid| {
+            let mut client = client.clone();
+            tokio::spawn(async move { wait_for_exit_code(&mut client, uuid).await })
+        })
+        .collect();
+
+    let mut result = 0;
+    for waiter in waiters {
+        let code = waiter.await??;
+        if result == 0 {
+            result = code;
+        }
+    }
+    Ok(result)
+}
+
+// Runs a job as if it was a local process: output goes to stdout and stderr,
+// Ctrl-C stops the job and the exit code of the job is returned
+async fn run(
+    client: &mut JobRuntimeClient<Channel>,
+    request: JobStartRequest,
+    max_retries: u32,
+) -> anyhow::Result<i32> {
+    let uuid = client.start_job(request).await?.into_inner().uuid;
+
+    let mut stop_client = client.clone();
+    let stop_uuid = uuid.clone();
+    let stopper = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("stopping job {}", stop_uuid);
+            let request = JobStopRequest { uuid: stop_uuid };
+            if let Err(status) = stop_client.stop_job(request).await {
+                eprintln!("failed to stop job: {}", status.message());
+            }
+        }
+    });
+
+    // the stream ends once the job is finished, also when it was stopped with Ctrl-C
+    let result = follow_logs(client, uuid.clone(), max_retries).await;
+    stopper.abort();
+    result?;
+
+    wait_for_exit_code(client, uuid).await
+}
+
+// Ctrl-P followed by Ctrl-Q, the same sequence docker uses
+const DETACH_KEYS: [u8; 2] = [0x10, 0x11];
+
+fn resize_request(rows: u16, cols: u16) -> AttachJobRequest {
+    AttachJobRequest {
+        event: Some(attach_job_request::Event::Resize(TerminalSize {
+            rows: rows as u32,
+            cols: cols as u32,
+        })),
+    }
+}
+
+// Splits keystrokes into input for the job and a detach request.
+// The first key of the sequence is held back until we know what follows it.
+fn scan_detach_keys(pending: &mut bool, chunk: &[u8]) -> (Vec<u8>, bool) {
+    let mut input = Vec::with_capacity(chunk.len() + 1);
+    for &byte in chunk {
+        if *pending {
+            *pending = false;
+            if byte == DETACH_KEYS[1] {
+                return (input, true);
+            }
+            input.push(DETACH_KEYS[0]);
+        }
+        if byte == DETACH_KEYS[0] {
+            *pending = true;
+        } else {
+            input.push(byte);
+        }
+    }
+    (input, false)
+}
+
+async fn attach(client: &mut JobRuntimeClient<Channel>, uuid: String) -> anyhow::Result<()> {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+    tx.send(AttachJobRequest {
+        event: Some(attach_job_request::Event::Uuid(uuid)),
+    })
+    .await?;
+    if let Some((rows, cols)) = terminal::window_size() {
+        tx.send(resize_request(rows, cols)).await?;
+    }
+
+    let mut output = client
+        .attach_job(ReceiverStream::new(rx))
+        .await?
+        .into_inner();
+    let raw_mode = terminal::RawMode::enable()?;
+    let (detach_tx, mut detach_rx) = tokio::sync::oneshot::channel();
+
+    // a plain thread instead of tokio::io::stdin, which would keep the runtime
+    // from shutting down while it waits for the next keystroke after the job ended
+    let input_tx = tx.clone();
+    std::thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buf = vec![0u8; STDIN_CHUNK_SIZE];
+        let mut pending = false;
+        loop {
+            let n = match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let (input, detach) = scan_detach_keys(&mut pending, &buf[..n]);
+            if !input.is_empty() {
+                let request = AttachJobRequest {
+                    event: Some(attach_job_request::Event::Data(input)),
+                };
+                if input_tx.blocking_send(request).is_err() {
+                    break;
+                }
+            }
+            if detach {
+                let _ = detach_tx.send(());
+                break;
+            }
+        }
+    });
+
+    let mut window_change = signal(SignalKind::window_change())?;
+    tokio::spawn(async move {
+        while window_change.recv().await.is_some() {
+            if let Some((rows, cols)) = terminal::window_size() {
+                if tx.send(resize_request(rows, cols)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    let result = loop {
+        tokio::select! {
+            message = output.message() => match message {
+                Ok(Some(response)) => {
+                    let current_stdout = io::stdout();
+                    let mut handle = current_stdout.lock();
+                    if let Err(err) = handle.write_all(&response.data).and_then(|_| handle.flush()) {
+                        break Err(err.into());
+                    }
+                }
+                Ok(None) => break Ok(()),
+                Err(status) => break Err(status.into()),
+            },
+            _ = &mut detach_rx => {
+                break Ok(());
+            },
+        }
+    };
+
+    drop(raw_mode);
+    eprintln!();
+    result
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
     // skip parsing for now, assume valid address
-    let cert = fs::read(args.cert)?;
-    let key = fs::read(args.key)?;
-    let ca_cert = fs::read(args.ca_cert)?;
+    let read =
+        |path: &Path| fs::read(path).with_context(|| format!("unable to read {}", path.display()));
+    let cert = read(&args.cert)?;
+    let key = read(Path::new(&args.key))?;
+    let ca_cert = read(&args.ca_cert)?;
+
+    warn_if_certificate_expires(&cert, args.cert_expiry_warning_days)?;
 
-    let client_config = tls::prepare_client_config(cert, key, ca_cert)?;
+    let client_config =
+        tls::prepare_client_config(cert, key, ca_cert).context("invalid TLS configuration")?;
     let client_tls_config = ClientTlsConfig::new().rustls_client_config(client_config);
 
     let channel = Channel::from_shared(args.addr)?
@@ -106,38 +1230,166 @@ async fn main() -> anyhow::Result<()> {
     let mut client = JobRuntimeClient::new(channel);
 
     match args.command {
-        Commands::Start { args, limits } => {
+        Commands::Start {
+            args,
+            limits,
+            scheduling,
+            dependencies,
+            stdin,
+            tty,
+            labels,
+            env,
+            group,
+        } => {
             let request = JobStartRequest {
                 args,
                 limits: limits.into(),
+                stdin,
+                tty,
+                labels: labels.into_iter().collect(),
+                priority: scheduling.priority,
+                pool: scheduling.pool.unwrap_or_default(),
+                after: dependencies.into(),
+                env: env.env.into_iter().collect(),
+                group_limits: group.limits(),
+                group: group.group.unwrap_or_default(),
             };
             let result = client.start_job(request).await?;
-            println!("{}", result.get_ref().uuid);
+            let uuid = result.into_inner().uuid;
+            println!("{}", uuid);
+            if stdin {
+                forward_stdin(&mut client, uuid).await?;
+            }
         }
-        Commands::FetchLogs { uuid } => {
-            let request = JobLogsRequest { uuid };
-            let result = client.fetch_job_logs(request).await?;
-
-            let mut stream = result.into_inner();
-            let current_stdout = io::stdout();
-            let mut handle = current_stdout.lock();
-            while let Some(res) = stream.message().await? {
-                if let Err(err) = handle.write_all(&res.data) {
-                    println!("error while writing to stdout: {}", err);
+        Commands::Run {
+            args,
+            limits,
+            scheduling,
+            dependencies,
+            env,
+            group,
+            max_retries,
+        } => {
+            let request = JobStartRequest {
+                args,
+                limits: limits.into(),
+                priority: scheduling.priority,
+                pool: scheduling.pool.unwrap_or_default(),
+                after: dependencies.into(),
+                env: env.env.into_iter().collect(),
+                group_limits: group.limits(),
+                group: group.group.unwrap_or_default(),
+                ..Default::default()
+            };
+            let code = run(&mut client, request, max_retries).await?;
+            std::process::exit(code);
+        }
+        Commands::Workflow { file, wait } => {
+            let steps = read_workflow(&file)?;
+            let names: Vec<String> = steps.iter().map(|step| step.name.clone()).collect();
+            let response = client
+                .start_workflow(WorkflowRequest { steps })
+                .await?
+                .into_inner();
+            println!("{}", response.workflow);
+            let mut uuids = vec![];
+            for name in names {
+                if let Some(uuid) = response.jobs.get(&name) {
+                    println!("{} {}", name, uuid);
+                    uuids.push(uuid.clone());
                 }
             }
+            if wait {
+                std::process::exit(wait_all(&client, uuids).await?);
+            }
+        }
+        Commands::Wait { uuids, timeout } => {
+            let code = with_timeout(timeout, wait_all(&client, uuids)).await?;
+            std::process::exit(code);
+        }
+        Commands::Watch {
+            uuids,
+            owner,
+            labels,
+        } => {
+            let request = WatchJobsRequest {
+                uuids,
+                owner: owner.unwrap_or_default(),
+                labels: labels.into_iter().collect(),
+            };
+            watch(&mut client, request).await?;
+        }
+        Commands::FetchLogs { uuid, max_retries } => {
+            follow_logs(&mut client, uuid, max_retries).await?;
+        }
+        Commands::Attach { uuid } => {
+            attach(&mut client, uuid).await?;
         }
         Commands::Stop { uuid } => {
             let request = JobStopRequest { uuid };
             client.stop_job(request).await?;
         }
+        Commands::Rm { uuid } => {
+            client.delete_job(JobDeleteRequest { uuid }).await?;
+        }
+        Commands::Prune { older_than, all } => {
+            let request = PruneJobsRequest {
+                older_than_secs: older_than.unwrap_or(0),
+                all_users: all,
+            };
+            let response = client.prune_jobs(request).await?;
+            for uuid in &response.get_ref().uuids {
+                println!("{}", uuid);
+            }
+        }
         Commands::Status { uuid } => {
             let request = JobStatusRequest { uuid };
             let result = client.fetch_job_status(request).await?;
             let job_status = result.get_ref();
             println!("{} - {:?}", job_status.uuid, job_status.status);
+            if !job_status.waiting_for.is_empty() {
+                println!("waiting for: {}", job_status.waiting_for.join(" "));
+            }
+        }
+        Commands::ServerInfo => {
+            let info = client.fetch_server_info(ServerInfoRequest {}).await?;
+            let info = info.get_ref();
+            print_certificate("server", info.server_certificate.as_ref());
+            print_certificate("client", info.client_certificate.as_ref());
+        }
+        Commands::Usage { user } => {
+            let request = QuotaUsageRequest {
+                user: user.unwrap_or_default(),
+            };
+            let usage = client.fetch_quota_usage(request).await?;
+            let usage = usage.get_ref();
+            println!("user: {}", usage.user);
+            print_usage("jobs", usage.jobs.as_ref());
+            print_usage("memory", usage.memory.as_ref());
+            print_usage("cpu", usage.cpu.as_ref());
+            print_usage("starts per minute", usage.starts_per_minute.as_ref());
         }
+        Commands::Schedule(command) => schedule(&mut client, command).await?,
+        Commands::Batch(command) => batch(&mut client, command).await?,
+        Commands::Group(command) => group(&mut client, command).await?,
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unknown_fields_of_workflow_steps_are_rejected() {
+        let step = "[[step]]\nname = \"build\"\nargs = [\"make\"]\npriority = 1\n";
+        let file: WorkflowFile = toml::from_str(step).unwrap();
+        assert_eq!(file.steps[0].priority, 1);
+
+        let typo = format!("{}afer = [\"test\"]\n", step);
+        assert!(toml::from_str::<WorkflowFile>(&typo).is_err());
+        let typo = "[[job]]\nargs = [\"make\"]\npriorty = 1\n";
+        assert!(toml::from_str::<BatchFile>(typo).is_err());
+    }
+}
@@ -0,0 +1,44 @@
+use std::io;
+use std::mem::MaybeUninit;
+
+const STDIN_FD: i32 = 0;
+const STDOUT_FD: i32 = 1;
+
+// Puts the local terminal into raw mode, so every keystroke is sent to the job as is.
+// The previous settings are restored when the guard is dropped.
+pub struct RawMode {
+    original: libc::termios,
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        let mut termios = MaybeUninit::uninit();
+        if unsafe { libc::tcgetattr(STDIN_FD, termios.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = unsafe { termios.assume_init() };
+
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(STDIN_FD, libc::TCSANOW, &raw) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(STDIN_FD, libc::TCSANOW, &self.original) };
+    }
+}
+
+// Returns (rows, cols) of the local terminal
+pub fn window_size() -> Option<(u16, u16)> {
+    let mut size = MaybeUninit::<libc::winsize>::uninit();
+    if unsafe { libc::ioctl(STDOUT_FD, libc::TIOCGWINSZ, size.as_mut_ptr()) } < 0 {
+        return None;
+    }
+    let size = unsafe { size.assume_init() };
+    Some((size.ws_row, size.ws_col))
+}
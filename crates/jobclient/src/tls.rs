@@ -1,5 +1,6 @@
-use tokio_rustls::rustls::{ciphersuite, Certificate, ClientConfig, PrivateKey, ProtocolVersion};
+use tokio_rustls::rustls::{ciphersuite, ClientConfig, ProtocolVersion};
 
+// Certificates and keys can be DER or PEM, the CA file can be a bundle of several CAs
 pub fn prepare_client_config(
     cert: Vec<u8>,
     key: Vec<u8>,
@@ -11,11 +12,9 @@ pub fn prepare_client_config(
         &ciphersuite::TLS13_CHACHA20_POLY1305_SHA256,
     ]);
     config.versions = vec![ProtocolVersion::TLSv1_3];
-    config
-        .root_store
-        .add(&Certificate(ca))
-        .expect("invalid ca certificate");
-    config.set_single_client_cert(vec![Certificate(cert)], PrivateKey(key))?;
+    config.root_store = pki::load_roots(&[ca])?;
+    let (certs, key) = pki::read_identity(&cert, &key)?;
+    config.set_single_client_cert(certs, key)?;
     config.set_protocols(&[b"h2".to_vec()]);
 
     Ok(config)
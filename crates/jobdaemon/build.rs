@@ -1,4 +1,10 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("../../proto/service.proto")?;
+    tonic_build::configure()
+        // limits of started jobs are written to the audit log
+        .type_attribute(
+            "jobruntime.JobResourceLimits",
+            "#[derive(serde::Serialize)]",
+        )
+        .compile(&["../../proto/service.proto"], &["../../proto"])?;
     Ok(())
 }
@@ -0,0 +1,422 @@
+use crate::grpc::grpc_jobruntime::JobResourceLimits;
+use crate::revocation::fingerprint;
+use chrono::{SecondsFormat, Utc};
+use futures::future::BoxFuture;
+use http::Extensions;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use tonic::transport::server::{TcpConnectInfo, TlsConnectInfo};
+use tonic::{Code, Request, Status};
+use tower::{BoxError, Layer, Service};
+
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    // the file is rotated once it grows over this size
+    pub max_bytes: u64,
+    // number of rotated files to keep next to the current one
+    pub max_files: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Allowed,
+    Denied,
+    Error,
+}
+
+// Command of one step of a started workflow
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditStep {
+    pub name: String,
+    pub args: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<JobResourceLimits>,
+}
+
+// One line of the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    pub time: String,
+    pub method: String,
+    pub principal: Option<String>,
+    pub fingerprint: Option<String>,
+    pub peer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workflow: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<JobResourceLimits>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<AuditStep>,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl AuditRecord {
+    // Who is calling is taken from the TLS session, the principal is filled in by the interceptor
+    pub fn new(method: &str, extensions: &Extensions) -> Self {
+        let tls = extensions.get::<TlsConnectInfo<TcpConnectInfo>>();
+        let fingerprint = tls
+            .and_then(|tls| tls.peer_certs())
+            .and_then(|certs| certs.first().map(|cert| fingerprint(cert.as_ref())));
+        let peer = tls
+            .map(|tls| tls.get_ref())
+            .or_else(|| extensions.get::<TcpConnectInfo>())
+            .and_then(|tcp| tcp.remote_addr());
+        Self {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            method: String::from(method),
+            principal: None,
+            fingerprint,
+            peer: peer.map(|addr| addr.to_string()),
+            job: None,
+            schedule: None,
+            batch: None,
+            group: None,
+            workflow: None,
+            args: vec![],
+            limits: None,
+            steps: vec![],
+            outcome: Outcome::Allowed,
+            reason: None,
+        }
+    }
+
+    pub fn set_status(&mut self, status: &Status) {
+        self.outcome = match status.code() {
+            Code::Ok => return,
+            Code::PermissionDenied | Code::Unauthenticated => Outcome::Denied,
+            _ => Outcome::Error,
+        };
+        self.reason = Some(String::from(status.message()));
+    }
+}
+
+// Record of the request being handled, the interceptor and the handlers add what they know about it.
+// It's written by the AuditLayer once the handler has replied.
+#[derive(Clone)]
+pub struct AuditContext(Arc<Mutex<AuditRecord>>);
+
+impl AuditContext {
+    // Requests that didn't pass through the AuditLayer get a context nobody writes
+    pub fn of<T>(request: &Request<T>) -> Self {
+        match request.extensions().get::<AuditContext>() {
+            Some(context) => context.clone(),
+            None => AuditContext::new(AuditRecord::new("", &Extensions::new())),
+        }
+    }
+
+    fn new(record: AuditRecord) -> Self {
+        Self(Arc::new(Mutex::new(record)))
+    }
+
+    fn update(&self, update: impl FnOnce(&mut AuditRecord)) {
+        // a poisoned record only loses details
+        if let Ok(mut record) = self.0.lock() {
+            update(&mut record);
+        }
+    }
+
+    pub fn principal(&self, principal: impl Into<String>) {
+        self.update(|record| record.principal = Some(principal.into()));
+    }
+
+    pub fn job(&self, job: impl Into<String>) {
+        self.update(|record| record.job = Some(job.into()));
+    }
+
+    pub fn schedule(&self, schedule: impl Into<String>) {
+        self.update(|record| record.schedule = Some(schedule.into()));
+    }
+
+    pub fn batch(&self, batch: impl Into<String>) {
+        self.update(|record| record.batch = Some(batch.into()));
+    }
+
+    pub fn group(&self, group: impl Into<String>) {
+        self.update(|record| record.group = Some(group.into()));
+    }
+
+    pub fn workflow(&self, workflow: impl Into<String>) {
+        self.update(|record| record.workflow = Some(workflow.into()));
+    }
+
+    pub fn command(&self, args: &[String], limits: Option<&JobResourceLimits>) {
+        self.update(|record| {
+            record.args = args.to_vec();
+            record.limits = limits.cloned();
+        });
+    }
+
+    pub fn step(&self, name: &str, args: &[String], limits: Option<&JobResourceLimits>) {
+        self.update(|record| {
+            record.steps.push(AuditStep {
+                name: String::from(name),
+                args: args.to_vec(),
+                limits: limits.cloned(),
+            })
+        });
+    }
+
+    fn finish(&self, status: Option<Status>) -> Option<AuditRecord> {
+        let mut record = self.0.lock().ok()?.clone();
+        if let Some(status) = status {
+            record.set_status(&status);
+        }
+        Some(record)
+    }
+}
+
+// Writes every request that reaches the server to the audit log, including the ones the
+// interceptor rejects. The outcome is taken from the status the handler replied with.
+#[derive(Clone)]
+pub struct AuditLayer {
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl AuditLayer {
+    pub fn new(audit: Option<Arc<AuditLog>>) -> Self {
+        Self { audit }
+    }
+}
+
+impl<S> Layer<S> for AuditLayer {
+    type Service = AuditService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuditService {
+            inner,
+            audit: self.audit.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AuditService<S> {
+    inner: S,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl<S, B, R> Service<http::Request<B>> for AuditService<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        let audit = match &self.audit {
+            Some(audit) => audit.clone(),
+            None => {
+                let response = self.inner.call(request);
+                return Box::pin(async move { response.await.map_err(Into::into) });
+            }
+        };
+        // the path is /<package>.<service>/<method>
+        let method = request.uri().path().rsplit('/').next().unwrap_or_default();
+        let context = AuditContext::new(AuditRecord::new(method, request.extensions()));
+        request.extensions_mut().insert(context.clone());
+
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await.map_err(Into::into);
+            // the interceptor fails with the status, a handler that failed replies with it in the headers
+            // and a successful one in the trailers
+            let status = match &response {
+                Ok(response) => Status::from_header_map(response.headers()),
+                Err(err) => Some(match err.downcast_ref::<Status>() {
+                    Some(status) => Status::new(status.code(), status.message()),
+                    None => Status::internal(err.to_string()),
+                }),
+            };
+            if let Some(record) = context.finish(status) {
+                audit.record(&record);
+            }
+            response
+        })
+    }
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+// Append-only JSON lines file, rotated to <path>.1, <path>.2, ... when it gets too big.
+// Records are written by a thread of their own, so requests never wait for the disk.
+pub struct AuditLog {
+    path: PathBuf,
+    lines: Option<mpsc::Sender<Vec<u8>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    pub fn open(config: AuditConfig) -> io::Result<Self> {
+        let file = open_append(&config.path)?;
+        let size = file.metadata()?.len();
+        let path = config.path.clone();
+        let (lines, rx) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name(String::from("audit-log"))
+            .spawn(move || write_lines(config, AuditFile { file, size }, rx))?;
+        Ok(Self {
+            path,
+            lines: Some(lines),
+            writer: Some(writer),
+        })
+    }
+
+    // A failed write doesn't fail the request, it is reported in the daemon log instead
+    pub fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(err) => {
+                log::error!("unable to serialize audit record: {}", err);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let sent = match &self.lines {
+            Some(lines) => lines.send(line).is_ok(),
+            None => false,
+        };
+        if !sent {
+            log::error!(
+                "unable to write audit log {}: writer has stopped",
+                self.path.display()
+            );
+        }
+    }
+}
+
+// Lines recorded before the daemon stops are written before the file is closed
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        self.lines.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_lines(config: AuditConfig, mut current: AuditFile, lines: mpsc::Receiver<Vec<u8>>) {
+    for line in lines {
+        if let Err(err) = write_line(&config, &mut current, &line) {
+            log::error!(
+                "unable to write audit log {}: {}",
+                config.path.display(),
+                err
+            );
+        }
+    }
+}
+
+fn write_line(config: &AuditConfig, current: &mut AuditFile, line: &[u8]) -> io::Result<()> {
+    if current.size > 0 && current.size + line.len() as u64 > config.max_bytes {
+        current.file.sync_all()?;
+        rotate(config)?;
+        *current = AuditFile {
+            file: open_append(&config.path)?,
+            size: 0,
+        };
+    }
+    current.file.write_all(line)?;
+    current.size += line.len() as u64;
+    Ok(())
+}
+
+fn rotated_path(config: &AuditConfig, index: usize) -> PathBuf {
+    let mut path = config.path.clone().into_os_string();
+    path.push(format!(".{}", index));
+    path.into()
+}
+
+fn rotate(config: &AuditConfig) -> io::Result<()> {
+    if config.max_files == 0 {
+        return fs::remove_file(&config.path);
+    }
+    let oldest = rotated_path(config, config.max_files);
+    if oldest.exists() {
+        fs::remove_file(oldest)?;
+    }
+    for index in (1..config.max_files).rev() {
+        let path = rotated_path(config, index);
+        if path.exists() {
+            fs::rename(path, rotated_path(config, index + 1))?;
+        }
+    }
+    fs::rename(&config.path, rotated_path(config, 1))
+}
+
+// Only the daemon user can read the audit log
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn audit_log_is_rotated_when_full() {
+        let dir = std::env::temp_dir().join(format!("jobruntime-audit-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let audit = AuditLog::open(AuditConfig {
+            path: path.clone(),
+            max_bytes: 300,
+            max_files: 2,
+        })
+        .unwrap();
+
+        for i in 0..10 {
+            let mut record = AuditRecord::new("StopJob", &Extensions::new());
+            record.job = Some(i.to_string());
+            record.set_status(&Status::permission_denied("not allowed"));
+            audit.record(&record);
+        }
+        // waits for the writer
+        drop(audit);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let last: serde_json::Value =
+            serde_json::from_str(content.lines().last().unwrap()).unwrap();
+        assert_eq!(last["job"], "9");
+        assert_eq!(last["outcome"], "denied");
+        assert_eq!(last["reason"], "not allowed");
+        assert!(dir.join("audit.log.2").exists());
+        assert!(!dir.join("audit.log.3").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,4 @@
+// Supervisor of a single job, started by jobdaemon when it runs with --shim-dir
+fn main() {
+    std::process::exit(runtime::shim::run(std::env::args_os().collect()));
+}
@@ -1,15 +1,38 @@
-use crate::tls;
-use bytes::Bytes;
+use crate::audit::AuditContext;
+use crate::tls::{self, ReloadableTls};
 use futures::Stream;
 use grpc_jobruntime::job_runtime_server::JobRuntime;
 use grpc_jobruntime::{
-    job_status_response, JobLogsRequest, JobLogsResponse, JobResourceLimits, JobStartRequest,
-    JobStartResponse, JobStatusRequest, JobStatusResponse, JobStopRequest, JobStopResponse,
+    attach_job_request, job_event, job_status_response, schedule_info, schedule_job_request,
+    AttachJobRequest, AttachJobResponse, BatchRequest, BatchStartRequest, BatchStartResponse,
+    BatchStatusResponse, CertificateInfo, DependencyCondition as GrpcDependencyCondition,
+    GroupRequest, GroupResponse, GroupStatusResponse, GroupSummary, JobCancelled, JobCreated,
+    JobDeleteRequest, JobDeleteResponse, JobEvent, JobLogsRequest, JobLogsResponse, JobOom,
+    JobResourceLimits, JobStartRequest, JobStartResponse, JobStatusRequest, JobStatusResponse,
+    JobStdinRequest, JobStdinResponse, JobStopRequest, JobStopResponse, JobWaitRequest,
+    ListGroupsRequest, ListGroupsResponse, ListSchedulesRequest, ListSchedulesResponse, LogStream,
+    OverlapPolicy as GrpcOverlapPolicy, PruneJobsRequest, PruneJobsResponse, QuotaUsageRequest,
+    QuotaUsageResponse, ResourceUsage, ScheduleInfo, ScheduleJobRequest, ScheduleJobResponse,
+    ScheduleRequest, ScheduleResponse, ServerInfoRequest, ServerInfoResponse, SignalGroupRequest,
+    TerminalSize, WatchJobsRequest, WorkflowRequest, WorkflowResponse,
+};
+use pki::CertificateValidity;
+use runtime::auth::Principal;
+use runtime::batch::{self, BatchId};
+use runtime::group::GroupStatus;
+use runtime::quota::QuotaUsage;
+use runtime::schedule::{OverlapPolicy, Schedule, ScheduleId, When};
+use runtime::workflow::{Dependency, DependencyCondition, StepDependency, WorkflowStep};
+use runtime::{
+    limits::ResourceLimits, JobEventKind, JobRequest, JobStatus, LogEntry, LogMessage, LogRecord,
+    RuntimeCommand, RuntimeError, RuntimeSender, ScheduleChange, WatchFilter, WatchMessage,
+    LOG_CHANNEL_SIZE, LOG_SEQ_LATEST, WATCH_CHANNEL_SIZE,
 };
-use runtime::{limits::ResourceLimits, JobStatus, LogRecord, RuntimeCommand, RuntimeSender};
 use std::pin::Pin;
-use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
-use tonic::{Request, Response, Status};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{Request, Response, Status, Streaming};
 use uuid::Uuid;
 
 pub mod grpc_jobruntime {
@@ -20,19 +43,194 @@ pub type Username = String;
 
 pub struct MyJobRuntime {
     cmd_tx: RuntimeSender,
+    tls: Option<Arc<ReloadableTls>>,
 }
 
 impl MyJobRuntime {
     pub fn new(cmd_tx: RuntimeSender) -> Self {
-        Self { cmd_tx }
+        Self { cmd_tx, tls: None }
+    }
+
+    // Pauses, resumes or deletes a schedule, all of them reply the same way
+    async fn update_schedule(
+        &self,
+        request: Request<ScheduleRequest>,
+        change: ScheduleChange,
+    ) -> Result<Response<ScheduleResponse>, Status> {
+        AuditContext::of(&request).schedule(&request.get_ref().id);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::UpdateSchedule {
+            principal,
+            schedule: schedule_id(&request.get_ref().id)?,
+            change,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        rx.await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(ScheduleResponse {}))
+    }
+
+    // Lets FetchServerInfo report the certificate the server is currently using
+    pub fn with_tls(mut self, tls: Arc<ReloadableTls>) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+}
+
+impl From<CertificateValidity> for CertificateInfo {
+    fn from(validity: CertificateValidity) -> Self {
+        CertificateInfo {
+            subject: validity.subject,
+            not_before: validity.not_before,
+            not_after: validity.not_after,
+        }
+    }
+}
+
+fn resource_usage(used: u64, limit: Option<u64>) -> ResourceUsage {
+    ResourceUsage {
+        used,
+        limited: limit.is_some(),
+        limit: limit.unwrap_or_default(),
     }
 }
 
-// TODO: check it
-impl From<Bytes> for JobLogsResponse {
-    fn from(data: Bytes) -> Self {
+impl From<QuotaUsage> for QuotaUsageResponse {
+    fn from(usage: QuotaUsage) -> Self {
+        let QuotaUsage { user, usage, quota } = usage;
+        QuotaUsageResponse {
+            user,
+            jobs: Some(resource_usage(usage.jobs, quota.max_jobs)),
+            memory: Some(resource_usage(usage.memory, quota.max_memory)),
+            cpu: Some(resource_usage(usage.cpu, quota.max_cpu)),
+            starts_per_minute: Some(resource_usage(
+                usage.starts_last_minute,
+                quota.max_starts_per_minute,
+            )),
+        }
+    }
+}
+
+impl From<LogEntry> for JobLogsResponse {
+    fn from(entry: LogEntry) -> Self {
+        let (data, stream) = match entry.record {
+            LogRecord::Stdout(data) => (data, LogStream::Stdout),
+            LogRecord::Stderr(data) => (data, LogStream::Stderr),
+        };
         JobLogsResponse {
             data: data.to_vec(),
+            seq: entry.seq,
+            missed: 0,
+            stream: stream as i32,
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn watch_message_to_event(message: WatchMessage) -> Result<JobEvent, Status> {
+    let event = match message {
+        WatchMessage::Event(event) => event,
+        WatchMessage::Lagged { missed } => {
+            return Ok(JobEvent {
+                missed,
+                ..Default::default()
+            })
+        }
+    };
+    let kind = match event.kind {
+        JobEventKind::Created => job_event::Event::Created(JobCreated {}),
+        JobEventKind::Started { pid } => job_event::Event::Started(pid),
+        JobEventKind::Exited { exit_code } => job_event::Event::Exited(exit_code),
+        JobEventKind::Killed { signal } => job_event::Event::Killed(signal),
+        JobEventKind::Oom => job_event::Event::Oom(JobOom {}),
+        JobEventKind::Cancelled { reason } => job_event::Event::Cancelled(JobCancelled { reason }),
+    };
+    Ok(JobEvent {
+        uuid: event.job.to_simple().to_string(),
+        owner: event.owner,
+        labels: event.labels.as_ref().clone(),
+        event: Some(kind),
+        missed: 0,
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn log_message_to_response(message: LogMessage) -> Result<JobLogsResponse, Status> {
+    match message {
+        LogMessage::Entry(entry) => Ok(entry.into()),
+        LogMessage::Lagged { missed } => Ok(JobLogsResponse {
+            missed,
+            ..Default::default()
+        }),
+        LogMessage::Disconnected { missed } => Err(Status::resource_exhausted(format!(
+            "log consumer is too slow, skipped {} records",
+            missed
+        ))),
+    }
+}
+
+impl From<runtime::JobStatusResponse> for JobStatusResponse {
+    fn from(response: runtime::JobStatusResponse) -> Self {
+        let status = match response.status {
+            JobStatus::Finished { exit_code } => {
+                Some(job_status_response::Status::ExitCode(exit_code))
+            }
+            JobStatus::Running { pid } => Some(job_status_response::Status::Pid(pid)),
+            JobStatus::Killed { signal } => Some(job_status_response::Status::Signal(signal)),
+            JobStatus::Pending => response
+                .queue_position
+                .map(|position| job_status_response::Status::QueuePosition(position as u64)),
+            JobStatus::Cancelled { reason } => Some(job_status_response::Status::Cancelled(reason)),
+            JobStatus::Lost { pid } => Some(job_status_response::Status::Lost(pid)),
+        };
+
+        JobStatusResponse {
+            status,
+            uuid: response.job.to_simple().to_string(),
+            owner: response.owner,
+            waiting_for: response
+                .waiting_for
+                .iter()
+                .map(|job| job.to_simple().to_string())
+                .collect(),
+        }
+    }
+}
+
+impl From<ResourceLimits> for JobResourceLimits {
+    fn from(limits: ResourceLimits) -> Self {
+        JobResourceLimits {
+            memory_high: limits.memory_high.unwrap_or_default(),
+            memory_max: limits.memory_max.unwrap_or_default(),
+            cpu_weight: limits.cpu_weight.unwrap_or_default(),
+            cpu_max: limits.cpu_max.unwrap_or_default(),
+            io_weight: limits.io_weight.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<GroupStatus> for GroupStatusResponse {
+    fn from(status: GroupStatus) -> Self {
+        let summary = status.summary();
+        GroupStatusResponse {
+            summary: Some(GroupSummary {
+                pending: summary.pending as u64,
+                running: summary.running as u64,
+                succeeded: summary.succeeded as u64,
+                failed: summary.failed as u64,
+            }),
+            name: status.name,
+            owner: status.owner,
+            limits: Some(status.limits.into()),
+            jobs: status.jobs.into_iter().map(Into::into).collect(),
         }
     }
 }
@@ -65,56 +263,223 @@ impl From<&JobResourceLimits> for ResourceLimits {
     }
 }
 
-fn extract_username_from_request<T>(request: &Request<T>) -> Result<Username, Status> {
-    match request.extensions().get::<tls::UsernameExtension>() {
-        Some(extension) => Ok(String::from(&extension.username)),
-        None => Err(Status::internal("unable to get username extension")),
+fn runtime_error_to_status(err: RuntimeError) -> Status {
+    match err {
+        RuntimeError::Unauthorized => Status::permission_denied(format!("runtime error: {}", err)),
+        RuntimeError::JobDoesNotExists => Status::not_found(format!("runtime error: {}", err)),
+        RuntimeError::UnknownPool(_)
+        | RuntimeError::InvalidSchedule(_)
+        | RuntimeError::InvalidWorkflow(_)
+        | RuntimeError::InvalidBatch(_)
+        | RuntimeError::InvalidGroup(_)
+        | RuntimeError::InvalidSignal(_) => {
+            Status::invalid_argument(format!("runtime error: {}", err))
+        }
+        RuntimeError::StdinNotOpen | RuntimeError::NotATerminal | RuntimeError::JobNotEnded => {
+            Status::failed_precondition(format!("runtime error: {}", err))
+        }
+        RuntimeError::QuotaExceeded(_) => {
+            Status::resource_exhausted(format!("runtime error: {}", err))
+        }
+        RuntimeError::Io(_) => Status::internal(format!("runtime error: {}", err)),
     }
 }
 
+#[allow(clippy::result_large_err)]
+fn job_request(request: JobStartRequest) -> Result<JobRequest, Status> {
+    let limits = match &request.limits {
+        Some(limits) => limits.into(),
+        None => ResourceLimits::default(),
+    };
+    let pool = match request.pool.as_str() {
+        "" => None,
+        _ => Some(request.pool),
+    };
+    let group = match request.group.as_str() {
+        "" => None,
+        _ => Some(request.group),
+    };
+    let dependencies = request
+        .after
+        .iter()
+        .map(|dependency| {
+            let job = Uuid::parse_str(&dependency.uuid).map_err(|_| {
+                Status::invalid_argument(format!("invalid uuid: {}", dependency.uuid))
+            })?;
+            Ok(Dependency {
+                job,
+                condition: dependency.condition().into(),
+            })
+        })
+        .collect::<Result<_, Status>>()?;
+    let mut args = request.args;
+    let (path, args) = if args.len() >= 2 {
+        let (left, right) = args.split_at_mut(1);
+        (left[0].clone(), Vec::from(right))
+    } else if args.len() == 1 {
+        (args[0].clone(), vec![])
+    } else {
+        return Err(Status::invalid_argument("args must be greater that 0"));
+    };
+
+    Ok(JobRequest {
+        path,
+        args,
+        limits,
+        stdin: request.stdin,
+        tty: request.tty,
+        labels: request.labels,
+        priority: request.priority,
+        pool,
+        dependencies,
+        env: request.env,
+        group,
+        group_limits: request.group_limits.as_ref().map(Into::into),
+    })
+}
+
+async fn resize_terminal(
+    cmd_tx: &RuntimeSender,
+    job: Uuid,
+    principal: Principal,
+    size: TerminalSize,
+) -> Result<(), Status> {
+    let (rows, cols) = match (u16::try_from(size.rows), u16::try_from(size.cols)) {
+        (Ok(rows), Ok(cols)) => (rows, cols),
+        _ => {
+            return Err(Status::invalid_argument(format!(
+                "invalid terminal size: {}x{}",
+                size.rows, size.cols
+            )))
+        }
+    };
+    let (sender, rx) = tokio::sync::oneshot::channel();
+    let cmd = RuntimeCommand::ResizeTerminal {
+        job,
+        principal,
+        rows,
+        cols,
+        sender,
+    };
+    if cmd_tx.send(cmd).await.is_err() {
+        return Err(Status::internal("runtime command channel error"));
+    };
+    match rx
+        .await
+        .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+    {
+        // clients attached from a terminal send their size to jobs without one too
+        Err(RuntimeError::NotATerminal) => Ok(()),
+        result => result.map_err(runtime_error_to_status),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn batch_id(id: &str) -> Result<BatchId, Status> {
+    Uuid::parse_str(id).map_err(|_| Status::invalid_argument(format!("invalid batch id: {}", id)))
+}
+
+impl From<GrpcDependencyCondition> for DependencyCondition {
+    fn from(condition: GrpcDependencyCondition) -> Self {
+        match condition {
+            GrpcDependencyCondition::Success => DependencyCondition::Success,
+            GrpcDependencyCondition::Finished => DependencyCondition::Finished,
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn schedule_id(id: &str) -> Result<ScheduleId, Status> {
+    Uuid::parse_str(id)
+        .map_err(|_| Status::invalid_argument(format!("invalid schedule id: {}", id)))
+}
+
+impl From<OverlapPolicy> for GrpcOverlapPolicy {
+    fn from(overlap: OverlapPolicy) -> Self {
+        match overlap {
+            OverlapPolicy::Skip => GrpcOverlapPolicy::Skip,
+            OverlapPolicy::Queue => GrpcOverlapPolicy::Queue,
+            OverlapPolicy::Allow => GrpcOverlapPolicy::Allow,
+        }
+    }
+}
+
+impl From<GrpcOverlapPolicy> for OverlapPolicy {
+    fn from(overlap: GrpcOverlapPolicy) -> Self {
+        match overlap {
+            GrpcOverlapPolicy::Skip => OverlapPolicy::Skip,
+            GrpcOverlapPolicy::Queue => OverlapPolicy::Queue,
+            GrpcOverlapPolicy::Allow => OverlapPolicy::Allow,
+        }
+    }
+}
+
+impl From<Schedule> for ScheduleInfo {
+    fn from(schedule: Schedule) -> Self {
+        let overlap: GrpcOverlapPolicy = schedule.overlap.into();
+        let mut args = vec![schedule.job.path];
+        args.extend(schedule.job.args);
+        ScheduleInfo {
+            id: schedule.id.to_simple().to_string(),
+            owner: schedule.owner,
+            args,
+            when: Some(match schedule.when {
+                When::Cron(cron) => schedule_info::When::Cron(String::from(cron)),
+                When::At(time) => schedule_info::When::RunAt(time),
+            }),
+            overlap: overlap as i32,
+            paused: schedule.paused,
+            next_run: schedule.next_run.unwrap_or_default(),
+            last_job: schedule
+                .last_job
+                .map(|job| job.to_simple().to_string())
+                .unwrap_or_default(),
+            last_error: schedule.last_error.unwrap_or_default(),
+        }
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn extract_principal_from_request<T>(request: &Request<T>) -> Result<Principal, Status> {
+    match request.extensions().get::<tls::PrincipalExtension>() {
+        Some(extension) => Ok(extension.principal.clone()),
+        None => Err(Status::internal("unable to get principal extension")),
+    }
+}
+
+#[allow(clippy::result_large_err)]
 #[tonic::async_trait]
 impl JobRuntime for MyJobRuntime {
     async fn start_job(
         &self,
         request: Request<JobStartRequest>,
     ) -> Result<Response<JobStartResponse>, Status> {
+        let audit = AuditContext::of(&request);
+        audit.command(&request.get_ref().args, request.get_ref().limits.as_ref());
+        if !request.get_ref().group.is_empty() {
+            audit.group(&request.get_ref().group);
+        }
         let (tx, rx) = tokio::sync::oneshot::channel();
-        let username = extract_username_from_request(&request)?;
-
-        let limits = match &request.get_ref().limits {
-            Some(limits) => limits.into(),
-            None => ResourceLimits::default(),
-        };
-
-        let mut args = request.into_inner().args;
-        let (path, args) = if args.len() >= 2 {
-            let (left, right) = args.split_at_mut(1);
-            (String::from(left[0].clone()), Vec::from(right))
-        } else if args.len() == 1 {
-            (String::from(args[0].clone()), vec![])
-        } else {
-            return Err(Status::invalid_argument("args must be greater that 0"));
-        };
+        let principal = extract_principal_from_request(&request)?;
 
         let cmd = RuntimeCommand::Start {
-            path,
-            args,
-            owner: username,
+            principal,
+            request: job_request(request.into_inner())?,
             sender: tx,
-            limits,
         };
 
-        if let Err(_) = self.cmd_tx.send(cmd) {
+        if self.cmd_tx.send(cmd).await.is_err() {
             return Err(Status::internal("runtime command channel error"));
         };
 
         let result = rx
             .await
-            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?;
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
 
-        Ok(Response::new(JobStartResponse {
-            uuid: result.to_simple().to_string(),
-        }))
+        let uuid = result.to_simple().to_string();
+        audit.job(&uuid);
+        Ok(Response::new(JobStartResponse { uuid }))
     }
 
     type FetchJobLogsStream = Pin<Box<dyn Stream<Item = Result<JobLogsResponse, Status>> + Send>>;
@@ -123,47 +488,60 @@ impl JobRuntime for MyJobRuntime {
         &self,
         request: Request<JobLogsRequest>,
     ) -> Result<Response<Self::FetchJobLogsStream>, Status> {
-        let (sender, rx) = tokio::sync::mpsc::unbounded_channel();
-        let owner = extract_username_from_request(&request)?;
+        AuditContext::of(&request).job(&request.get_ref().uuid);
+        let (sender, rx) = tokio::sync::mpsc::channel(LOG_CHANNEL_SIZE);
+        let (result, result_rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
         let job = Uuid::parse_str(&request.get_ref().uuid).map_err(|_| {
             Status::invalid_argument(format!("invalid uuid: {}", request.get_ref().uuid))
         })?;
-        let cmd = RuntimeCommand::FetchLogs { job, sender, owner };
+        let from_seq = request.get_ref().from_seq;
+        let cmd = RuntimeCommand::FetchLogs {
+            job,
+            principal,
+            from_seq,
+            sender,
+            result,
+        };
 
-        if let Err(_) = self.cmd_tx.send(cmd) {
+        if self.cmd_tx.send(cmd).await.is_err() {
             return Err(Status::internal("runtime command channel error"));
         };
 
-        let log_receiver = UnboundedReceiverStream::new(rx);
-        let log_stream = log_receiver.map(|item| {
-            let resp = JobLogsResponse {
-                data: match item {
-                    LogRecord::Stderr(data) | LogRecord::Stdout(data) => Bytes::from(data).to_vec(),
-                },
-            };
-            Ok(resp)
-        });
-        Ok(Response::new(Box::pin(log_stream)))
+        result_rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        let log_receiver = ReceiverStream::new(rx);
+        let log_stream = log_receiver.map(log_message_to_response);
+        let log_stream: Self::FetchJobLogsStream = Box::pin(log_stream);
+        Ok(Response::new(log_stream))
     }
 
     async fn stop_job(
         &self,
         request: Request<JobStopRequest>,
     ) -> Result<Response<JobStopResponse>, Status> {
+        AuditContext::of(&request).job(&request.get_ref().uuid);
         let (sender, rx) = tokio::sync::oneshot::channel();
         let job = Uuid::parse_str(&request.get_ref().uuid).map_err(|_| {
             Status::invalid_argument(format!("invalid uuid: {}", request.get_ref().uuid))
         })?;
-        let owner = extract_username_from_request(&request)?;
-        let cmd = RuntimeCommand::Stop { job, owner, sender };
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::Stop {
+            job,
+            principal,
+            sender,
+        };
 
-        if let Err(_) = self.cmd_tx.send(cmd) {
+        if self.cmd_tx.send(cmd).await.is_err() {
             return Err(Status::internal("runtime command channel error"));
         };
 
         rx.await
             .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
-            .map_err(|err| Status::unknown(format!("runtime error: {}", err)))?;
+            .map_err(runtime_error_to_status)?;
 
         Ok(Response::new(JobStopResponse {}))
     }
@@ -172,36 +550,780 @@ impl JobRuntime for MyJobRuntime {
         &self,
         request: Request<JobStatusRequest>,
     ) -> Result<Response<JobStatusResponse>, Status> {
+        AuditContext::of(&request).job(&request.get_ref().uuid);
         let (sender, rx) = tokio::sync::oneshot::channel();
-        let owner = extract_username_from_request(&request)?;
+        let principal = extract_principal_from_request(&request)?;
 
         let job = Uuid::parse_str(&request.into_inner().uuid)
-            .or_else(|_| Err(Status::invalid_argument("invalid uuid")))?;
+            .map_err(|_| Status::invalid_argument("invalid uuid"))?;
 
-        let cmd = RuntimeCommand::Status { job, sender, owner };
+        let cmd = RuntimeCommand::Status {
+            job,
+            sender,
+            principal,
+        };
 
-        if let Err(_) = self.cmd_tx.send(cmd) {
+        if self.cmd_tx.send(cmd).await.is_err() {
             return Err(Status::internal("runtime command channel error"));
         };
 
         let result = rx
             .await
             .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
-            .map_err(|err| Status::unknown(format!("runtime error: {}", err)))?;
+            .map_err(runtime_error_to_status)?;
 
-        let status = match result.status {
-            JobStatus::Finished { exit_code } => {
-                Some(job_status_response::Status::ExitCode(exit_code))
+        Ok(Response::new(result.into()))
+    }
+
+    async fn wait_job(
+        &self,
+        request: Request<JobWaitRequest>,
+    ) -> Result<Response<JobStatusResponse>, Status> {
+        AuditContext::of(&request).job(&request.get_ref().uuid);
+        let (sender, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+
+        let job = Uuid::parse_str(&request.into_inner().uuid)
+            .map_err(|_| Status::invalid_argument("invalid uuid"))?;
+
+        let cmd = RuntimeCommand::Wait {
+            job,
+            sender,
+            principal,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let result = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(result.into()))
+    }
+
+    type WatchJobsStream = Pin<Box<dyn Stream<Item = Result<JobEvent, Status>> + Send>>;
+
+    async fn watch_jobs(
+        &self,
+        request: Request<WatchJobsRequest>,
+    ) -> Result<Response<Self::WatchJobsStream>, Status> {
+        let principal = extract_principal_from_request(&request)?;
+        let request = request.into_inner();
+        let jobs = request
+            .uuids
+            .iter()
+            .map(|uuid| {
+                Uuid::parse_str(uuid)
+                    .map_err(|_| Status::invalid_argument(format!("invalid uuid: {}", uuid)))
+            })
+            .collect::<Result<_, _>>()?;
+        let filter = WatchFilter {
+            jobs,
+            owner: Some(request.owner).filter(|owner| !owner.is_empty()),
+            labels: request.labels,
+        };
+
+        let (sender, rx) = tokio::sync::mpsc::channel(WATCH_CHANNEL_SIZE);
+        let cmd = RuntimeCommand::Watch {
+            principal,
+            filter,
+            sender,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let events = ReceiverStream::new(rx).map(watch_message_to_event);
+        let events: Self::WatchJobsStream = Box::pin(events);
+        Ok(Response::new(events))
+    }
+
+    async fn write_job_stdin(
+        &self,
+        request: Request<Streaming<JobStdinRequest>>,
+    ) -> Result<Response<JobStdinResponse>, Status> {
+        let audit = AuditContext::of(&request);
+        let principal = extract_principal_from_request(&request)?;
+        let mut stream = request.into_inner();
+
+        let mut message = match stream.message().await? {
+            Some(message) => message,
+            None => return Ok(Response::new(JobStdinResponse {})),
+        };
+        audit.job(&message.uuid);
+        let job = Uuid::parse_str(&message.uuid)
+            .map_err(|_| Status::invalid_argument(format!("invalid uuid: {}", message.uuid)))?;
+
+        let (sender, rx) = tokio::sync::oneshot::channel();
+        let cmd = RuntimeCommand::OpenStdin {
+            job,
+            principal: principal.clone(),
+            sender,
+        };
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+        let stdin = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        loop {
+            if !message.data.is_empty() && stdin.send(message.data.into()).await.is_err() {
+                return Err(Status::failed_precondition("job is no longer running"));
+            }
+
+            if message.close {
+                let (sender, rx) = tokio::sync::oneshot::channel();
+                let cmd = RuntimeCommand::CloseStdin {
+                    job,
+                    principal,
+                    sender,
+                };
+                if self.cmd_tx.send(cmd).await.is_err() {
+                    return Err(Status::internal("runtime command channel error"));
+                };
+                rx.await
+                    .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+                    .map_err(runtime_error_to_status)?;
+                break;
+            }
+
+            message = match stream.message().await? {
+                Some(message) => message,
+                None => break,
+            };
+        }
+
+        Ok(Response::new(JobStdinResponse {}))
+    }
+
+    type AttachJobStream = Pin<Box<dyn Stream<Item = Result<AttachJobResponse, Status>> + Send>>;
+
+    async fn attach_job(
+        &self,
+        request: Request<Streaming<AttachJobRequest>>,
+    ) -> Result<Response<Self::AttachJobStream>, Status> {
+        let audit = AuditContext::of(&request);
+        let principal = extract_principal_from_request(&request)?;
+        let mut stream = request.into_inner();
+
+        let job = match stream.message().await?.and_then(|message| message.event) {
+            Some(attach_job_request::Event::Uuid(uuid)) => {
+                audit.job(&uuid);
+                Uuid::parse_str(&uuid)
+                    .map_err(|_| Status::invalid_argument(format!("invalid uuid: {}", uuid)))?
+            }
+            _ => {
+                return Err(Status::invalid_argument(
+                    "the first message must contain job uuid",
+                ))
             }
-            JobStatus::Running { pid } => Some(job_status_response::Status::Pid(pid)),
-            JobStatus::Killed { signal } => Some(job_status_response::Status::Signal(signal)),
-            JobStatus::Pending => None,
         };
 
-        Ok(Response::new(JobStatusResponse {
-            status,
-            uuid: result.job.to_simple().to_string(),
-            owner: result.owner,
+        let (sender, rx) = tokio::sync::oneshot::channel();
+        let cmd = RuntimeCommand::OpenStdin {
+            job,
+            principal: principal.clone(),
+            sender,
+        };
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+        let stdin = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        let (sender, output_rx) = tokio::sync::mpsc::channel(LOG_CHANNEL_SIZE);
+        let (result, result_rx) = tokio::sync::oneshot::channel();
+        let cmd = RuntimeCommand::FetchLogs {
+            job,
+            principal: principal.clone(),
+            from_seq: LOG_SEQ_LATEST,
+            sender,
+            result,
+        };
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+        result_rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        // input is forwarded until the client detaches or the job stops reading it,
+        // an invalid resize ends the output stream with the error
+        let cmd_tx = self.cmd_tx.clone();
+        let (error_tx, error_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            while let Ok(Some(message)) = stream.message().await {
+                match message.event {
+                    Some(attach_job_request::Event::Data(data)) => {
+                        if stdin.send(data.into()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Some(attach_job_request::Event::Resize(size)) => {
+                        let resized = resize_terminal(&cmd_tx, job, principal.clone(), size).await;
+                        if let Err(status) = resized {
+                            let _ = error_tx.send(status);
+                            break;
+                        }
+                    }
+                    Some(attach_job_request::Event::Uuid(_)) | None => {}
+                }
+            }
+        });
+
+        let output = ReceiverStream::new(output_rx).filter_map(|message| match message {
+            LogMessage::Entry(entry) => Some(Ok(AttachJobResponse {
+                data: JobLogsResponse::from(entry).data,
+            })),
+            // a terminal can't show what was skipped, just carry on
+            LogMessage::Lagged { .. } => None,
+            LogMessage::Disconnected { missed } => Some(Err(Status::resource_exhausted(format!(
+                "attached client is too slow, skipped {} records",
+                missed
+            )))),
+        });
+        // the end of the output ends the stream, even while the client still sends input
+        let output = output.map(Some).chain(tokio_stream::once(None));
+        let error = futures::stream::once(error_rx)
+            .filter_map(|status| status.ok().map(|status| Some(Err(status))));
+        let output = output
+            .merge(error)
+            .take_while(Option::is_some)
+            .filter_map(|response| response);
+        let output: Self::AttachJobStream = Box::pin(output);
+        Ok(Response::new(output))
+    }
+
+    async fn fetch_server_info(
+        &self,
+        request: Request<ServerInfoRequest>,
+    ) -> Result<Response<ServerInfoResponse>, Status> {
+        let server_certificate = match &self.tls {
+            Some(tls) => Some(
+                tls.server_certificate_validity()
+                    .map_err(|err| Status::internal(err.to_string()))?
+                    .into(),
+            ),
+            None => None,
+        };
+        let client_certificate = match request
+            .peer_certs()
+            .as_ref()
+            .and_then(|certs| certs.first())
+        {
+            Some(cert) => Some(
+                CertificateValidity::of(cert.as_ref())
+                    .map_err(|err| Status::internal(err.to_string()))?
+                    .into(),
+            ),
+            None => None,
+        };
+        Ok(Response::new(ServerInfoResponse {
+            server_certificate,
+            client_certificate,
+        }))
+    }
+
+    async fn fetch_quota_usage(
+        &self,
+        request: Request<QuotaUsageRequest>,
+    ) -> Result<Response<QuotaUsageResponse>, Status> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let user = match request.get_ref().user.as_str() {
+            "" => None,
+            user => Some(String::from(user)),
+        };
+
+        let cmd = RuntimeCommand::Usage {
+            principal,
+            user,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let usage = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(usage.into()))
+    }
+
+    async fn schedule_job(
+        &self,
+        request: Request<ScheduleJobRequest>,
+    ) -> Result<Response<ScheduleJobResponse>, Status> {
+        let audit = AuditContext::of(&request);
+        if let Some(job) = &request.get_ref().job {
+            audit.command(&job.args, job.limits.as_ref());
+        }
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let overlap = request.get_ref().overlap();
+        let request = request.into_inner();
+
+        let when = match request.when {
+            Some(schedule_job_request::When::Cron(cron)) => When::Cron(
+                cron.parse()
+                    .map_err(|err| Status::invalid_argument(format!("invalid cron: {}", err)))?,
+            ),
+            Some(schedule_job_request::When::RunAt(time)) => When::At(time),
+            None => return Err(Status::invalid_argument("cron or run_at is required")),
+        };
+        let job = match request.job {
+            Some(job) => job_request(job)?,
+            None => return Err(Status::invalid_argument("job is required")),
+        };
+
+        let cmd = RuntimeCommand::Schedule {
+            principal,
+            request: job,
+            when,
+            overlap: overlap.into(),
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let id = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        let id = id.to_simple().to_string();
+        audit.schedule(&id);
+        Ok(Response::new(ScheduleJobResponse { id }))
+    }
+
+    async fn start_workflow(
+        &self,
+        request: Request<WorkflowRequest>,
+    ) -> Result<Response<WorkflowResponse>, Status> {
+        let audit = AuditContext::of(&request);
+        for step in &request.get_ref().steps {
+            if let Some(job) = &step.job {
+                audit.step(&step.name, &job.args, job.limits.as_ref());
+            }
+        }
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let steps = request
+            .into_inner()
+            .steps
+            .into_iter()
+            .map(|step| {
+                let after = step
+                    .after
+                    .iter()
+                    .map(|dependency| StepDependency {
+                        step: dependency.step.clone(),
+                        condition: dependency.condition().into(),
+                    })
+                    .collect();
+                let request = match step.job {
+                    Some(job) => job_request(job)?,
+                    None => {
+                        return Err(Status::invalid_argument(format!(
+                            "step {} has no job",
+                            step.name
+                        )))
+                    }
+                };
+                Ok(WorkflowStep {
+                    name: step.name,
+                    request,
+                    after,
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+
+        let cmd = RuntimeCommand::StartWorkflow {
+            principal,
+            steps,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let workflow = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        let id = workflow.id.to_simple().to_string();
+        audit.workflow(&id);
+        Ok(Response::new(WorkflowResponse {
+            workflow: id,
+            jobs: workflow
+                .jobs
+                .into_iter()
+                .map(|(step, job)| (step, job.to_simple().to_string()))
+                .collect(),
+        }))
+    }
+
+    async fn start_batch(
+        &self,
+        request: Request<BatchStartRequest>,
+    ) -> Result<Response<BatchStartResponse>, Status> {
+        let audit = AuditContext::of(&request);
+        if let Some(template) = &request.get_ref().template {
+            audit.command(&template.args, template.limits.as_ref());
+        }
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let request = request.into_inner();
+
+        let requests = match (request.template, request.jobs.is_empty()) {
+            (Some(template), true) => {
+                let count = request.count as usize;
+                if count > batch::MAX_BATCH_SIZE {
+                    return Err(Status::invalid_argument(format!(
+                        "count must be at most {}",
+                        batch::MAX_BATCH_SIZE
+                    )));
+                }
+                batch::expand_array(&job_request(template)?, count)
+            }
+            (None, false) => request
+                .jobs
+                .into_iter()
+                .map(job_request)
+                .collect::<Result<_, Status>>()?,
+            (Some(_), false) => {
+                return Err(Status::invalid_argument(
+                    "either jobs or template can be given, not both",
+                ))
+            }
+            (None, true) => return Err(Status::invalid_argument("jobs or template is required")),
+        };
+
+        let cmd = RuntimeCommand::StartBatch {
+            principal,
+            requests,
+            atomic: request.atomic,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let started = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        let (uuids, errors) = started
+            .jobs
+            .into_iter()
+            .map(|job| match job {
+                Ok(job) => (job.to_simple().to_string(), String::new()),
+                Err(err) => (String::new(), err.to_string()),
+            })
+            .unzip();
+        let batch = started.id.to_simple().to_string();
+        audit.batch(&batch);
+        Ok(Response::new(BatchStartResponse {
+            batch,
+            uuids,
+            errors,
+        }))
+    }
+
+    async fn stop_batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<Response<JobStopResponse>, Status> {
+        AuditContext::of(&request).batch(&request.get_ref().id);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::StopBatch {
+            principal,
+            batch: batch_id(&request.get_ref().id)?,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        rx.await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(JobStopResponse {}))
+    }
+
+    async fn wait_batch(
+        &self,
+        request: Request<BatchRequest>,
+    ) -> Result<Response<BatchStatusResponse>, Status> {
+        AuditContext::of(&request).batch(&request.get_ref().id);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::BatchJobs {
+            principal: principal.clone(),
+            batch: batch_id(&request.get_ref().id)?,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let jobs = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        // every job is waited for at once, the runtime wakes them up as they end
+        let waiters = jobs.into_iter().map(|job| {
+            let principal = principal.clone();
+            async move {
+                let (sender, rx) = tokio::sync::oneshot::channel();
+                let cmd = RuntimeCommand::Wait {
+                    job,
+                    sender,
+                    principal,
+                };
+                if self.cmd_tx.send(cmd).await.is_err() {
+                    return Err(Status::internal("runtime command channel error"));
+                };
+                rx.await
+                    .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+                    .map_err(runtime_error_to_status)
+                    .map(JobStatusResponse::from)
+            }
+        });
+        let jobs = futures::future::try_join_all(waiters).await?;
+
+        Ok(Response::new(BatchStatusResponse { jobs }))
+    }
+
+    async fn stop_group(
+        &self,
+        request: Request<GroupRequest>,
+    ) -> Result<Response<GroupResponse>, Status> {
+        AuditContext::of(&request).group(&request.get_ref().name);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::StopGroup {
+            principal,
+            group: request.into_inner().name,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        rx.await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(GroupResponse {}))
+    }
+
+    async fn signal_group(
+        &self,
+        request: Request<SignalGroupRequest>,
+    ) -> Result<Response<GroupResponse>, Status> {
+        AuditContext::of(&request).group(&request.get_ref().name);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let request = request.into_inner();
+        let cmd = RuntimeCommand::SignalGroup {
+            principal,
+            group: request.name,
+            signal: request.signal,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        rx.await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(GroupResponse {}))
+    }
+
+    async fn fetch_group_status(
+        &self,
+        request: Request<GroupRequest>,
+    ) -> Result<Response<GroupStatusResponse>, Status> {
+        AuditContext::of(&request).group(&request.get_ref().name);
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::GroupStatus {
+            principal,
+            group: request.into_inner().name,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let status = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(status.into()))
+    }
+
+    async fn list_groups(
+        &self,
+        request: Request<ListGroupsRequest>,
+    ) -> Result<Response<ListGroupsResponse>, Status> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::ListGroups {
+            principal,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let groups = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?;
+
+        Ok(Response::new(ListGroupsResponse {
+            groups: groups
+                .into_iter()
+                .map(|group| GroupStatusResponse {
+                    jobs: vec![],
+                    ..group.into()
+                })
+                .collect(),
+        }))
+    }
+
+    async fn delete_job(
+        &self,
+        request: Request<JobDeleteRequest>,
+    ) -> Result<Response<JobDeleteResponse>, Status> {
+        AuditContext::of(&request).job(&request.get_ref().uuid);
+        let (sender, rx) = tokio::sync::oneshot::channel();
+        let job = Uuid::parse_str(&request.get_ref().uuid).map_err(|_| {
+            Status::invalid_argument(format!("invalid uuid: {}", request.get_ref().uuid))
+        })?;
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::Delete {
+            job,
+            principal,
+            sender,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        rx.await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(JobDeleteResponse {}))
+    }
+
+    async fn prune_jobs(
+        &self,
+        request: Request<PruneJobsRequest>,
+    ) -> Result<Response<PruneJobsResponse>, Status> {
+        let (sender, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let older_than = match request.get_ref().older_than_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let cmd = RuntimeCommand::Prune {
+            principal,
+            older_than,
+            all_users: request.get_ref().all_users,
+            sender,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let jobs = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(PruneJobsResponse {
+            uuids: jobs.iter().map(|job| job.to_simple().to_string()).collect(),
+        }))
+    }
+
+    async fn list_schedules(
+        &self,
+        request: Request<ListSchedulesRequest>,
+    ) -> Result<Response<ListSchedulesResponse>, Status> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let principal = extract_principal_from_request(&request)?;
+        let cmd = RuntimeCommand::ListSchedules {
+            principal,
+            sender: tx,
+        };
+
+        if self.cmd_tx.send(cmd).await.is_err() {
+            return Err(Status::internal("runtime command channel error"));
+        };
+
+        let schedules = rx
+            .await
+            .map_err(|err| Status::unknown(format!("connection error: {}", err)))?
+            .map_err(runtime_error_to_status)?;
+
+        Ok(Response::new(ListSchedulesResponse {
+            schedules: schedules.into_iter().map(ScheduleInfo::from).collect(),
         }))
     }
+
+    async fn pause_schedule(
+        &self,
+        request: Request<ScheduleRequest>,
+    ) -> Result<Response<ScheduleResponse>, Status> {
+        self.update_schedule(request, ScheduleChange::Pause).await
+    }
+
+    async fn resume_schedule(
+        &self,
+        request: Request<ScheduleRequest>,
+    ) -> Result<Response<ScheduleResponse>, Status> {
+        self.update_schedule(request, ScheduleChange::Resume).await
+    }
+
+    async fn delete_schedule(
+        &self,
+        request: Request<ScheduleRequest>,
+    ) -> Result<Response<ScheduleResponse>, Status> {
+        self.update_schedule(request, ScheduleChange::Delete).await
+    }
 }
@@ -1,2 +1,4 @@
+pub mod audit;
 pub mod grpc;
+pub mod revocation;
 pub mod tls;
@@ -1,55 +1,337 @@
+pub mod audit;
 pub mod grpc;
+pub mod revocation;
 pub mod tls;
 
-use clap::Parser;
+use anyhow::Context;
+use audit::{AuditConfig, AuditLayer, AuditLog};
+use clap::{ArgEnum, Parser};
 use grpc::grpc_jobruntime::job_runtime_server::JobRuntimeServer;
 use grpc::MyJobRuntime;
-use runtime::JobRuntime;
+use revocation::{RevocationConfig, RevocationList};
+use runtime::auth::Rbac;
+use runtime::logstore::{FileLogStore, FileLogStoreConfig, MemoryLogStore, DEFAULT_MAX_JOB_BYTES};
+use runtime::quota::QuotaPolicy;
+use runtime::retention::RetentionPolicy;
+use runtime::scheduler::SchedulerConfig;
+use runtime::shim::Shims;
+use runtime::{JobRuntime, SlowConsumerPolicy};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tls::{ReloadableTls, TlsFiles};
+use tokio::signal::unix::{signal, SignalKind};
 use tonic::transport::{Server, ServerTlsConfig};
 
+#[derive(ArgEnum, Clone)]
+enum SlowConsumer {
+    Lag,
+    Disconnect,
+}
+
+impl From<SlowConsumer> for SlowConsumerPolicy {
+    fn from(policy: SlowConsumer) -> Self {
+        match policy {
+            SlowConsumer::Lag => SlowConsumerPolicy::Lag,
+            SlowConsumer::Disconnect => SlowConsumerPolicy::Disconnect,
+        }
+    }
+}
+
 #[derive(Parser)]
 #[clap(name = "jobdaemon")]
 struct Cli {
     #[clap(long)]
-    key: String,
-    #[clap(long)]
-    cert: String,
+    key: PathBuf,
     #[clap(long)]
-    ca_cert: String,
+    cert: PathBuf,
+    /// CA certificate or PEM bundle trusted to sign client certificates, can be repeated
+    #[clap(long, required = true)]
+    ca_cert: Vec<PathBuf>,
     #[clap(long)]
     addr: String,
+
+    /// Store job logs on disk in this directory instead of keeping them in memory
+    #[clap(long)]
+    log_dir: Option<PathBuf>,
+    /// Maximum size of logs kept for a single job [default: 16777216]
+    #[clap(long)]
+    log_max_bytes: Option<u64>,
+    /// Size of a single log segment on disk
+    #[clap(long, default_value = "8388608")]
+    log_segment_bytes: u64,
+    /// Compress rotated log segments
+    #[clap(long)]
+    log_compress: bool,
+    /// Remove log segments older than that many seconds
+    #[clap(long)]
+    log_retention_secs: Option<u64>,
+    /// Jobs running at the same time, the rest waits in a queue. Unlimited by default
+    #[clap(long)]
+    max_running_jobs: Option<usize>,
+    /// Concurrency pool jobs can be started in and how many of its jobs run at the same time,
+    /// can be repeated
+    #[clap(long = "pool", value_name = "NAME=LIMIT", parse(try_from_str = parse_pool))]
+    pools: Vec<(String, usize)>,
+    /// What to do with clients that can't keep up with the job output
+    #[clap(long, arg_enum, default_value = "lag")]
+    slow_log_consumer: SlowConsumer,
+    /// TOML file with roles, their permissions and user bindings.
+    /// Without it admins are users having the "admin" role in the certificate
+    #[clap(long)]
+    auth_policy: Option<PathBuf>,
+    /// TOML file with default resource limits and quotas per user and role.
+    /// Without it users are unlimited
+    #[clap(long)]
+    quota_policy: Option<PathBuf>,
+    /// JSON file schedules are kept in, so they survive restarts.
+    /// Without it schedules are lost when the daemon stops
+    #[clap(long)]
+    schedule_file: Option<PathBuf>,
+    /// File jobs are recorded in, so their status and logs survive restarts.
+    /// Jobs that were running when the daemon stopped are reported as lost unless they run under a shim
+    #[clap(long)]
+    state_file: Option<PathBuf>,
+    /// Directory of per job supervisors. With it jobs without stdin and terminal keep running
+    /// when the daemon stops and are reattached on start when --state-file is given too
+    #[clap(long)]
+    shim_dir: Option<PathBuf>,
+    /// Path of the jobshim binary, by default the one next to jobdaemon
+    #[clap(long)]
+    shim: Option<PathBuf>,
+    /// Delete ended jobs and their logs that many seconds after they ended
+    #[clap(long)]
+    job_ttl_secs: Option<u64>,
+    /// Keep only that many of the most recent ended jobs of every user
+    #[clap(long)]
+    max_ended_jobs: Option<usize>,
+    /// Number of the most recent failed jobs of every user kept regardless of --job-ttl-secs and --max-ended-jobs
+    #[clap(long, default_value = "10")]
+    keep_failed_jobs: usize,
+    /// CRL file of the CA, can be repeated. Reloaded on SIGHUP
+    #[clap(long = "crl", value_name = "PATH")]
+    crl_files: Vec<PathBuf>,
+    /// File with revoked certificates, one `serial:<hex>` or `sha256:<hex>` per line.
+    /// Reloaded on SIGHUP
+    #[clap(long)]
+    deny_list: Option<PathBuf>,
+    /// Append a JSON line for every API request to this file
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+    /// Rotate the audit log when it grows over that many bytes
+    #[clap(long, default_value = "104857600")]
+    audit_log_max_bytes: u64,
+    /// Number of rotated audit logs to keep
+    #[clap(long, default_value = "10")]
+    audit_log_max_files: usize,
+    /// Warn when the server certificate expires in less than that many days
+    #[clap(long, default_value = "30")]
+    cert_expiry_warning_days: u64,
+}
+
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+fn check_certificate_expiry(tls: &ReloadableTls, warning_days: u64) {
+    match tls.server_certificate_validity() {
+        Ok(validity) if validity.days_left() < 0 => log::error!(
+            "server certificate {} expired on {}",
+            validity.subject,
+            pki::format_timestamp(validity.not_after)
+        ),
+        Ok(validity) if validity.expires_within(warning_days) => log::warn!(
+            "server certificate {} expires in {} days on {}",
+            validity.subject,
+            validity.days_left(),
+            pki::format_timestamp(validity.not_after)
+        ),
+        Ok(_) => {}
+        Err(err) => log::error!("unable to check server certificate expiry: {:#}", err),
+    }
+}
+
+// The daemon can run for longer than the certificate is valid, so the check is repeated
+fn warn_before_certificate_expiry(tls: Arc<ReloadableTls>, warning_days: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            check_certificate_expiry(&tls, warning_days);
+        }
+    });
+}
+
+// Reloads the server certificate, trusted CAs and the revocation list on every SIGHUP.
+// Anything that fails to load keeps its previous version.
+fn reload_on_hangup(
+    tls_files: TlsFiles,
+    tls: Arc<ReloadableTls>,
+    revocation_config: RevocationConfig,
+    revocations: Arc<RwLock<RevocationList>>,
+    warning_days: u64,
+) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            match tls.reload(&tls_files) {
+                Ok(()) => {
+                    log::info!("reloaded TLS certificates");
+                    check_certificate_expiry(&tls, warning_days);
+                }
+                Err(err) => log::error!("unable to reload TLS certificates: {:#}", err),
+            }
+            match RevocationList::load(&revocation_config) {
+                Ok(list) => {
+                    log::info!("reloaded revocation list with {} entries", list.len());
+                    match revocations.write() {
+                        Ok(mut current) => *current = list,
+                        Err(_) => log::error!("revocation list lock is poisoned"),
+                    }
+                }
+                Err(err) => log::error!("unable to reload revocation list: {:#}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn load_auth_policy(path: &Path) -> anyhow::Result<Rbac> {
+    let policy = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read auth policy {}", path.display()))?;
+    let rbac: Rbac = toml::from_str(&policy)
+        .with_context(|| format!("invalid auth policy {}", path.display()))?;
+    for role in rbac.undefined_roles() {
+        log::warn!("auth policy refers to undefined role: {}", role);
+    }
+    Ok(rbac)
+}
+
+fn parse_pool(pool: &str) -> Result<(String, usize), String> {
+    match pool.split_once('=') {
+        Some((name, limit)) if !name.is_empty() => limit
+            .parse()
+            .map(|limit| (name.to_string(), limit))
+            .map_err(|err| format!("invalid limit of pool {}: {}", name, err)),
+        _ => Err(format!("expected NAME=LIMIT, got {}", pool)),
+    }
+}
+
+fn load_quota_policy(path: &Path) -> anyhow::Result<QuotaPolicy> {
+    let policy = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read quota policy {}", path.display()))?;
+    toml::from_str(&policy).with_context(|| format!("invalid quota policy {}", path.display()))
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let revocation_config = RevocationConfig {
+        crl_files: args.crl_files.clone(),
+        deny_list: args.deny_list.clone(),
+    };
+    let revocations = Arc::new(RwLock::new(RevocationList::load(&revocation_config)?));
+    let tls_files = TlsFiles {
+        cert: args.cert.clone(),
+        key: args.key.clone(),
+        ca_certs: args.ca_cert.clone(),
+    };
+    let tls = Arc::new(ReloadableTls::load(&tls_files)?);
+    reload_on_hangup(
+        tls_files,
+        tls.clone(),
+        revocation_config,
+        revocations.clone(),
+        args.cert_expiry_warning_days,
+    )?;
+    warn_before_certificate_expiry(tls.clone(), args.cert_expiry_warning_days);
     let (rt, cmd_tx) = JobRuntime::new();
-    let rt = rt.enable_cgroups()?;
+    let rt = rt
+        .enable_cgroups()?
+        .with_slow_consumer_policy(args.slow_log_consumer.clone().into())
+        .with_scheduler(SchedulerConfig {
+            max_running: args.max_running_jobs,
+            pools: args.pools.iter().cloned().collect(),
+        })
+        .with_retention(RetentionPolicy {
+            ttl: args.job_ttl_secs.map(Duration::from_secs),
+            max_per_user: args.max_ended_jobs,
+            keep_failures: args.keep_failed_jobs,
+        });
+    let rt = match &args.auth_policy {
+        Some(path) => rt.with_authorizer(load_auth_policy(path)?),
+        None => rt.with_authorizer(Rbac::default()),
+    };
+    let rt = match &args.quota_policy {
+        Some(path) => rt.with_quotas(load_quota_policy(path)?),
+        None => rt,
+    };
+    let rt = match &args.schedule_file {
+        Some(path) => rt
+            .with_schedule_file(path.clone())
+            .with_context(|| format!("unable to load schedules from {}", path.display()))?,
+        None => rt,
+    };
+    let rt = match &args.log_dir {
+        Some(log_dir) => {
+            let config = FileLogStoreConfig {
+                segment_bytes: args.log_segment_bytes,
+                max_job_bytes: args.log_max_bytes.unwrap_or(DEFAULT_MAX_JOB_BYTES),
+                compress: args.log_compress,
+                retention: args.log_retention_secs.map(Duration::from_secs),
+            };
+            rt.with_log_store(FileLogStore::new(log_dir, config)?)
+        }
+        None => rt.with_log_store(MemoryLogStore::new(
+            args.log_max_bytes.unwrap_or(DEFAULT_MAX_JOB_BYTES) as usize,
+        )),
+    };
+    let rt = match &args.shim_dir {
+        Some(shim_dir) => {
+            let binary = match &args.shim {
+                Some(binary) => binary.clone(),
+                None => std::env::current_exe()?.with_file_name("jobshim"),
+            };
+            rt.with_shims(
+                Shims::new(binary, shim_dir.clone())
+                    .with_context(|| format!("unable to create {}", shim_dir.display()))?,
+            )
+        }
+        None => rt,
+    };
+    let rt = match &args.state_file {
+        Some(path) => rt
+            .with_journal(path.clone())
+            .with_context(|| format!("unable to restore jobs from {}", path.display()))?,
+        None => rt,
+    };
 
-    let runtime = MyJobRuntime::new(cmd_tx);
+    let audit = match &args.audit_log {
+        Some(path) => Some(Arc::new(
+            AuditLog::open(AuditConfig {
+                path: path.clone(),
+                max_bytes: args.audit_log_max_bytes,
+                max_files: args.audit_log_max_files,
+            })
+            .with_context(|| format!("unable to open audit log {}", path.display()))?,
+        )),
+        None => None,
+    };
+    let runtime = MyJobRuntime::new(cmd_tx).with_tls(tls.clone());
+    let interceptor = tls::CertificateInterceptor::new(revocations);
     tokio::spawn(async {
         rt.start().await;
     });
 
-    let server_key = std::fs::read(args.key)?;
-    let server_cert = std::fs::read(args.cert)?;
-    let ca_cert = std::fs::read(args.ca_cert)?;
-
     let mut tls_config = ServerTlsConfig::new();
-    tls_config.rustls_server_config(tls::prepare_server_config(
-        server_cert,
-        server_key,
-        ca_cert,
-    )?);
+    tls_config.rustls_server_config(tls.server_config());
 
     println!("starting server at: {}", args.addr);
 
     Server::builder()
+        .layer(AuditLayer::new(audit))
         .tls_config(tls_config)?
-        .add_service(JobRuntimeServer::with_interceptor(
-            runtime,
-            tls::intercept_extract_username_from_certificate,
-        ))
+        .add_service(JobRuntimeServer::with_interceptor(runtime, interceptor))
         .serve(args.addr.parse().unwrap())
         .await?;
 
@@ -0,0 +1,190 @@
+use anyhow::Context;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::time::ASN1Time;
+
+// Where revoked certificates come from. Files are read again on every reload,
+// so they can be replaced while the daemon is running.
+#[derive(Debug, Clone, Default)]
+pub struct RevocationConfig {
+    // CRL files in DER or PEM format, published by the CA
+    pub crl_files: Vec<PathBuf>,
+    // Local list of revoked certificates, one per line:
+    // `serial:<hex>` or `sha256:<hex fingerprint of the DER certificate>`
+    pub deny_list: Option<PathBuf>,
+}
+
+// Why a certificate was rejected
+#[derive(Debug, PartialEq, Eq)]
+pub enum Revoked {
+    Serial(String),
+    Fingerprint(String),
+}
+
+impl fmt::Display for Revoked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Revoked::Serial(serial) => write!(f, "serial number {} is revoked", serial),
+            Revoked::Fingerprint(fingerprint) => {
+                write!(f, "fingerprint sha256:{} is revoked", fingerprint)
+            }
+        }
+    }
+}
+
+// Serial numbers and fingerprints are kept as lowercase hex,
+// serial numbers without leading zeros so that different notations match
+#[derive(Debug, Default)]
+pub struct RevocationList {
+    serials: HashSet<String>,
+    fingerprints: HashSet<String>,
+}
+
+impl RevocationList {
+    pub fn load(config: &RevocationConfig) -> anyhow::Result<Self> {
+        let mut list = Self::default();
+        for path in &config.crl_files {
+            list.add_crl(path)
+                .with_context(|| format!("unable to load CRL {}", path.display()))?;
+        }
+        if let Some(path) = &config.deny_list {
+            let content = fs::read_to_string(path)
+                .with_context(|| format!("unable to read deny list {}", path.display()))?;
+            list.add_deny_list(&content)
+                .with_context(|| format!("invalid deny list {}", path.display()))?;
+        }
+        Ok(list)
+    }
+
+    pub fn len(&self) -> usize {
+        self.serials.len() + self.fingerprints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn add_crl(&mut self, path: &Path) -> anyhow::Result<()> {
+        let data = fs::read(path)?;
+        let der = if data.starts_with(b"-----BEGIN") {
+            let (_, pem) = x509_parser::pem::parse_x509_pem(&data)
+                .map_err(|err| anyhow::anyhow!("invalid PEM: {}", err))?;
+            pem.contents
+        } else {
+            data
+        };
+        let (_, crl) = x509_parser::parse_x509_crl(&der)
+            .map_err(|err| anyhow::anyhow!("invalid CRL: {}", err))?;
+
+        if let Some(next_update) = crl.next_update() {
+            if next_update.timestamp() < ASN1Time::now().timestamp() {
+                log::warn!(
+                    "CRL {} is outdated, next update was due {}",
+                    path.display(),
+                    next_update.to_rfc2822()
+                );
+            }
+        }
+        for revoked in crl.iter_revoked_certificates() {
+            self.serials.insert(format!("{:x}", revoked.serial()));
+        }
+        Ok(())
+    }
+
+    fn add_deny_list(&mut self, content: &str) -> anyhow::Result<()> {
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (kind, value) = line.split_once(':').with_context(|| {
+                format!("line {}: expected serial:<hex> or sha256:<hex>", number + 1)
+            })?;
+            let value = normalize_hex(value)
+                .with_context(|| format!("line {}: invalid hex value", number + 1))?;
+            match kind.trim() {
+                "serial" => {
+                    self.serials
+                        .insert(value.trim_start_matches('0').to_string());
+                }
+                "sha256" => {
+                    self.fingerprints.insert(value);
+                }
+                other => anyhow::bail!("line {}: unknown entry type {}", number + 1, other),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, der: &[u8], cert: &X509Certificate<'_>) -> Result<(), Revoked> {
+        let serial = format!("{:x}", cert.tbs_certificate.serial);
+        if self.serials.contains(&serial) {
+            return Err(Revoked::Serial(serial));
+        }
+        if !self.fingerprints.is_empty() {
+            let fingerprint = fingerprint(der);
+            if self.fingerprints.contains(&fingerprint) {
+                return Err(Revoked::Fingerprint(fingerprint));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Accepts hex with or without colons, e.g. the output of `openssl x509 -serial` or `-fingerprint`
+fn normalize_hex(value: &str) -> Option<String> {
+    let value: String = value
+        .trim()
+        .chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(value)
+}
+
+pub fn fingerprint(der: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, der)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn deny_list_revokes_by_serial_and_fingerprint() {
+        let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.push("tests/certs/admin.cert.der");
+        let der = fs::read(path).unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).unwrap();
+
+        let mut list = RevocationList::default();
+        list.add_deny_list("# leaked\nserial:00:01:ff\n").unwrap();
+        assert!(list.check(&der, &cert).is_ok());
+
+        let serial = cert.tbs_certificate.raw_serial_as_string();
+        list.add_deny_list(&format!("serial:{}", serial)).unwrap();
+        assert!(matches!(list.check(&der, &cert), Err(Revoked::Serial(_))));
+
+        let mut list = RevocationList::default();
+        list.add_deny_list(&format!("sha256:{}", fingerprint(&der).to_uppercase()))
+            .unwrap();
+        assert!(matches!(
+            list.check(&der, &cert),
+            Err(Revoked::Fingerprint(_))
+        ));
+
+        assert!(list.add_deny_list("md5:abcd").is_err());
+        assert!(list.add_deny_list("serial:xyz").is_err());
+    }
+}
@@ -1,12 +1,49 @@
+use crate::audit::AuditContext;
 use crate::grpc::Username;
+use crate::revocation::RevocationList;
+use anyhow::Context;
+use pki::CertificateValidity;
+use runtime::auth::Principal;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::rustls::sign::CertifiedKey;
 use tokio_rustls::rustls::{
-    ciphersuite, AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig,
+    ciphersuite, AllowAnyAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier,
+    ClientHello, DistinguishedNames, ResolvesServerCert, ServerConfig, SupportedCipherSuite,
+    TLSError,
 };
+use tonic::service::Interceptor;
 use tonic::{Request, Status};
+use webpki::DNSName;
 use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::x509::AttributeTypeAndValue;
 
-pub struct UsernameExtension {
-    pub username: Username,
+pub struct PrincipalExtension {
+    pub principal: Principal,
+}
+
+const CIPHERSUITES: [&SupportedCipherSuite; 3] = [
+    &ciphersuite::TLS13_AES_128_GCM_SHA256,
+    &ciphersuite::TLS13_AES_256_GCM_SHA384,
+    &ciphersuite::TLS13_CHACHA20_POLY1305_SHA256,
+];
+
+fn certified_key(cert: &[u8], key: &[u8]) -> anyhow::Result<CertifiedKey> {
+    let (certs, key) = pki::read_identity(cert, key)?;
+    CertificateValidity::of(&certs[0].0)?.ensure_valid()?;
+    Ok(CertifiedKey::new(certs, Arc::new(pki::signing_key(&key)?)))
+}
+
+fn server_config(
+    verifier: Arc<dyn ClientCertVerifier>,
+    resolver: Arc<dyn ResolvesServerCert>,
+) -> ServerConfig {
+    let mut config = ServerConfig::with_ciphersuites(verifier, &CIPHERSUITES);
+    config.cert_resolver = resolver;
+    config.set_protocols(&[b"h2".to_vec()]);
+    config
 }
 
 pub fn prepare_server_config(
@@ -14,39 +51,244 @@ pub fn prepare_server_config(
     key: Vec<u8>,
     ca: Vec<u8>,
 ) -> anyhow::Result<ServerConfig> {
-    let mut roots = RootCertStore::empty();
-    roots.add(&Certificate(ca)).expect("invalid ca certificate");
-
-    let mut config = ServerConfig::with_ciphersuites(
-        AllowAnyAuthenticatedClient::new(roots),
-        &[
-            &ciphersuite::TLS13_AES_128_GCM_SHA256,
-            &ciphersuite::TLS13_AES_256_GCM_SHA384,
-            &ciphersuite::TLS13_CHACHA20_POLY1305_SHA256,
-        ],
-    );
-    config.set_single_cert(vec![Certificate(cert)], PrivateKey(key))?;
-    config.set_protocols(&[b"h2".to_vec()]);
-    Ok(config)
+    let verifier = AllowAnyAuthenticatedClient::new(pki::load_roots(&[ca])?);
+    let resolver = AlwaysResolves(certified_key(&cert, &key)?);
+    Ok(server_config(verifier, Arc::new(resolver)))
 }
 
-pub fn extract_name_from_certificate(cert: X509Certificate<'_>) -> anyhow::Result<Username> {
-    // there should be better validation, but for now I'll only check the first CommonName from the certificate
-    let name = cert
-        .subject()
-        .iter_common_name()
-        .next()
-        .and_then(|cn| cn.as_str().ok());
+struct AlwaysResolves(CertifiedKey);
 
-    match name {
-        Some(name) => Ok(String::from(name)),
-        None => anyhow::bail!("invalid CommonName in certificate"),
+impl ResolvesServerCert for AlwaysResolves {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.0.clone())
     }
 }
 
-pub fn intercept_extract_username_from_certificate(
-    mut request: Request<()>,
-) -> Result<Request<()>, Status> {
+// Files the server TLS configuration is built from
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub ca_certs: Vec<PathBuf>,
+}
+
+impl TlsFiles {
+    fn load(&self) -> anyhow::Result<(Arc<dyn ClientCertVerifier>, CertifiedKey)> {
+        let read = |path: &PathBuf| {
+            fs::read(path).with_context(|| format!("unable to read {}", path.display()))
+        };
+        let ca_certs = self
+            .ca_certs
+            .iter()
+            .map(read)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let verifier = AllowAnyAuthenticatedClient::new(pki::load_roots(&ca_certs)?);
+        let key = certified_key(&read(&self.cert)?, &read(&self.key)?).with_context(|| {
+            format!(
+                "invalid server certificate {} or key {}",
+                self.cert.display(),
+                self.key.display()
+            )
+        })?;
+        Ok((verifier, key))
+    }
+}
+
+// Server certificate and trusted CAs that can be replaced while the server is running.
+// The server config holds on to the verifier and the resolver below, so a reload affects
+// only new handshakes and connections that are already established keep working.
+pub struct ReloadableTls {
+    verifier: Arc<ReloadableVerifier>,
+    resolver: Arc<ReloadableResolver>,
+}
+
+impl ReloadableTls {
+    pub fn load(files: &TlsFiles) -> anyhow::Result<Self> {
+        let (verifier, key) = files.load()?;
+        Ok(Self {
+            verifier: Arc::new(ReloadableVerifier(RwLock::new(verifier))),
+            resolver: Arc::new(ReloadableResolver(RwLock::new(key))),
+        })
+    }
+
+    pub fn server_certificate_validity(&self) -> anyhow::Result<CertificateValidity> {
+        let key = self
+            .resolver
+            .0
+            .read()
+            .map_err(|_| anyhow::anyhow!("TLS configuration lock is poisoned"))?;
+        CertificateValidity::of(&key.cert[0].0)
+    }
+
+    pub fn server_config(&self) -> ServerConfig {
+        server_config(self.verifier.clone(), self.resolver.clone())
+    }
+
+    // Either everything is replaced or nothing, when one of the files is invalid
+    // the server keeps using the previous configuration
+    pub fn reload(&self, files: &TlsFiles) -> anyhow::Result<()> {
+        let (verifier, key) = files.load()?;
+        match (self.verifier.0.write(), self.resolver.0.write()) {
+            (Ok(mut current_verifier), Ok(mut current_key)) => {
+                *current_verifier = verifier;
+                *current_key = key;
+                Ok(())
+            }
+            _ => anyhow::bail!("TLS configuration lock is poisoned"),
+        }
+    }
+}
+
+struct ReloadableVerifier(RwLock<Arc<dyn ClientCertVerifier>>);
+
+impl ReloadableVerifier {
+    fn current(&self) -> Result<Arc<dyn ClientCertVerifier>, TLSError> {
+        self.0
+            .read()
+            .map(|verifier| verifier.clone())
+            .map_err(|_| TLSError::General(String::from("client verifier lock is poisoned")))
+    }
+}
+
+impl ClientCertVerifier for ReloadableVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.current()
+            .map(|verifier| verifier.offer_client_auth())
+            .unwrap_or(true)
+    }
+
+    fn client_auth_mandatory(&self, sni: Option<&DNSName>) -> Option<bool> {
+        self.current().ok()?.client_auth_mandatory(sni)
+    }
+
+    fn client_auth_root_subjects(&self, sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        self.current().ok()?.client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        self.current()?.verify_client_cert(presented_certs, sni)
+    }
+}
+
+struct ReloadableResolver(RwLock<CertifiedKey>);
+
+impl ResolvesServerCert for ReloadableResolver {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        self.0.read().ok().map(|key| key.clone())
+    }
+}
+
+fn attribute_values<'a, 'b: 'a>(
+    values: impl Iterator<Item = &'a AttributeTypeAndValue<'b>>,
+    attribute: &str,
+) -> anyhow::Result<Vec<String>> {
+    values
+        .map(|value| match value.as_str() {
+            Ok(value) if !value.trim().is_empty() => Ok(String::from(value)),
+            Ok(_) => anyhow::bail!("certificate has an empty {}", attribute),
+            Err(err) => anyhow::bail!(
+                "{} in certificate is not a valid string: {}",
+                attribute,
+                err
+            ),
+        })
+        .collect()
+}
+
+// CommonName is the username, a certificate with more than one is ambiguous
+pub fn extract_name_from_certificate(
+    cert: &X509Certificate<'_>,
+) -> anyhow::Result<Option<Username>> {
+    let mut names = attribute_values(cert.subject().iter_common_name(), "CommonName")?;
+    if names.len() > 1 {
+        anyhow::bail!("certificate has {} CommonNames, expected one", names.len());
+    }
+    Ok(names.pop())
+}
+
+// Every Organization and OrganizationalUnit attribute of the subject is a role
+pub fn extract_roles_from_certificate(cert: &X509Certificate<'_>) -> anyhow::Result<Vec<String>> {
+    let subject = cert.subject();
+    let mut roles = attribute_values(subject.iter_organization(), "Organization")?;
+    roles.extend(attribute_values(
+        subject.iter_organizational_unit(),
+        "OrganizationalUnit",
+    )?);
+    roles.sort_unstable();
+    roles.dedup();
+    Ok(roles)
+}
+
+const SPIFFE_SCHEME: &str = "spiffe://";
+
+// SPIFFE ID is spiffe://<trust domain>/<path>, the trust domain can't be empty
+// and the ID can't have a query or a fragment
+fn validate_spiffe_id(id: &str) -> anyhow::Result<()> {
+    let rest = &id[SPIFFE_SCHEME.len()..];
+    let trust_domain = rest.split('/').next().unwrap_or_default();
+    let valid_domain = !trust_domain.is_empty()
+        && trust_domain
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-._".contains(c));
+    if !valid_domain || id.contains(['?', '#']) {
+        anyhow::bail!("invalid SPIFFE ID in certificate: {}", id);
+    }
+    Ok(())
+}
+
+// The principal is named after the CommonName, or the SPIFFE ID if there is no CommonName
+pub fn extract_principal_from_certificate(cert: &X509Certificate<'_>) -> anyhow::Result<Principal> {
+    let mut uris = vec![];
+    let mut emails = vec![];
+    if let Some((_, san)) = cert.tbs_certificate.subject_alternative_name() {
+        for name in &san.general_names {
+            match name {
+                GeneralName::URI(uri) => uris.push(String::from(*uri)),
+                GeneralName::RFC822Name(email) => emails.push(String::from(*email)),
+                _ => {}
+            }
+        }
+    }
+
+    let spiffe_ids: Vec<&String> = uris
+        .iter()
+        .filter(|uri| uri.starts_with(SPIFFE_SCHEME))
+        .collect();
+    if spiffe_ids.len() > 1 {
+        anyhow::bail!(
+            "certificate has {} SPIFFE IDs, expected at most one",
+            spiffe_ids.len()
+        );
+    }
+    let spiffe_id = spiffe_ids.first().map(|id| String::from(id.as_str()));
+    if let Some(id) = &spiffe_id {
+        validate_spiffe_id(id)?;
+    }
+
+    let name = match (extract_name_from_certificate(cert)?, &spiffe_id) {
+        (Some(name), _) => name,
+        (None, Some(id)) => id.clone(),
+        (None, None) => anyhow::bail!("certificate has neither a CommonName nor a SPIFFE ID"),
+    };
+
+    Ok(Principal {
+        name,
+        roles: extract_roles_from_certificate(cert)?,
+        uris,
+        emails,
+        spiffe_id,
+    })
+}
+
+#[allow(clippy::result_large_err)]
+fn principal_from_peer_certificate(
+    request: &Request<()>,
+    revocations: Option<&RevocationList>,
+) -> Result<Principal, Status> {
     // it will only fail if we didn't enable tls feature
     let certs = request.peer_certs().unwrap();
     let cert = certs
@@ -55,14 +297,69 @@ pub fn intercept_extract_username_from_certificate(
         .ok_or_else(|| Status::unauthenticated("no certificate found"))?;
     let (_, x509_cert) = x509_parser::parse_x509_certificate(cert.as_ref())
         .map_err(|err| Status::unauthenticated(format!("failed to parse certificate: {}", err)))?;
-    request.extensions_mut().insert(UsernameExtension {
-        username: extract_name_from_certificate(x509_cert).map_err(|err| {
-            Status::unauthenticated(format!("failed to extract username: {}", err))
-        })?,
-    });
+
+    if let Some(revocations) = revocations {
+        if let Err(reason) = revocations.check(cert.as_ref(), &x509_cert) {
+            log::warn!(
+                target: "audit",
+                "rejected revoked certificate: subject=\"{}\" peer={} reason=\"{}\"",
+                x509_cert.subject(),
+                request
+                    .remote_addr()
+                    .map_or_else(|| String::from("unknown"), |addr| addr.to_string()),
+                reason
+            );
+            return Err(Status::unauthenticated("certificate has been revoked"));
+        }
+    }
+
+    extract_principal_from_certificate(&x509_cert)
+        .map_err(|err| Status::unauthenticated(format!("failed to extract principal: {}", err)))
+}
+
+#[allow(clippy::result_large_err)]
+fn authenticate(
+    mut request: Request<()>,
+    revocations: Option<&RevocationList>,
+) -> Result<Request<()>, Status> {
+    let principal = principal_from_peer_certificate(&request, revocations)?;
+    AuditContext::of(&request).principal(&principal.name);
+    request
+        .extensions_mut()
+        .insert(PrincipalExtension { principal });
     Ok(request)
 }
 
+#[allow(clippy::result_large_err)]
+pub fn intercept_extract_principal_from_certificate(
+    request: Request<()>,
+) -> Result<Request<()>, Status> {
+    authenticate(request, None)
+}
+
+// Rejects revoked certificates before extracting the principal.
+// The revocation list is shared, so it can be replaced while the server is running.
+#[derive(Clone)]
+pub struct CertificateInterceptor {
+    revocations: Arc<RwLock<RevocationList>>,
+}
+
+impl CertificateInterceptor {
+    pub fn new(revocations: Arc<RwLock<RevocationList>>) -> Self {
+        Self { revocations }
+    }
+}
+
+impl Interceptor for CertificateInterceptor {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        let revocations = self
+            .revocations
+            .read()
+            .map_err(|_| Status::internal("revocation list is unavailable"))?;
+        authenticate(request, Some(&revocations))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -70,15 +367,57 @@ mod test {
     use std::fs;
     use std::path::PathBuf;
 
-    // I'm testing only for happy path here, but there should also be a lot more tests for invalid certs, invalid common names, etc
-    #[test]
-    fn extract_name_from_certificate_extracts_valid_name() {
+    fn read_test_cert(name: &str) -> Vec<u8> {
         let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        path.push("tests/certs/admin.cert.der");
+        path.push("tests/certs");
+        path.push(name);
+        fs::read(path).unwrap()
+    }
 
-        println!("{:?}", path);
-        let admin_cert = fs::read(path).unwrap();
+    fn principal_error(name: &str) -> String {
+        let der = read_test_cert(name);
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).unwrap();
+        extract_principal_from_certificate(&cert)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn extract_name_from_certificate_extracts_valid_name() {
+        let admin_cert = read_test_cert("admin.cert.der");
         let (_, cert) = x509_parser::parse_x509_certificate(&admin_cert).unwrap();
-        assert_eq!(extract_name_from_certificate(cert).unwrap(), "admin");
+        assert_eq!(
+            extract_name_from_certificate(&cert).unwrap().as_deref(),
+            Some("admin")
+        );
+    }
+
+    #[test]
+    fn extract_principal_from_spiffe_certificate() {
+        let der = read_test_cert("spiffe.cert.der");
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).unwrap();
+        let principal = extract_principal_from_certificate(&cert).unwrap();
+
+        let spiffe_id = "spiffe://example.org/ns/prod/sa/worker";
+        assert_eq!(principal.name, spiffe_id);
+        assert_eq!(principal.spiffe_id.as_deref(), Some(spiffe_id));
+        assert_eq!(principal.roles, vec!["batch", "ops"]);
+        assert_eq!(principal.emails, vec!["worker@example.org"]);
+    }
+
+    #[test]
+    fn extract_principal_rejects_ambiguous_or_missing_identity() {
+        assert_eq!(
+            principal_error("two_cn.cert.der"),
+            "certificate has 2 CommonNames, expected one"
+        );
+        assert_eq!(
+            principal_error("two_spiffe.cert.der"),
+            "certificate has 2 SPIFFE IDs, expected at most one"
+        );
+        assert_eq!(
+            principal_error("no_identity.cert.der"),
+            "certificate has neither a CommonName nor a SPIFFE ID"
+        );
     }
 }
@@ -0,0 +1,203 @@
+// Jobs run under the jobshim binary built next to jobdaemon
+use runtime::auth::Principal;
+use runtime::logstore::{FileLogStore, FileLogStoreConfig};
+use runtime::shim::Shims;
+use runtime::{
+    JobId, JobRequest, JobRuntime, JobStatus, JobStatusResponse, LogMessage, LogRecord,
+    RuntimeCommand, RuntimeSender,
+};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::{mpsc, oneshot};
+
+fn user() -> Principal {
+    Principal::new("user", vec![])
+}
+
+// Each daemon gets a tokio runtime of its own, dropping it stops every task
+// of the daemon the way an exit would. Everything the job runtime keeps across
+// restarts lives in the state directory.
+fn start_daemon(state: &Path) -> (Runtime, RuntimeSender) {
+    let daemon = Runtime::new().unwrap();
+    let shims = Shims::new(
+        PathBuf::from(env!("CARGO_BIN_EXE_jobshim")),
+        state.join("shims"),
+    )
+    .unwrap();
+    let logs = FileLogStore::new(state.join("logs"), FileLogStoreConfig::default()).unwrap();
+    // reattaching to the jobs of the journal spawns tasks on the daemon
+    let context = daemon.enter();
+    let (runtime, cmd_tx) = JobRuntime::new();
+    let runtime = runtime
+        .with_log_store(logs)
+        .with_shims(shims)
+        .with_journal(state.join("journal"))
+        .unwrap();
+    daemon.spawn(runtime.start());
+    drop(context);
+    (daemon, cmd_tx)
+}
+
+fn stop_daemon(daemon: Runtime) {
+    daemon.shutdown_timeout(Duration::from_secs(5));
+}
+
+async fn start_job(cmd_tx: &RuntimeSender, script: &str) -> JobId {
+    let (sender, rx) = oneshot::channel();
+    let request = JobRequest {
+        path: String::from("/bin/sh"),
+        args: vec![String::from("-c"), String::from(script)],
+        ..Default::default()
+    };
+    let command = RuntimeCommand::Start {
+        principal: user(),
+        request,
+        sender,
+    };
+    cmd_tx.send(command).await.unwrap();
+    rx.await.unwrap().unwrap()
+}
+
+async fn stop_job(cmd_tx: &RuntimeSender, job: JobId) {
+    let (sender, rx) = oneshot::channel();
+    let command = RuntimeCommand::Stop {
+        job,
+        principal: user(),
+        sender,
+    };
+    cmd_tx.send(command).await.unwrap();
+    rx.await.unwrap().unwrap();
+}
+
+async fn wait_job(cmd_tx: &RuntimeSender, job: JobId) -> JobStatusResponse {
+    let (sender, rx) = oneshot::channel();
+    let command = RuntimeCommand::Wait {
+        job,
+        principal: user(),
+        sender,
+    };
+    cmd_tx.send(command).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), rx)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+}
+
+// Output of the job so far, or all of it once the job has ended
+async fn output(cmd_tx: &RuntimeSender, job: JobId) -> String {
+    let (sender, mut rx) = mpsc::channel(16);
+    let (result, result_rx) = oneshot::channel();
+    let command = RuntimeCommand::FetchLogs {
+        job,
+        principal: user(),
+        from_seq: 0,
+        sender,
+        result,
+    };
+    cmd_tx.send(command).await.unwrap();
+    result_rx.await.unwrap().unwrap();
+    let mut output = String::new();
+    // a running job keeps the stream open, whatever came within a moment is enough
+    while let Ok(Some(message)) = tokio::time::timeout(Duration::from_millis(500), rx.recv()).await
+    {
+        if let LogMessage::Entry(entry) = message {
+            let (LogRecord::Stdout(data) | LogRecord::Stderr(data)) = entry.record;
+            output.push_str(&String::from_utf8_lossy(&data));
+        }
+    }
+    output
+}
+
+async fn wait_for_output(cmd_tx: &RuntimeSender, job: JobId, expected: &str) {
+    for _ in 0..50 {
+        if output(cmd_tx, job).await == expected {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("job {} never printed {:?}", job, expected);
+}
+
+fn state_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("jobshim-test-{}", uuid::Uuid::new_v4()))
+}
+
+#[test]
+fn job_runs_and_is_killed_through_its_shim() {
+    let state = state_dir();
+    let (daemon, cmd_tx) = start_daemon(&state);
+    daemon.block_on(async {
+        let job = start_job(&cmd_tx, "echo hello; echo oops >&2; exit 3").await;
+        let status = wait_job(&cmd_tx, job).await;
+        assert!(matches!(
+            status.status,
+            JobStatus::Finished { exit_code: 3 }
+        ));
+        // stdout and stderr are read apart, only the order within each of them is kept
+        let output = output(&cmd_tx, job).await;
+        let mut lines: Vec<&str> = output.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(lines, vec!["hello", "oops"]);
+
+        let job = start_job(&cmd_tx, "echo started; exec sleep 30").await;
+        wait_for_output(&cmd_tx, job, "started\n").await;
+        stop_job(&cmd_tx, job).await;
+        let status = wait_job(&cmd_tx, job).await;
+        assert!(matches!(status.status, JobStatus::Killed { signal: 9 }));
+
+        // a path that can't be run fails the job instead of the start
+        let (sender, rx) = oneshot::channel();
+        let request = JobRequest {
+            path: String::from("/nonexistent"),
+            ..Default::default()
+        };
+        let command = RuntimeCommand::Start {
+            principal: user(),
+            request,
+            sender,
+        };
+        cmd_tx.send(command).await.unwrap();
+        let job = rx.await.unwrap().unwrap();
+        let status = wait_job(&cmd_tx, job).await;
+        assert!(matches!(
+            status.status,
+            JobStatus::Finished { exit_code: 127 }
+        ));
+    });
+    stop_daemon(daemon);
+    std::fs::remove_dir_all(state).unwrap();
+}
+
+#[test]
+fn job_is_reattached_after_a_restart() {
+    let state = state_dir();
+    let go = state.join("go");
+    let script = format!(
+        "echo before; while [ ! -e {} ]; do sleep 0.05; done; echo after; exec sleep 30",
+        go.display()
+    );
+
+    let (daemon, cmd_tx) = start_daemon(&state);
+    let job = daemon.block_on(async {
+        let job = start_job(&cmd_tx, &script).await;
+        wait_for_output(&cmd_tx, job, "before\n").await;
+        job
+    });
+    stop_daemon(daemon);
+
+    // the job keeps running while no daemon is around
+    std::fs::write(&go, "").unwrap();
+    let (daemon, cmd_tx) = start_daemon(&state);
+    daemon.block_on(async {
+        wait_for_output(&cmd_tx, job, "before\nafter\n").await;
+
+        stop_job(&cmd_tx, job).await;
+        let status = wait_job(&cmd_tx, job).await;
+        assert!(matches!(status.status, JobStatus::Killed { signal: 9 }));
+        assert_eq!(output(&cmd_tx, job).await, "before\nafter\n");
+    });
+    stop_daemon(daemon);
+    std::fs::remove_dir_all(state).unwrap();
+}
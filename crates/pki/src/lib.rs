@@ -0,0 +1,333 @@
+use anyhow::Context;
+use rustls::sign::{self, SigningKey};
+use rustls::{Certificate, PrivateKey, RootCertStore, SignatureScheme};
+use x509_parser::time::ASN1Time;
+
+// Certificates and keys can be given either in DER or in PEM format,
+// PEM files can contain more than one block, e.g. a certificate chain or a CA bundle.
+
+const PEM_HEADER: &[u8] = b"-----BEGIN ";
+
+fn is_pem(data: &[u8]) -> bool {
+    data.windows(PEM_HEADER.len())
+        .any(|window| window == PEM_HEADER)
+}
+
+struct PemBlock {
+    label: String,
+    contents: Vec<u8>,
+}
+
+// Text outside of the blocks is ignored, openssl writes e.g. a human readable summary there
+fn pem_blocks(data: &[u8]) -> anyhow::Result<Vec<PemBlock>> {
+    let text = std::str::from_utf8(data).context("invalid PEM: not valid UTF-8")?;
+    let mut blocks = vec![];
+    let mut lines = text.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        let label = match line
+            .strip_prefix("-----BEGIN ")
+            .and_then(|rest| rest.strip_suffix("-----"))
+        {
+            Some(label) => label,
+            None => continue,
+        };
+        let end = format!("-----END {}-----", label);
+        let mut encoded = String::new();
+        loop {
+            match lines.next() {
+                Some(line) if line == end => break,
+                Some(line) => encoded.push_str(line),
+                None => anyhow::bail!("invalid PEM: missing {}", end),
+            }
+        }
+        let contents = base64::decode(&encoded)
+            .with_context(|| format!("invalid PEM: {} is not valid base64", label))?;
+        blocks.push(PemBlock {
+            label: String::from(label),
+            contents,
+        });
+    }
+    Ok(blocks)
+}
+
+// Reads a single DER certificate or every CERTIFICATE block of a PEM file,
+// the first certificate is the leaf and the rest is the chain
+pub fn read_certs(data: &[u8]) -> anyhow::Result<Vec<Certificate>> {
+    let certs: Vec<Certificate> = if is_pem(data) {
+        pem_blocks(data)?
+            .into_iter()
+            .filter(|block| block.label == "CERTIFICATE")
+            .map(|block| Certificate(block.contents))
+            .collect()
+    } else {
+        vec![Certificate(data.to_vec())]
+    };
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found");
+    }
+    for cert in &certs {
+        x509_parser::parse_x509_certificate(&cert.0)
+            .map_err(|err| anyhow::anyhow!("invalid certificate: {}", err))?;
+    }
+    Ok(certs)
+}
+
+// Reads a PKCS#8, RSA (PKCS#1) or EC (SEC1) private key. rustls only understands
+// PKCS#8 for EC keys, so SEC1 keys are wrapped into PKCS#8.
+pub fn read_private_key(data: &[u8]) -> anyhow::Result<PrivateKey> {
+    let key = if is_pem(data) {
+        let block = pem_blocks(data)?
+            .into_iter()
+            .find(|block| block.label.ends_with("PRIVATE KEY"))
+            .context("no private key found")?;
+        match block.label.as_str() {
+            "PRIVATE KEY" | "RSA PRIVATE KEY" => PrivateKey(block.contents),
+            "EC PRIVATE KEY" => sec1_to_pkcs8(&block.contents)?,
+            "ENCRYPTED PRIVATE KEY" => anyhow::bail!("encrypted private keys are not supported"),
+            other => anyhow::bail!("unsupported private key type: {}", other),
+        }
+    } else if sign::any_supported_type(&PrivateKey(data.to_vec())).is_ok() {
+        PrivateKey(data.to_vec())
+    } else {
+        sec1_to_pkcs8(data)?
+    };
+    signing_key(&key)?;
+    Ok(key)
+}
+
+pub fn signing_key(key: &PrivateKey) -> anyhow::Result<Box<dyn SigningKey>> {
+    sign::any_supported_type(key).map_err(|_| {
+        anyhow::anyhow!("invalid or unsupported private key, expected RSA, ECDSA or Ed25519")
+    })
+}
+
+// OIDs of id-ecPublicKey and of the curves supported by rustls
+const EC_PUBLIC_KEY_OID: &[u8] = &[0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const EC_CURVE_OIDS: [&[u8]; 2] = [
+    // secp256r1
+    &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07],
+    // secp384r1
+    &[0x06, 0x05, 0x2b, 0x81, 0x04, 0x00, 0x22],
+];
+
+// The SEC1 key may or may not name its curve, instead of parsing it
+// every supported curve is tried and ring rejects the ones that don't match
+fn sec1_to_pkcs8(sec1: &[u8]) -> anyhow::Result<PrivateKey> {
+    EC_CURVE_OIDS
+        .iter()
+        .map(|curve| {
+            let algorithm = der_tlv(0x30, &[EC_PUBLIC_KEY_OID, curve].concat());
+            let info = [&[0x02, 0x01, 0x00][..], &algorithm, &der_tlv(0x04, sec1)].concat();
+            PrivateKey(der_tlv(0x30, &info))
+        })
+        .find(|key| sign::any_ecdsa_type(key).is_ok())
+        .context("invalid or unsupported private key, expected RSA, ECDSA or Ed25519")
+}
+
+fn der_tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|byte| *byte == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+// Reads a certificate chain and the private key of its leaf certificate
+pub fn read_identity(cert: &[u8], key: &[u8]) -> anyhow::Result<(Vec<Certificate>, PrivateKey)> {
+    let certs = read_certs(cert).context("unable to read certificate")?;
+    let key = read_private_key(key).context("unable to read private key")?;
+    check_key_matches(&certs[0], &key)?;
+    Ok((certs, key))
+}
+
+const SCHEMES: [SignatureScheme; 5] = [
+    SignatureScheme::ED25519,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+// Signs a message with the key and verifies it with the public key of the certificate,
+// otherwise a mismatch only shows up as a failed handshake on the other side
+pub fn check_key_matches(cert: &Certificate, key: &PrivateKey) -> anyhow::Result<()> {
+    let signer = signing_key(key)?
+        .choose_scheme(&SCHEMES)
+        .context("private key doesn't support any TLS 1.3 signature scheme")?;
+    let algorithm = match signer.get_scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+    let message = b"jobruntime key check";
+    let signature = signer
+        .sign(message)
+        .map_err(|err| anyhow::anyhow!("unable to sign with private key: {}", err))?;
+    webpki::EndEntityCert::from(&cert.0)
+        .and_then(|cert| cert.verify_signature(algorithm, message, &signature))
+        .map_err(|_| anyhow::anyhow!("private key doesn't match the certificate"))
+}
+
+// Every CA file is either a single DER certificate or a PEM bundle with one or more certificates.
+// All of them are trusted, so a new CA can be rolled out before the old one is retired.
+pub fn load_roots(ca_certs: &[Vec<u8>]) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for ca in ca_certs {
+        for cert in read_certs(ca).context("unable to read CA certificates")? {
+            roots
+                .add(&cert)
+                .map_err(|err| anyhow::anyhow!("invalid CA certificate: {}", err))?;
+        }
+    }
+    if roots.is_empty() {
+        anyhow::bail!("no CA certificates found");
+    }
+    Ok(roots)
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+// Validity period of a certificate, times are seconds since the Unix epoch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CertificateValidity {
+    pub subject: String,
+    pub not_before: i64,
+    pub not_after: i64,
+}
+
+impl CertificateValidity {
+    pub fn of(der: &[u8]) -> anyhow::Result<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|err| anyhow::anyhow!("invalid certificate: {}", err))?;
+        let validity = cert.validity();
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            not_before: validity.not_before.timestamp(),
+            not_after: validity.not_after.timestamp(),
+        })
+    }
+
+    pub fn ensure_valid(&self) -> anyhow::Result<()> {
+        let now = now();
+        if now < self.not_before {
+            anyhow::bail!(
+                "certificate {} is not valid before {}",
+                self.subject,
+                format_timestamp(self.not_before)
+            );
+        }
+        if now > self.not_after {
+            anyhow::bail!(
+                "certificate {} expired on {}",
+                self.subject,
+                format_timestamp(self.not_after)
+            );
+        }
+        Ok(())
+    }
+
+    // Whole days left until the certificate expires, negative once it has expired
+    pub fn days_left(&self) -> i64 {
+        (self.not_after - now()).div_euclid(SECONDS_PER_DAY)
+    }
+
+    pub fn expires_within(&self, days: u64) -> bool {
+        self.days_left() < days as i64
+    }
+}
+
+pub fn now() -> i64 {
+    ASN1Time::now().timestamp()
+}
+
+pub fn format_timestamp(timestamp: i64) -> String {
+    ASN1Time::from_timestamp(timestamp).to_rfc2822()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn read_fixture(path: &str) -> Vec<u8> {
+        fs::read(format!("{}/{}", env!("CARGO_MANIFEST_DIR"), path)).unwrap()
+    }
+
+    #[test]
+    fn pem_and_der_are_read_the_same() {
+        let chain = read_certs(&read_fixture("tests/certs/admin.cert.pem")).unwrap();
+        let der = read_certs(&read_fixture("../../certs/admin.cert.der")).unwrap();
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain[0], der[0]);
+
+        let pem_key = read_private_key(&read_fixture("tests/certs/admin.key.pem")).unwrap();
+        let der_key = read_private_key(&read_fixture("../../certs/admin.key.der")).unwrap();
+        assert_eq!(pem_key, der_key);
+
+        let roots = load_roots(&[read_fixture("tests/certs/ca.pem")]).unwrap();
+        assert_eq!(roots.len(), 2);
+    }
+
+    #[test]
+    fn rsa_and_ec_keys_match_their_certificates() {
+        let identities = [
+            ("tests/certs/rsa.cert.pem", "tests/certs/rsa.key.pem"),
+            ("tests/certs/ec.cert.der", "tests/certs/ec.key.pem"),
+            ("tests/certs/ec.cert.der", "tests/certs/ec.key.der"),
+        ];
+        for (cert, key) in identities {
+            read_identity(&read_fixture(cert), &read_fixture(key)).unwrap();
+        }
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        let cert = read_fixture("../../certs/server.cert.der");
+        for key in ["../../certs/invalid.pem", "../../certs/invalid.der"] {
+            let err = read_identity(&cert, &read_fixture(key)).unwrap_err();
+            assert_eq!(err.to_string(), "private key doesn't match the certificate");
+        }
+
+        let err = read_certs(&read_fixture("../../certs/invalid.pem")).unwrap_err();
+        assert_eq!(err.to_string(), "no certificate found");
+        assert!(read_certs(&read_fixture("../../certs/invalid.der")).is_err());
+        assert!(read_private_key(b"not a key").is_err());
+        assert!(load_roots(&[]).is_err());
+    }
+
+    #[test]
+    fn validity_reports_expired_and_expiring_certificates() {
+        let cert = &read_certs(&read_fixture("../../certs/admin.cert.der")).unwrap()[0];
+        let mut validity = CertificateValidity::of(&cert.0).unwrap();
+        assert_eq!(validity.subject, "CN=admin");
+        assert!(validity.ensure_valid().is_ok());
+        assert!(!validity.expires_within(30));
+
+        validity.not_after = now() + 10 * SECONDS_PER_DAY + 60;
+        assert_eq!(validity.days_left(), 10);
+        assert!(validity.expires_within(30));
+
+        validity.not_after = now() - 60;
+        assert!(validity
+            .ensure_valid()
+            .unwrap_err()
+            .to_string()
+            .starts_with("certificate CN=admin expired on"));
+
+        validity.not_before = now() + 60;
+        assert!(validity.ensure_valid().is_err());
+    }
+}
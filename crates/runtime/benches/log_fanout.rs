@@ -0,0 +1,105 @@
+// Measures how fast the output of a single noisy job is delivered to many followers.
+// Every follower is the equivalent of one FetchJobLogs stream.
+//
+// Run with: cargo bench -p runtime --bench log_fanout
+use runtime::auth::Principal;
+use runtime::{JobRequest, JobRuntime, LogMessage, RuntimeCommand, LOG_CHANNEL_SIZE};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+
+const JOB_OUTPUT_BYTES: u64 = 64 * 1024 * 1024;
+const FOLLOWERS: [usize; 3] = [1, 100, 1000];
+
+#[derive(Default)]
+struct FollowerStats {
+    bytes: u64,
+    missed: u64,
+}
+
+async fn follow(mut rx: mpsc::Receiver<LogMessage>) -> FollowerStats {
+    let mut stats = FollowerStats::default();
+    while let Some(message) = rx.recv().await {
+        match message {
+            LogMessage::Entry(entry) => match entry.record {
+                runtime::LogRecord::Stdout(data) | runtime::LogRecord::Stderr(data) => {
+                    stats.bytes += data.len() as u64
+                }
+            },
+            LogMessage::Lagged { missed } | LogMessage::Disconnected { missed } => {
+                stats.missed += missed
+            }
+        }
+    }
+    stats
+}
+
+async fn run(followers: usize) -> (Duration, Vec<FollowerStats>) {
+    let (rt, cmd_tx) = JobRuntime::new();
+    rt.start().await;
+
+    let (sender, rx) = oneshot::channel();
+    cmd_tx
+        .send(RuntimeCommand::Start {
+            principal: Principal::new("bench", vec![]),
+            request: JobRequest {
+                path: String::from("head"),
+                args: vec![
+                    String::from("-c"),
+                    JOB_OUTPUT_BYTES.to_string(),
+                    String::from("/dev/zero"),
+                ],
+                ..Default::default()
+            },
+            sender,
+        })
+        .await
+        .unwrap();
+    let job = rx.await.unwrap().unwrap();
+
+    let started = Instant::now();
+    let mut handles = Vec::with_capacity(followers);
+    for _ in 0..followers {
+        let (sender, rx) = mpsc::channel(LOG_CHANNEL_SIZE);
+        let (result, result_rx) = oneshot::channel();
+        cmd_tx
+            .send(RuntimeCommand::FetchLogs {
+                job,
+                principal: Principal::new("bench", vec![]),
+                from_seq: 0,
+                sender,
+                result,
+            })
+            .await
+            .unwrap();
+        result_rx.await.unwrap().unwrap();
+        handles.push(tokio::spawn(follow(rx)));
+    }
+
+    let mut stats = Vec::with_capacity(followers);
+    for handle in handles {
+        stats.push(handle.await.unwrap());
+    }
+    (started.elapsed(), stats)
+}
+
+#[tokio::main]
+async fn main() {
+    println!(
+        "{:>10} {:>12} {:>16} {:>16} {:>14}",
+        "followers", "elapsed", "delivered MiB", "aggregate MiB/s", "missed records"
+    );
+    for followers in FOLLOWERS {
+        let (elapsed, stats) = run(followers).await;
+        let delivered: u64 = stats.iter().map(|stats| stats.bytes).sum();
+        let missed: u64 = stats.iter().map(|stats| stats.missed).sum();
+        let delivered_mib = delivered as f64 / (1024.0 * 1024.0);
+        println!(
+            "{:>10} {:>12.2?} {:>16.1} {:>16.1} {:>14}",
+            followers,
+            elapsed,
+            delivered_mib,
+            delivered_mib / elapsed.as_secs_f64(),
+            missed
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+// Authenticated caller of the runtime
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Principal {
+    pub name: String,
+    pub roles: Vec<String>,
+    // other identities of the caller, e.g. taken from the SAN extension of its certificate
+    pub uris: Vec<String>,
+    pub emails: Vec<String>,
+    pub spiffe_id: Option<String>,
+}
+
+impl Principal {
+    pub fn new(name: impl Into<String>, roles: Vec<String>) -> Self {
+        Self {
+            name: name.into(),
+            roles,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Start,
+    Stop,
+    Status,
+    Logs,
+    // write to stdin of a job, attach to its terminal or resize it
+    Stdin,
+    // see which jobs exist, e.g. by watching their lifecycle events
+    List,
+    Signal,
+    // see resource usage and quota of a user
+    Usage,
+    // create, pause, resume and delete schedules of recurring jobs
+    Schedule,
+    // forget a finished job together with its logs
+    Delete,
+    // give jobs a priority outside of the range every user may use, needs the "any" scope
+    Priority,
+}
+
+// Whether the job belongs to the caller or to someone else
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Own,
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Action {
+    pub operation: Operation,
+    pub scope: Scope,
+}
+
+impl Action {
+    pub fn new(operation: Operation, scope: Scope) -> Self {
+        Self { operation, scope }
+    }
+}
+
+// Decides whether a principal is allowed to perform an action
+pub trait Authorizer: Send + Sync {
+    fn authorize(&self, principal: &Principal, action: Action) -> bool;
+
+    // All roles of the principal, used to find its quota
+    fn roles(&self, principal: &Principal) -> Vec<String> {
+        principal.roles.clone()
+    }
+}
+
+// Permission in the policy file, written as "operation" or "operation:scope", e.g. "stop:any".
+// Without a scope it only applies to own jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Permission(Action);
+
+impl TryFrom<String> for Permission {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let (operation, scope) = match value.split_once(':') {
+            Some((operation, scope)) => (operation, scope),
+            None => (value.as_str(), "own"),
+        };
+        let operation = match operation {
+            "start" => Operation::Start,
+            "stop" => Operation::Stop,
+            "status" => Operation::Status,
+            "logs" => Operation::Logs,
+            "stdin" => Operation::Stdin,
+            "list" => Operation::List,
+            "signal" => Operation::Signal,
+            "usage" => Operation::Usage,
+            "schedule" => Operation::Schedule,
+            "delete" => Operation::Delete,
+            "priority" => Operation::Priority,
+            other => return Err(format!("unknown operation: {}", other)),
+        };
+        let scope = match scope {
+            "own" => Scope::Own,
+            "any" => Scope::Any,
+            other => return Err(format!("unknown scope: {}", other)),
+        };
+        Ok(Permission(Action::new(operation, scope)))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Role {
+    #[serde(default)]
+    pub permissions: HashSet<Permission>,
+}
+
+// Role based access control. Roles of a principal are the ones from its certificate,
+// the ones bound to its name in the policy and the default roles given to everyone.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rbac {
+    #[serde(default)]
+    pub default_roles: Vec<String>,
+    #[serde(default)]
+    pub roles: HashMap<String, Role>,
+    #[serde(default)]
+    pub users: HashMap<String, Vec<String>>,
+}
+
+pub const ADMIN_ROLE: &str = "admin";
+pub const USER_ROLE: &str = "user";
+
+const OPERATIONS: [Operation; 11] = [
+    Operation::Start,
+    Operation::Stop,
+    Operation::Status,
+    Operation::Logs,
+    Operation::Stdin,
+    Operation::List,
+    Operation::Signal,
+    Operation::Usage,
+    Operation::Schedule,
+    Operation::Delete,
+    Operation::Priority,
+];
+
+impl Default for Rbac {
+    // Admin can do anything with any job, a user only with its own jobs
+    fn default() -> Self {
+        let permissions = |scope| {
+            OPERATIONS
+                .iter()
+                .map(|operation| Permission(Action::new(*operation, scope)))
+                .collect()
+        };
+        Self {
+            default_roles: vec![String::from(USER_ROLE)],
+            roles: HashMap::from([
+                (
+                    String::from(ADMIN_ROLE),
+                    Role {
+                        permissions: permissions(Scope::Any),
+                    },
+                ),
+                (
+                    String::from(USER_ROLE),
+                    Role {
+                        permissions: permissions(Scope::Own),
+                    },
+                ),
+            ]),
+            // admins get their role from the certificate, never from their name
+            users: HashMap::new(),
+        }
+    }
+}
+
+impl Rbac {
+    // Lists roles that are referenced but never defined, they don't grant anything
+    pub fn undefined_roles(&self) -> Vec<&str> {
+        let mut undefined: Vec<&str> = self
+            .default_roles
+            .iter()
+            .chain(self.users.values().flatten())
+            .map(String::as_str)
+            .filter(|role| !self.roles.contains_key(*role))
+            .collect();
+        undefined.sort_unstable();
+        undefined.dedup();
+        undefined
+    }
+
+    fn roles_of<'a>(&'a self, principal: &'a Principal) -> impl Iterator<Item = &'a String> {
+        principal
+            .roles
+            .iter()
+            .chain(self.users.get(&principal.name).into_iter().flatten())
+            .chain(self.default_roles.iter())
+    }
+}
+
+impl Authorizer for Rbac {
+    fn authorize(&self, principal: &Principal, action: Action) -> bool {
+        // permission for any job includes own jobs
+        let any = Permission(Action::new(action.operation, Scope::Any));
+        let wanted = Permission(action);
+        self.roles_of(principal)
+            .filter_map(|role| self.roles.get(role))
+            .any(|role| role.permissions.contains(&wanted) || role.permissions.contains(&any))
+    }
+
+    fn roles(&self, principal: &Principal) -> Vec<String> {
+        let mut roles: Vec<String> = self.roles_of(principal).cloned().collect();
+        roles.sort_unstable();
+        roles.dedup();
+        roles
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_policy_matches_admin_and_user_roles() {
+        let rbac = Rbac::default();
+        let admin = Principal::new("admin", vec![]);
+        let operator = Principal::new("alice", vec![String::from(ADMIN_ROLE)]);
+        let user = Principal::new("bob", vec![]);
+
+        assert!(rbac.authorize(&operator, Action::new(Operation::Stop, Scope::Any)));
+        // a certificate named admin is not enough
+        assert!(!rbac.authorize(&admin, Action::new(Operation::Stop, Scope::Any)));
+        assert!(rbac.authorize(&user, Action::new(Operation::Stop, Scope::Own)));
+        assert!(!rbac.authorize(&user, Action::new(Operation::Stop, Scope::Any)));
+    }
+
+    #[test]
+    fn permission_is_parsed_with_optional_scope() {
+        let parse = |value: &str| Permission::try_from(String::from(value));
+        assert_eq!(
+            parse("logs:any"),
+            Ok(Permission(Action::new(Operation::Logs, Scope::Any)))
+        );
+        assert_eq!(
+            parse("start"),
+            Ok(Permission(Action::new(Operation::Start, Scope::Own)))
+        );
+        assert!(parse("reboot").is_err());
+        assert!(parse("stop:everyone").is_err());
+    }
+}
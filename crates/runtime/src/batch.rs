@@ -0,0 +1,67 @@
+use crate::{JobId, JobRequest, RuntimeError};
+use uuid::Uuid;
+
+pub type BatchId = Uuid;
+
+// Label put on every job of a batch, its value is the batch id
+pub const BATCH_LABEL: &str = "jobruntime/batch";
+// Label with the index of an array job
+pub const ARRAY_INDEX_LABEL: &str = "jobruntime/array-index";
+// Environment variable with the index of an array job
+pub const ARRAY_INDEX_ENV: &str = "JOB_ARRAY_INDEX";
+// Replaced with the index of an array job in its arguments
+pub const ARRAY_INDEX_PLACEHOLDER: &str = "{index}";
+// More jobs than that in one request are most likely a mistake
+pub const MAX_BATCH_SIZE: usize = 10_000;
+
+#[derive(Debug)]
+pub struct StartedBatch {
+    pub id: BatchId,
+    // result of every job in the order of the request, all of them are Ok in an atomic batch
+    pub jobs: Vec<Result<JobId, RuntimeError>>,
+}
+
+// Copies of the template for indexes 0..count, with the index in the arguments and in the environment
+pub fn expand_array(template: &JobRequest, count: usize) -> Vec<JobRequest> {
+    (0..count)
+        .map(|index| {
+            let index = index.to_string();
+            let mut request = template.clone();
+            request.path = request.path.replace(ARRAY_INDEX_PLACEHOLDER, &index);
+            for arg in request.args.iter_mut() {
+                *arg = arg.replace(ARRAY_INDEX_PLACEHOLDER, &index);
+            }
+            request
+                .env
+                .insert(String::from(ARRAY_INDEX_ENV), index.clone());
+            request
+                .labels
+                .insert(String::from(ARRAY_INDEX_LABEL), index);
+            request
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn array_index_is_substituted_into_args_and_env() {
+        let template = JobRequest {
+            path: String::from("/usr/bin/sweep"),
+            args: vec![
+                String::from("--seed={index}"),
+                String::from("out/{index}.csv"),
+            ],
+            ..Default::default()
+        };
+        let requests = expand_array(&template, 3);
+
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].args, vec!["--seed=2", "out/2.csv"]);
+        assert_eq!(requests[2].env[ARRAY_INDEX_ENV], "2");
+        assert_eq!(requests[0].labels[ARRAY_INDEX_LABEL], "0");
+        assert_eq!(template.args[0], "--seed={index}");
+    }
+}
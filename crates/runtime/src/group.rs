@@ -0,0 +1,156 @@
+use crate::limits::{Cgroup, ResourceLimits};
+use crate::{JobId, JobStatus, JobStatusResponse, Owner};
+
+// Label put on every job of a group, its value is the group name
+pub const GROUP_LABEL: &str = "jobruntime/group";
+// Group cgroups live next to the cgroups of jobs, the prefix keeps them apart
+const CGROUP_PREFIX: &str = "group-";
+const MAX_NAME_LENGTH: usize = 64;
+
+// Jobs started with the same group name, e.g. processes of a service that live and die together.
+// The group belongs to the user that started its first job.
+pub struct Group {
+    pub name: String,
+    pub owner: Owner,
+    // applied to the cgroup of the group, so they are shared by all of its jobs
+    pub limits: ResourceLimits,
+    pub jobs: Vec<JobId>,
+    cgroup: Option<Cgroup>,
+}
+
+impl Group {
+    pub fn new(name: String, owner: Owner) -> Self {
+        Self {
+            name,
+            owner,
+            limits: ResourceLimits::default(),
+            jobs: Vec::new(),
+            cgroup: None,
+        }
+    }
+
+    // Creates the cgroup of the group under the cgroup of the runtime,
+    // cgroups of its jobs are created inside of it
+    pub fn create_cgroup(&mut self, runtime_cgroup: &Cgroup) -> std::io::Result<()> {
+        let name = format!("{}{}", CGROUP_PREFIX, self.name);
+        let cgroup = Cgroup::new_relative_to(runtime_cgroup, &name)?;
+        cgroup.enable_controllers()?;
+        cgroup.apply_limits(self.limits.clone())?;
+        self.cgroup = Some(cgroup);
+        Ok(())
+    }
+
+    pub fn set_limits(&mut self, limits: ResourceLimits) -> std::io::Result<()> {
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.apply_limits(limits.clone())?;
+        }
+        self.limits = limits;
+        Ok(())
+    }
+
+    pub fn cgroup(&self) -> Option<&Cgroup> {
+        self.cgroup.as_ref()
+    }
+
+    pub fn take_cgroup(&mut self) -> Option<Cgroup> {
+        self.cgroup.take()
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "group name must have 1 to {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+    // the name is used as a directory name of the cgroup
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !name.starts_with('.');
+    if !valid {
+        return Err(format!(
+            "group name {} may only contain letters, digits, '-', '_' and '.'",
+            name
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GroupSummary {
+    pub pending: usize,
+    pub running: usize,
+    pub succeeded: usize,
+    // exited with another code than 0, killed, cancelled or lost
+    pub failed: usize,
+}
+
+#[derive(Debug)]
+pub struct GroupStatus {
+    pub name: String,
+    pub owner: Owner,
+    pub limits: ResourceLimits,
+    pub jobs: Vec<JobStatusResponse>,
+}
+
+impl GroupStatus {
+    pub fn summary(&self) -> GroupSummary {
+        let mut summary = GroupSummary::default();
+        for job in &self.jobs {
+            match job.status {
+                JobStatus::Pending => summary.pending += 1,
+                JobStatus::Running { .. } => summary.running += 1,
+                JobStatus::Finished { exit_code: 0 } => summary.succeeded += 1,
+                JobStatus::Finished { .. }
+                | JobStatus::Killed { .. }
+                | JobStatus::Cancelled { .. }
+                | JobStatus::Lost { .. } => summary.failed += 1,
+            }
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn summary_counts_jobs_by_state() {
+        let job = |status| JobStatusResponse {
+            job: Uuid::new_v4(),
+            owner: String::from("user"),
+            status,
+            queue_position: None,
+            waiting_for: vec![],
+        };
+        let status = GroupStatus {
+            name: String::from("web"),
+            owner: String::from("user"),
+            limits: ResourceLimits::default(),
+            jobs: vec![
+                job(JobStatus::Running { pid: 10 }),
+                job(JobStatus::Running { pid: 11 }),
+                job(JobStatus::Finished { exit_code: 0 }),
+                job(JobStatus::Killed { signal: 9 }),
+                job(JobStatus::Pending),
+            ],
+        };
+        assert_eq!(
+            status.summary(),
+            GroupSummary {
+                pending: 1,
+                running: 2,
+                succeeded: 1,
+                failed: 1,
+            }
+        );
+
+        assert!(validate_name("web-1.blue").is_ok());
+        assert!(validate_name("../jobruntime").is_err());
+        assert!(validate_name("").is_err());
+    }
+}
@@ -0,0 +1,457 @@
+use crate::limits::ResourceLimits;
+use crate::{JobId, JobStatus, Labels, Owner};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Reason of jobs that were still pending when the daemon stopped
+pub const NOT_STARTED_REASON: &str = "the daemon restarted before the job started";
+
+// Deleted jobs only go away when the journal is compacted, the writer compacts it
+// on its own once that many were deleted
+const COMPACT_AFTER_DELETED: usize = 1000;
+
+// One line of the journal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JournalEntry {
+    Created {
+        job: JobId,
+        owner: Owner,
+        // path followed by the arguments
+        command: Vec<String>,
+        #[serde(default)]
+        labels: Labels,
+        #[serde(default)]
+        limits: ResourceLimits,
+        #[serde(default)]
+        pool: Option<String>,
+        // where the log store keeps the output of the job, if it keeps it on disk
+        #[serde(default)]
+        logs: Option<PathBuf>,
+    },
+    Started {
+        job: JobId,
+        pid: i32,
+    },
+    // ended is in milliseconds since the epoch, journals of older daemons don't have it
+    Exited {
+        job: JobId,
+        exit_code: i32,
+        #[serde(default)]
+        ended: Option<u64>,
+    },
+    Killed {
+        job: JobId,
+        signal: i32,
+        #[serde(default)]
+        ended: Option<u64>,
+    },
+    Cancelled {
+        job: JobId,
+        reason: String,
+        #[serde(default)]
+        ended: Option<u64>,
+    },
+    // The job was running when the daemon stopped
+    Lost {
+        job: JobId,
+        pid: i32,
+        #[serde(default)]
+        ended: Option<u64>,
+    },
+    // The job and its logs were deleted, it's dropped from the journal when it's compacted
+    Deleted {
+        job: JobId,
+    },
+}
+
+impl JournalEntry {
+    fn job(&self) -> JobId {
+        match self {
+            JournalEntry::Created { job, .. }
+            | JournalEntry::Started { job, .. }
+            | JournalEntry::Exited { job, .. }
+            | JournalEntry::Killed { job, .. }
+            | JournalEntry::Cancelled { job, .. }
+            | JournalEntry::Lost { job, .. }
+            | JournalEntry::Deleted { job } => *job,
+        }
+    }
+}
+
+// Last known state of a job, as replayed from the journal
+#[derive(Debug, Clone)]
+pub struct JobRecord {
+    pub job: JobId,
+    pub owner: Owner,
+    pub command: Vec<String>,
+    pub labels: Labels,
+    pub limits: ResourceLimits,
+    pub pool: Option<String>,
+    pub logs: Option<PathBuf>,
+    pub status: JobStatus,
+    // when the job ended, if it did and the journal knows
+    pub ended: Option<SystemTime>,
+}
+
+impl JobRecord {
+    fn apply(&mut self, entry: JournalEntry) {
+        let (status, ended) = match entry {
+            JournalEntry::Created { .. } | JournalEntry::Deleted { .. } => return,
+            JournalEntry::Started { pid, .. } => (JobStatus::Running { pid }, None),
+            JournalEntry::Exited {
+                exit_code, ended, ..
+            } => (JobStatus::Finished { exit_code }, ended),
+            JournalEntry::Killed { signal, ended, .. } => (JobStatus::Killed { signal }, ended),
+            JournalEntry::Cancelled { reason, ended, .. } => {
+                (JobStatus::Cancelled { reason }, ended)
+            }
+            JournalEntry::Lost { pid, ended, .. } => (JobStatus::Lost { pid }, ended),
+        };
+        self.status = status;
+        self.ended = ended.map(from_millis);
+    }
+
+    // Entries that bring a new journal to the same state
+    fn entries(&self) -> Vec<JournalEntry> {
+        let job = self.job;
+        let created = JournalEntry::Created {
+            job,
+            owner: self.owner.clone(),
+            command: self.command.clone(),
+            labels: self.labels.clone(),
+            limits: self.limits.clone(),
+            pool: self.pool.clone(),
+            logs: self.logs.clone(),
+        };
+        let ended = self.ended.map(millis);
+        let status = match &self.status {
+            JobStatus::Pending => None,
+            JobStatus::Running { pid } => Some(JournalEntry::Started { job, pid: *pid }),
+            JobStatus::Finished { exit_code } => Some(JournalEntry::Exited {
+                job,
+                exit_code: *exit_code,
+                ended,
+            }),
+            JobStatus::Killed { signal } => Some(JournalEntry::Killed {
+                job,
+                signal: *signal,
+                ended,
+            }),
+            JobStatus::Cancelled { reason } => Some(JournalEntry::Cancelled {
+                job,
+                reason: reason.clone(),
+                ended,
+            }),
+            JobStatus::Lost { pid } => Some(JournalEntry::Lost {
+                job,
+                pid: *pid,
+                ended,
+            }),
+        };
+        std::iter::once(created).chain(status).collect()
+    }
+}
+
+// Milliseconds since the epoch, as kept in the journal
+pub fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+// Append-only file with the specs and status transitions of all jobs, one JSON entry per line.
+// Entries are written by a thread of their own, so the runtime never waits for the disk.
+// The thread writes the entries that piled up at once and flushes them as soon as it has
+// caught up, so they survive the daemon dying but not necessarily the machine.
+pub struct Journal {
+    path: PathBuf,
+    entries: Option<mpsc::Sender<JournalEntry>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl Journal {
+    // Replays the journal and rewrites it with the final state of every job only, so it doesn't grow forever.
+    // Jobs that were pending won't start anymore, the ones that were running are left to the caller,
+    // they may still be running under a shim.
+    pub fn open(path: PathBuf) -> io::Result<(Self, Vec<JobRecord>)> {
+        let mut records = match File::open(&path) {
+            Ok(file) => replay(BufReader::new(file))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        let now = SystemTime::now();
+        for record in records.iter_mut() {
+            if let JobStatus::Pending = record.status {
+                record.status = JobStatus::Cancelled {
+                    reason: String::from(NOT_STARTED_REASON),
+                };
+                record.ended = Some(now);
+            }
+        }
+
+        compact(&path, &records)?;
+        let file = open_for_append(&path)?;
+        let (entries, rx) = mpsc::channel();
+        let writer_path = path.clone();
+        let writer = thread::Builder::new()
+            .name(String::from("journal"))
+            .spawn(move || write_entries(writer_path, file, rx))?;
+        Ok((
+            Self {
+                path,
+                entries: Some(entries),
+                writer: Some(writer),
+            },
+            records,
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Errors of writing the entry are only logged by the writer
+    pub fn record(&self, entry: &JournalEntry) -> io::Result<()> {
+        match &self.entries {
+            Some(entries) if entries.send(entry.clone()).is_ok() => Ok(()),
+            _ => Err(io::Error::other("journal writer has stopped")),
+        }
+    }
+}
+
+// Lines recorded before the daemon stops are written before the file is closed
+impl Drop for Journal {
+    fn drop(&mut self) {
+        self.entries.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn write_entries(path: PathBuf, file: File, entries: mpsc::Receiver<JournalEntry>) {
+    let mut file = BufWriter::new(file);
+    let mut deleted = 0;
+    while let Ok(entry) = entries.recv() {
+        let result = std::iter::once(entry)
+            .chain(entries.try_iter())
+            .try_for_each(|entry| {
+                if let JournalEntry::Deleted { .. } = entry {
+                    deleted += 1;
+                }
+                serde_json::to_writer(&mut file, &entry)?;
+                file.write_all(b"\n")
+            })
+            .and_then(|_| file.flush());
+        if let Err(err) = result {
+            log::error!("unable to write journal {}: {}", path.display(), err);
+        }
+
+        // everything is on disk at this point, so the file has the whole state
+        if deleted >= COMPACT_AFTER_DELETED {
+            deleted = 0;
+            match compact_in_place(&path) {
+                Ok(compacted) => file = BufWriter::new(compacted),
+                Err(err) => log::error!("unable to compact journal {}: {}", path.display(), err),
+            }
+        }
+    }
+}
+
+// Rewrites the journal without the deleted jobs and returns the new file to append to
+fn compact_in_place(path: &Path) -> io::Result<File> {
+    let records = replay(BufReader::new(File::open(path)?))?;
+    compact(path, &records)?;
+    open_for_append(path)
+}
+
+fn open_for_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+fn replay<R: BufRead>(reader: R) -> io::Result<Vec<JobRecord>> {
+    let mut records: Vec<JobRecord> = vec![];
+    let mut indexes: HashMap<JobId, usize> = HashMap::new();
+    let mut deleted = HashSet::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // the last line is cut short when the daemon died while writing it
+        let entry: JournalEntry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(err) => {
+                log::warn!("skipping invalid journal line {}: {}", number + 1, err);
+                continue;
+            }
+        };
+        let index = indexes.get(&entry.job()).copied();
+        match (entry, index) {
+            (
+                JournalEntry::Created {
+                    job,
+                    owner,
+                    command,
+                    labels,
+                    limits,
+                    pool,
+                    logs,
+                },
+                None,
+            ) => {
+                indexes.insert(job, records.len());
+                records.push(JobRecord {
+                    job,
+                    owner,
+                    command,
+                    labels,
+                    limits,
+                    pool,
+                    logs,
+                    status: JobStatus::Pending,
+                    ended: None,
+                });
+            }
+            (JournalEntry::Deleted { job }, Some(_)) => {
+                deleted.insert(job);
+            }
+            (entry, Some(index)) => records[index].apply(entry),
+            (entry, None) => log::warn!("journal entry of unknown job {}", entry.job()),
+        }
+    }
+    records.retain(|record| !deleted.contains(&record.job));
+    Ok(records)
+}
+
+// Writes the records to a new file that replaces the journal at once
+fn compact(path: &Path, records: &[JobRecord]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        for entry in records.iter().flat_map(JobRecord::entries) {
+            serde_json::to_writer(&mut file, &entry)?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn created(job: JobId) -> JournalEntry {
+        JournalEntry::Created {
+            job,
+            owner: String::from("user"),
+            command: vec![String::from("/bin/true")],
+            labels: Labels::new(),
+            limits: ResourceLimits::default(),
+            pool: None,
+            logs: None,
+        }
+    }
+
+    #[test]
+    fn journal_is_replayed_after_restart() {
+        let path = std::env::temp_dir().join(format!("jobruntime-journal-{}", Uuid::new_v4()));
+        let (finished, running, pending, deleted) = (
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+            Uuid::new_v4(),
+        );
+        {
+            let (journal, records) = Journal::open(path.clone()).unwrap();
+            assert!(records.is_empty());
+            for entry in [
+                created(finished),
+                created(running),
+                JournalEntry::Started {
+                    job: finished,
+                    pid: 10,
+                },
+                JournalEntry::Started {
+                    job: running,
+                    pid: 11,
+                },
+                JournalEntry::Exited {
+                    job: finished,
+                    exit_code: 3,
+                    ended: Some(1_000),
+                },
+                created(pending),
+                created(deleted),
+                JournalEntry::Deleted { job: deleted },
+            ] {
+                journal.record(&entry).unwrap();
+            }
+        }
+        // a line cut short by a crash
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"event\":\"exited\",\"jo").unwrap();
+
+        let (_, records) = Journal::open(path.clone()).unwrap();
+        let statuses: Vec<_> = records.iter().map(|record| record.status.clone()).collect();
+        assert!(matches!(statuses[0], JobStatus::Finished { exit_code: 3 }));
+        assert!(matches!(statuses[1], JobStatus::Running { pid: 11 }));
+        assert!(matches!(statuses[2], JobStatus::Cancelled { .. }));
+        assert_eq!(records[0].ended, Some(from_millis(1_000)));
+        assert_eq!(records[1].ended, None);
+        let cancelled = records[2].ended.map(millis);
+        assert!(cancelled.is_some());
+
+        // the compacted journal keeps the same state
+        let (_, records) = Journal::open(path.clone()).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(matches!(records[1].status, JobStatus::Running { pid: 11 }));
+        assert_eq!(records[0].ended, Some(from_millis(1_000)));
+        assert_eq!(records[2].ended.map(millis), cancelled);
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 6);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn journal_is_compacted_once_enough_jobs_were_deleted() {
+        let path = std::env::temp_dir().join(format!("jobruntime-journal-{}", Uuid::new_v4()));
+        let kept = Uuid::new_v4();
+        {
+            let (journal, _) = Journal::open(path.clone()).unwrap();
+            journal.record(&created(kept)).unwrap();
+            for _ in 0..COMPACT_AFTER_DELETED {
+                let job = Uuid::new_v4();
+                journal.record(&created(job)).unwrap();
+                journal.record(&JournalEntry::Deleted { job }).unwrap();
+            }
+        }
+        // compacted by the writer, not by opening the journal again
+        let lines = fs::read_to_string(&path).unwrap();
+        assert_eq!(lines.lines().count(), 1);
+        assert!(lines.contains(&kept.to_string()));
+        fs::remove_file(path).unwrap();
+    }
+}
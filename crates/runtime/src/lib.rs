@@ -1,34 +1,99 @@
+pub mod auth;
+pub mod batch;
+pub mod group;
+pub mod journal;
 pub mod limits;
+pub mod logstore;
+pub mod pty;
+pub mod quota;
+pub mod retention;
+pub mod schedule;
+pub mod scheduler;
+pub mod shim;
+pub mod workflow;
 
+use auth::{Action, Authorizer, Operation, Principal, Rbac, Scope};
+use batch::{BatchId, StartedBatch};
 use bytes::{Bytes, BytesMut};
+use group::{Group, GroupStatus};
+use journal::{JobRecord, Journal, JournalEntry};
 use limits::{Cgroup, ResourceLimits};
-use std::collections::{HashMap, HashSet};
-use std::hash::{Hash, Hasher};
+use logstore::{LogEntries, LogStore, MemoryLogStore, DEFAULT_MAX_JOB_BYTES};
+use pty::{Pty, PtyMaster};
+use quota::{QuotaPolicy, QuotaUsage, Usage};
+use retention::{FinishedJob, RetentionPolicy};
+use schedule::{OverlapPolicy, Schedule, ScheduleId, Schedules, When};
+use scheduler::{Scheduler, SchedulerConfig, USER_PRIORITIES};
+use shim::{ShimJob, Shims};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
 use std::process::{ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::process::{Child, Command};
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
-use tokio::sync::oneshot;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::{oneshot, watch};
 use uuid::Uuid;
+use workflow::{Dependency, DependencyCondition, StartedWorkflow, WorkflowStep};
 
 const RUNTIME_EVENT_ERROR_MSG: &str = "runtime event channel does not work";
 const RUNTIME_CGROUP_NAME: &str = "jobruntime";
 const SIGKILL: i32 = 9;
-
-// it's hardcoded just for the purpose of this program to simualte authorization
-const ADMIN_ROLE: &str = "admin";
+const LOG_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+// How long to keep reading output after the process exited.
+// Pipes can be inherited by orphaned grandchildren, so we can't wait for EOF forever.
+const PIPE_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const COMMAND_CHANNEL_SIZE: usize = 1024;
+const EVENT_CHANNEL_SIZE: usize = 1024;
+// Window of the jobs per minute quota
+const START_RATE_WINDOW: Duration = Duration::from_secs(60);
+// Exit code of a queued job that couldn't be started, the same as a shell that can't find the command
+const SPAWN_FAILED_EXIT_CODE: i32 = 127;
+// How often due schedules are looked for
+const SCHEDULE_TICK: Duration = Duration::from_secs(1);
 
 pub type JobId = Uuid;
 pub type Owner = String;
-pub type RuntimeSender = UnboundedSender<RuntimeCommand>;
-pub type LogSender = UnboundedSender<LogRecord>;
+pub type RuntimeSender = Sender<RuntimeCommand>;
+pub type LogSender = Sender<LogMessage>;
 pub type StatusSender = oneshot::Sender<Result<JobStatusResponse, RuntimeError>>;
 pub type StopSender = oneshot::Sender<Result<(), RuntimeError>>;
-pub type StartSender = oneshot::Sender<JobId>;
+pub type StartSender = oneshot::Sender<Result<JobId, RuntimeError>>;
+pub type FetchLogsSender = oneshot::Sender<Result<(), RuntimeError>>;
+pub type StdinWriter = Sender<Bytes>;
+pub type StdinSender = oneshot::Sender<Result<StdinWriter, RuntimeError>>;
+pub type WaitSender = oneshot::Sender<Result<JobStatusResponse, RuntimeError>>;
+pub type WatchSender = Sender<WatchMessage>;
+pub type UsageSender = oneshot::Sender<Result<QuotaUsage, RuntimeError>>;
+pub type ScheduleSender = oneshot::Sender<Result<ScheduleId, RuntimeError>>;
+pub type ListSchedulesSender = oneshot::Sender<Result<Vec<Schedule>, RuntimeError>>;
+pub type WorkflowSender = oneshot::Sender<Result<StartedWorkflow, RuntimeError>>;
+pub type BatchSender = oneshot::Sender<Result<StartedBatch, RuntimeError>>;
+pub type BatchJobsSender = oneshot::Sender<Result<Vec<JobId>, RuntimeError>>;
+pub type GroupStatusSender = oneshot::Sender<Result<GroupStatus, RuntimeError>>;
+pub type ListGroupsSender = oneshot::Sender<Vec<GroupStatus>>;
+pub type PruneSender = oneshot::Sender<Result<Vec<JobId>, RuntimeError>>;
+pub type Labels = HashMap<String, String>;
 
 pub const LOG_SIZE: usize = 1024;
+// Passed as the first sequence number to receive only records produced from now on
+pub const LOG_SEQ_LATEST: u64 = u64::MAX;
+// Number of chunks waiting to be written into the stdin of a job
+pub const STDIN_CHANNEL_SIZE: usize = 64;
+// Number of log messages buffered for every client that follows the logs
+pub const LOG_CHANNEL_SIZE: usize = 256;
+// Number of the most recent records of a running job shared by all of its followers.
+// Followers that fall behind by more than that are lagged or disconnected.
+pub const LOG_BROADCAST_SIZE: usize = 1024;
+// Number of lifecycle events buffered for every client that watches jobs
+pub const WATCH_CHANNEL_SIZE: usize = 256;
+// Number of the most recent lifecycle events shared by all watchers
+pub const EVENT_BROADCAST_SIZE: usize = 1024;
 
 #[derive(Debug, Error)]
 pub enum RuntimeError {
@@ -36,6 +101,26 @@ pub enum RuntimeError {
     Unauthorized,
     #[error("job does not exists")]
     JobDoesNotExists,
+    #[error("stdin of the job is not open")]
+    StdinNotOpen,
+    #[error("job is not running in a terminal")]
+    NotATerminal,
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("unknown pool: {0}")]
+    UnknownPool(String),
+    #[error("invalid schedule: {0}")]
+    InvalidSchedule(String),
+    #[error("invalid workflow: {0}")]
+    InvalidWorkflow(String),
+    #[error("invalid batch: {0}")]
+    InvalidBatch(String),
+    #[error("invalid group: {0}")]
+    InvalidGroup(String),
+    #[error("invalid signal: {0}")]
+    InvalidSignal(i32),
+    #[error("job has not ended yet")]
+    JobNotEnded,
     #[error("io error")]
     Io(#[from] std::io::Error),
 }
@@ -45,6 +130,10 @@ pub struct JobStatusResponse {
     pub job: JobId,
     pub owner: String,
     pub status: JobStatus,
+    // position in the queue of a pending job, starting from 1
+    pub queue_position: Option<usize>,
+    // jobs a pending job still waits for
+    pub waiting_for: Vec<JobId>,
 }
 
 #[derive(Debug, Clone)]
@@ -53,11 +142,107 @@ pub enum LogRecord {
     Stderr(Bytes),
 }
 
-#[derive(Default, Debug)]
+// Log record tagged with its position in the job output.
+// Sequence numbers start at 0 and increase by one for every record,
+// so a client can resume a stream from the last sequence it received.
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub record: LogRecord,
+}
+
+// Messages delivered to a client that fetches the logs of a job
+#[derive(Debug)]
+pub enum LogMessage {
+    Entry(LogEntry),
+    // The client didn't keep up with the job output and that many records were skipped
+    Lagged { missed: u64 },
+    // The client didn't keep up with the job output and was disconnected.
+    // This is the last message sent to the client.
+    Disconnected { missed: u64 },
+}
+
+// What to do with a client that doesn't read the logs as fast as the job produces them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SlowConsumerPolicy {
+    // Skip records that the client fell behind on and report how many were missed
+    #[default]
+    Lag,
+    // Stop streaming to the client as soon as it falls behind
+    Disconnect,
+}
+
+#[derive(Debug, Clone)]
+pub enum JobEventKind {
+    Created,
+    Started { pid: i32 },
+    Exited { exit_code: i32 },
+    Killed { signal: i32 },
+    // Reported right before Killed or Exited when the OOM killer hit the cgroup of the job
+    Oom,
+    // The job never ran, e.g. because a job it depends on failed
+    Cancelled { reason: String },
+}
+
+// Change in the lifecycle of a job, published to everyone watching the runtime
+#[derive(Debug, Clone)]
+pub struct JobEvent {
+    pub job: JobId,
+    pub owner: Owner,
+    pub labels: Arc<Labels>,
+    pub kind: JobEventKind,
+}
+
+// Messages delivered to a client that watches lifecycle events
+#[derive(Debug)]
+pub enum WatchMessage {
+    Event(JobEvent),
+    // The client didn't keep up and that many events were skipped
+    Lagged { missed: u64 },
+}
+
+// Selects the events a watcher is interested in, empty fields match everything
+#[derive(Debug, Default)]
+pub struct WatchFilter {
+    pub jobs: Vec<JobId>,
+    pub owner: Option<Owner>,
+    // all of them have to be present on the job with the same values
+    pub labels: Labels,
+}
+
+impl WatchFilter {
+    fn matches(&self, event: &JobEvent) -> bool {
+        (self.jobs.is_empty() || self.jobs.contains(&event.job))
+            && self.owner.iter().all(|owner| *owner == event.owner)
+            && self
+                .labels
+                .iter()
+                .all(|(key, value)| event.labels.get(key) == Some(value))
+    }
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct JobRequest {
-    pub owner: String,
     pub path: String,
     pub args: Vec<String>,
+    pub limits: ResourceLimits,
+    // keep stdin open for writing, otherwise the job reads from /dev/null
+    pub stdin: bool,
+    // run the job under a pseudo-terminal, its stdin is always open in that case
+    pub tty: bool,
+    pub labels: Labels,
+    // waiting jobs with a higher priority are started first
+    pub priority: i32,
+    // concurrency pool the job runs in, see SchedulerConfig
+    pub pool: Option<String>,
+    // the job stays pending until these jobs end
+    pub dependencies: Vec<Dependency>,
+    // set in the environment of the process on top of the one of the daemon
+    pub env: HashMap<String, String>,
+    // group the job joins, it's created with the first job
+    pub group: Option<String>,
+    // replace limits of the whole group
+    pub group_limits: Option<ResourceLimits>,
 }
 
 #[derive(Debug, Clone)]
@@ -66,6 +251,9 @@ pub enum JobStatus {
     Running { pid: i32 },
     Finished { exit_code: i32 },
     Killed { signal: i32 },
+    Cancelled { reason: String },
+    // The daemon stopped while the job was running, the process may still be alive
+    Lost { pid: i32 },
 }
 
 // Events that JobRuntime knows how to process
@@ -74,125 +262,424 @@ enum RuntimeEvent {
     JobExit { job: JobId, status: ExitStatus },
     JobKill { job: JobId },
     JobStart { job: JobId, pid: i32 },
+    // The shim couldn't start the job
+    JobSpawnFailed { job: JobId, reason: String },
     LogCreated { job: JobId, record: LogRecord },
 }
 
-// Representation of the client that is connected
-// to the runtime and fetches stream of logs
-#[derive(Debug)]
-struct LogClient {
-    id: Uuid,
-    sender: LogSender,
-}
-
-impl Hash for LogClient {
-    fn hash<H>(&self, state: &mut H)
-    where
-        H: Hasher,
-    {
-        self.id.hash(state)
-    }
-}
-
-impl PartialEq for LogClient {
-    fn eq(&self, other: &Self) -> bool {
-        self.id == other.id
-    }
-}
-
-impl Eq for LogClient {}
-
-impl LogClient {
-    fn new(sender: LogSender) -> Self {
-        Self {
-            id: Uuid::new_v4(),
-            sender,
-        }
-    }
-}
-
 struct Job {
     uuid: Uuid,
     owner: Owner,
-    logs: Vec<LogRecord>,
+    labels: Arc<Labels>,
+    // path followed by the arguments
+    command: Vec<String>,
+    next_seq: u64,
+    // live output of the job, dropped when the job ends so that followers see the end of the stream
+    log_tx: Option<broadcast::Sender<LogEntry>>,
+    // dropping the last clone of the writer closes stdin of the process
+    stdin: Option<StdinWriter>,
+    pty: Option<PtyMaster>,
     kill_switch: Option<tokio::sync::oneshot::Sender<()>>,
     status: JobStatus,
+    // clients waiting for the job to finish, woken up when it exits or gets killed
+    waiters: Vec<WaitSender>,
+    cgroup: Option<Cgroup>,
+    // counted against the quota of the owner until the job ends
+    limits: ResourceLimits,
+    // pool the job takes a slot of while it runs
+    pool: Option<String>,
+    // set until the job is spawned
+    queued: Option<QueuedStart>,
+    // tells the shim of the job how far the log store has written out its records
+    written_tx: Option<watch::Sender<Option<u64>>>,
+    // dependencies that haven't ended yet
+    dependencies: Vec<Dependency>,
+    // when the job ended, jobs restored from journals without the time count from the restart
+    ended: Option<SystemTime>,
+}
+
+// Everything a pending job needs to be started once it leaves the queue
+struct QueuedStart {
+    request: JobRequest,
+    kill_switch: oneshot::Receiver<()>,
+    stdin: Option<Receiver<Bytes>>,
 }
 
 impl Job {
-    fn new(owner: String) -> (Self, tokio::sync::oneshot::Receiver<()>) {
+    fn new(owner: String, labels: Labels) -> (Self, tokio::sync::oneshot::Receiver<()>) {
         let (rx, tx) = tokio::sync::oneshot::channel();
         let instance = Self {
             uuid: Uuid::new_v4(),
             owner,
-            logs: Vec::new(),
+            labels: Arc::new(labels),
+            command: Vec::new(),
+            next_seq: 0,
+            log_tx: Some(broadcast::channel(LOG_BROADCAST_SIZE).0),
+            stdin: None,
+            pty: None,
             kill_switch: Some(rx),
             status: JobStatus::Pending,
+            waiters: Vec::new(),
+            cgroup: None,
+            limits: ResourceLimits::default(),
+            pool: None,
+            queued: None,
+            written_tx: None,
+            dependencies: Vec::new(),
+            ended: None,
         };
 
         (instance, tx)
     }
 
+    // Job that ended before the daemon restarted, only its status and logs are left
+    fn restored(record: JobRecord, next_seq: u64) -> Self {
+        let ended = match record.status {
+            JobStatus::Running { .. } => None,
+            _ => record.ended.or_else(|| Some(SystemTime::now())),
+        };
+        Self {
+            uuid: record.job,
+            owner: record.owner,
+            labels: Arc::new(record.labels),
+            command: record.command,
+            next_seq,
+            log_tx: None,
+            stdin: None,
+            pty: None,
+            kill_switch: None,
+            status: record.status,
+            waiters: Vec::new(),
+            cgroup: None,
+            limits: record.limits,
+            pool: record.pool,
+            queued: None,
+            written_tx: None,
+            dependencies: Vec::new(),
+            ended,
+        }
+    }
+
+    // Lets the shim of the job remove the output the log store has written out
+    fn report_written(&self, written_until: Option<u64>) {
+        if let Some(written_tx) = &self.written_tx {
+            if *written_tx.borrow() != written_until {
+                // fails only once the shim is gone
+                let _ = written_tx.send(written_until);
+            }
+        }
+    }
+
     fn started(&mut self, pid: i32) {
         self.status = JobStatus::Running { pid };
     }
 
     fn killed(&mut self, signal: i32) {
         self.status = JobStatus::Killed { signal };
+        self.ended = Some(SystemTime::now());
+        self.log_tx = None;
+        self.stdin = None;
+        self.notify_waiters();
     }
 
     fn finished(&mut self, exit_code: i32) {
-        self.status = JobStatus::Finished { exit_code }
+        self.status = JobStatus::Finished { exit_code };
+        self.ended = Some(SystemTime::now());
+        self.log_tx = None;
+        self.stdin = None;
+        self.notify_waiters();
+    }
+
+    fn cancelled(&mut self, reason: String) {
+        self.status = JobStatus::Cancelled { reason };
+        self.ended = Some(SystemTime::now());
+        self.queued = None;
+        self.dependencies.clear();
+        self.log_tx = None;
+        self.stdin = None;
+        self.notify_waiters();
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(
+            self.status,
+            JobStatus::Finished { .. }
+                | JobStatus::Killed { .. }
+                | JobStatus::Cancelled { .. }
+                | JobStatus::Lost { .. }
+        )
+    }
+
+    fn succeeded(&self) -> bool {
+        matches!(self.status, JobStatus::Finished { exit_code: 0 })
+    }
+
+    fn status_response(&self) -> JobStatusResponse {
+        JobStatusResponse {
+            job: self.uuid,
+            owner: self.owner.clone(),
+            status: self.status.clone(),
+            queue_position: None,
+            waiting_for: self
+                .dependencies
+                .iter()
+                .map(|dependency| dependency.job)
+                .collect(),
+        }
+    }
+
+    fn event(&self, kind: JobEventKind) -> JobEvent {
+        JobEvent {
+            job: self.uuid,
+            owner: self.owner.clone(),
+            labels: self.labels.clone(),
+            kind,
+        }
+    }
+
+    // Checks the cgroup of the job after it ended, the process itself only sees SIGKILL
+    fn was_oom_killed(&self) -> bool {
+        match self.cgroup.as_ref().map(Cgroup::oom_kill_count) {
+            Some(Ok(count)) => count > 0,
+            Some(Err(err)) => {
+                log::warn!("unable to read memory events of job {}: {}", self.uuid, err);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn notify_waiters(&mut self) {
+        for waiter in std::mem::take(&mut self.waiters) {
+            // the client may have given up waiting in the meantime
+            let _ = waiter.send(Ok(self.status_response()));
+        }
+    }
+
+    fn next_log_entry(&mut self, record: LogRecord) -> LogEntry {
+        let entry = LogEntry {
+            seq: self.next_seq,
+            record,
+        };
+        self.next_seq += 1;
+        entry
     }
 }
 
 #[derive(Debug)]
 pub enum RuntimeCommand {
     Start {
-        owner: Owner,
-        path: String,
-        args: Vec<String>,
+        principal: Principal,
+        request: JobRequest,
         sender: StartSender,
-        limits: ResourceLimits,
     },
     Stop {
         job: JobId,
-        owner: Owner,
+        principal: Principal,
         sender: StopSender,
     },
     Status {
         job: JobId,
-        owner: Owner,
+        principal: Principal,
         sender: StatusSender,
     },
     FetchLogs {
         job: JobId,
-        owner: Owner,
+        principal: Principal,
+        // first sequence number the client is interested in
+        from_seq: u64,
         sender: LogSender,
+        result: FetchLogsSender,
+    },
+    // Returns a writer to the stdin of the job.
+    // Writes are applied in order and wait when the process doesn't read its input.
+    OpenStdin {
+        job: JobId,
+        principal: Principal,
+        sender: StdinSender,
+    },
+    // Closes stdin of the job once all writers returned by OpenStdin are dropped
+    CloseStdin {
+        job: JobId,
+        principal: Principal,
+        sender: StopSender,
+    },
+    // Replies with the final status once the job has finished or was killed
+    Wait {
+        job: JobId,
+        principal: Principal,
+        sender: WaitSender,
+    },
+    // Streams lifecycle events of the jobs matching the filter.
+    // Events of jobs owned by someone else need the list permission for any job.
+    Watch {
+        principal: Principal,
+        filter: WatchFilter,
+        sender: WatchSender,
+    },
+    // Changes the window size of a job started with a terminal
+    ResizeTerminal {
+        job: JobId,
+        principal: Principal,
+        rows: u16,
+        cols: u16,
+        sender: StopSender,
+    },
+    // Reports resource usage and quota of a user, the caller itself when no user is given
+    Usage {
+        principal: Principal,
+        user: Option<Owner>,
+        sender: UsageSender,
+    },
+    // Starts the job at the given time or every time the cron expression matches.
+    // Runs are started with the permissions and quota of the caller.
+    Schedule {
+        principal: Principal,
+        request: JobRequest,
+        when: When,
+        overlap: OverlapPolicy,
+        sender: ScheduleSender,
+    },
+    // Lists own schedules, schedules of other users need the list permission for any job
+    ListSchedules {
+        principal: Principal,
+        sender: ListSchedulesSender,
+    },
+    // Pauses, resumes or deletes a schedule, jobs it already started keep running
+    UpdateSchedule {
+        principal: Principal,
+        schedule: ScheduleId,
+        change: ScheduleChange,
+        sender: StopSender,
+    },
+    // Starts jobs of all steps at once, each step waits for the steps it depends on.
+    // Either all steps are accepted or none of them runs.
+    StartWorkflow {
+        principal: Principal,
+        steps: Vec<WorkflowStep>,
+        sender: WorkflowSender,
+    },
+    // Starts many jobs at once. An atomic batch is either accepted as a whole or not at all,
+    // otherwise every job is admitted on its own.
+    StartBatch {
+        principal: Principal,
+        requests: Vec<JobRequest>,
+        atomic: bool,
+        sender: BatchSender,
     },
+    // Stops all jobs of a batch that haven't ended yet
+    StopBatch {
+        principal: Principal,
+        batch: BatchId,
+        sender: StopSender,
+    },
+    // Jobs of a batch in the order they were requested
+    BatchJobs {
+        principal: Principal,
+        batch: BatchId,
+        sender: BatchJobsSender,
+    },
+    // Stops all jobs of a group that haven't ended yet
+    StopGroup {
+        principal: Principal,
+        group: String,
+        sender: StopSender,
+    },
+    // Sends a signal to all running jobs of a group
+    SignalGroup {
+        principal: Principal,
+        group: String,
+        signal: i32,
+        sender: StopSender,
+    },
+    GroupStatus {
+        principal: Principal,
+        group: String,
+        sender: GroupStatusSender,
+    },
+    // Groups the principal may see
+    ListGroups {
+        principal: Principal,
+        sender: ListGroupsSender,
+    },
+    // Forgets a job that has ended, together with its logs
+    Delete {
+        job: JobId,
+        principal: Principal,
+        sender: StopSender,
+    },
+    // Deletes ended jobs of the caller, or of all users, that ended longer ago than given
+    Prune {
+        principal: Principal,
+        older_than: Option<Duration>,
+        all_users: bool,
+        sender: PruneSender,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScheduleChange {
+    Pause,
+    Resume,
+    Delete,
+}
+
+// What the runtime remembers about a user for its quota
+#[derive(Debug, Default)]
+struct UserActivity {
+    // roles the user had when it last started a job
+    roles: Vec<String>,
+    starts: VecDeque<Instant>,
 }
 
 // Job runtime that spawn processes and stores its logs
 pub struct JobRuntime {
     jobs: HashMap<Uuid, Job>,
-    peers: HashMap<Uuid, HashSet<LogClient>>,
-    event_rx: UnboundedReceiver<RuntimeEvent>,
-    event_tx: UnboundedSender<RuntimeEvent>,
-    cmd_rx: UnboundedReceiver<RuntimeCommand>,
+    event_rx: Receiver<RuntimeEvent>,
+    event_tx: Sender<RuntimeEvent>,
+    cmd_rx: Receiver<RuntimeCommand>,
     cgroup: Option<Cgroup>,
+    log_store: Box<dyn LogStore>,
+    slow_consumer_policy: SlowConsumerPolicy,
+    lifecycle_tx: broadcast::Sender<JobEvent>,
+    authorizer: Arc<dyn Authorizer>,
+    quotas: QuotaPolicy,
+    activity: HashMap<Owner, UserActivity>,
+    scheduler: Scheduler,
+    schedules: Schedules,
+    // jobs waiting for the key job to end
+    dependants: HashMap<JobId, Vec<JobId>>,
+    batches: HashMap<BatchId, Vec<JobId>>,
+    groups: HashMap<String, Group>,
+    journal: Option<Journal>,
+    shims: Option<Shims>,
+    retention: RetentionPolicy,
 }
 
 impl JobRuntime {
-    pub fn new() -> (Self, UnboundedSender<RuntimeCommand>) {
-        let (event_tx, event_rx) = mpsc::unbounded_channel();
-        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    pub fn new() -> (Self, RuntimeSender) {
+        let (event_tx, event_rx) = mpsc::channel(EVENT_CHANNEL_SIZE);
+        let (cmd_tx, cmd_rx) = mpsc::channel(COMMAND_CHANNEL_SIZE);
         let runtime = Self {
             jobs: HashMap::new(),
-            peers: HashMap::new(),
             event_tx,
             event_rx,
             cmd_rx,
             cgroup: None,
+            log_store: Box::new(MemoryLogStore::new(DEFAULT_MAX_JOB_BYTES as usize)),
+            slow_consumer_policy: SlowConsumerPolicy::default(),
+            lifecycle_tx: broadcast::channel(EVENT_BROADCAST_SIZE).0,
+            authorizer: Arc::new(Rbac::default()),
+            quotas: QuotaPolicy::default(),
+            activity: HashMap::new(),
+            scheduler: Scheduler::default(),
+            schedules: Schedules::default(),
+            dependants: HashMap::new(),
+            batches: HashMap::new(),
+            groups: HashMap::new(),
+            journal: None,
+            shims: None,
+            retention: RetentionPolicy::default(),
         };
         (runtime, cmd_tx)
     }
@@ -204,72 +691,473 @@ impl JobRuntime {
         Ok(self)
     }
 
+    pub fn with_log_store<S: LogStore + 'static>(mut self, log_store: S) -> Self {
+        self.log_store = Box::new(log_store);
+        self
+    }
+
+    pub fn with_authorizer<A: Authorizer + 'static>(mut self, authorizer: A) -> Self {
+        self.authorizer = Arc::new(authorizer);
+        self
+    }
+
+    pub fn with_quotas(mut self, quotas: QuotaPolicy) -> Self {
+        self.quotas = quotas;
+        self
+    }
+
+    pub fn with_scheduler(mut self, config: SchedulerConfig) -> Self {
+        self.scheduler = Scheduler::new(config);
+        self
+    }
+
+    // Keeps schedules in the file, so they survive restarts
+    pub fn with_schedule_file(mut self, path: PathBuf) -> Result<Self, RuntimeError> {
+        self.schedules = Schedules::load(path)?;
+        Ok(self)
+    }
+
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    // Runs jobs without stdin and terminal under shims, so they keep running when the daemon stops
+    pub fn with_shims(mut self, shims: Shims) -> Self {
+        self.shims = Some(shims);
+        self
+    }
+
+    // Records jobs in the journal at the path, so they survive restarts.
+    // Has to be called after cgroups, the log store and shims are set up,
+    // running jobs are reattached to their shims and their logs are read from the store.
+    pub fn with_journal(mut self, path: PathBuf) -> Result<Self, RuntimeError> {
+        let (journal, records) = Journal::open(path)?;
+        let mut lost = vec![];
+        for record in records {
+            let job = record.job;
+            let location = self.log_store.location(job);
+            if record.logs.is_some() && record.logs != location {
+                log::warn!(
+                    "logs of job {} were kept in {:?}, they are not available anymore",
+                    job,
+                    record.logs
+                );
+            }
+            let next_seq = match self.log_store.last_seq(job) {
+                Ok(last_seq) => last_seq.map_or(0, |seq| seq + 1),
+                Err(err) => {
+                    log::warn!("unable to read logs of job {}: {}", job, err);
+                    0
+                }
+            };
+            if let Some(batch) = record
+                .labels
+                .get(batch::BATCH_LABEL)
+                .and_then(|batch| batch.parse().ok())
+            {
+                self.batches.entry(batch).or_default().push(job);
+            }
+            if let Some(name) = record.labels.get(group::GROUP_LABEL) {
+                self.groups
+                    .entry(name.clone())
+                    .or_insert_with(|| Group::new(name.clone(), record.owner.clone()))
+                    .jobs
+                    .push(job);
+            }
+
+            let running = match record.status {
+                JobStatus::Running { pid } => Some(pid),
+                _ => None,
+            };
+            let mut job_instance = Job::restored(record, next_seq);
+            if let Some(pid) = running {
+                if let Err(err) = self.reattach(&mut job_instance, pid) {
+                    log::warn!("job {} is lost: {}", job, error_reason(err));
+                    let ended = SystemTime::now();
+                    job_instance.status = JobStatus::Lost { pid };
+                    job_instance.ended = Some(ended);
+                    lost.push(JournalEntry::Lost {
+                        job,
+                        pid,
+                        ended: Some(journal::millis(ended)),
+                    });
+                }
+            }
+            self.jobs.insert(job, job_instance);
+        }
+        for entry in &lost {
+            journal.record(entry)?;
+        }
+        log::info!(
+            "restored {} jobs from {}",
+            self.jobs.len(),
+            journal.path().display()
+        );
+        self.journal = Some(journal);
+        Ok(self)
+    }
+
+    // Follows a job that kept running under its shim while the daemon was down
+    fn reattach(&mut self, job: &mut Job, pid: i32) -> Result<(), RuntimeError> {
+        let shim = match &self.shims {
+            Some(shims) if shims.exists(job.uuid) => shims.open(job.uuid)?,
+            _ => {
+                return Err(RuntimeError::Io(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "it wasn't run by a shim",
+                )))
+            }
+        };
+        // the group cgroup comes back with its first job, limits of the group are kept in the cgroup itself
+        let group = job.labels.get(group::GROUP_LABEL).cloned();
+        if let (Some(group), Some(runtime_cgroup)) = (
+            group.and_then(|group| self.groups.get_mut(&group)),
+            &self.cgroup,
+        ) {
+            if group.cgroup().is_none() {
+                group.create_cgroup(runtime_cgroup)?;
+            }
+        }
+        let parent_cgroup = match job
+            .labels
+            .get(group::GROUP_LABEL)
+            .and_then(|group| self.groups.get(group))
+        {
+            Some(group) => group.cgroup(),
+            None => self.cgroup.as_ref(),
+        };
+        if let Some(parent_cgroup) = parent_cgroup {
+            job.cgroup = Some(Cgroup::new_relative_to(
+                parent_cgroup,
+                &job.uuid.to_string(),
+            )?);
+        }
+
+        let (kill_switch, kill_switch_rx) = oneshot::channel();
+        job.kill_switch = Some(kill_switch);
+        job.log_tx = Some(broadcast::channel(LOG_BROADCAST_SIZE).0);
+        self.scheduler
+            .resume(job.uuid, &job.owner, job.pool.as_deref());
+        // the records before next_seq were found in the log store
+        let (written_tx, written_rx) = watch::channel(Some(job.next_seq));
+        job.written_tx = Some(written_tx);
+        tokio::spawn(Self::handle_shim_job(
+            job.uuid,
+            shim,
+            job.next_seq,
+            written_rx,
+            kill_switch_rx,
+            self.event_tx.clone(),
+        ));
+        log::info!("reattached to job {} with pid {}", job.uuid, pid);
+        Ok(())
+    }
+
+    pub fn with_slow_consumer_policy(mut self, policy: SlowConsumerPolicy) -> Self {
+        self.slow_consumer_policy = policy;
+        self
+    }
+
     // main event loop that accepts commands and process them
     // only place that is able to mutate interal state
     pub async fn start(mut self) {
         tokio::spawn(async move {
+            let mut prune_interval = tokio::time::interval(LOG_PRUNE_INTERVAL);
+            let mut schedule_interval = tokio::time::interval(SCHEDULE_TICK);
             loop {
                 tokio::select! {
                     Some(command) = self.cmd_rx.recv() => {
                         match command {
-                            RuntimeCommand::Start{path, args, owner, sender, limits} => {
-                                if let Err(_) = self.start_job(path, args, owner, sender, limits){
+                            RuntimeCommand::Start{principal, request, sender} => {
+                                if sender.send(self.start_job(principal, request)).is_err() {
                                     log::error!("unable to send response back to client");
                                 };
                             },
-                            RuntimeCommand::Stop{ job, owner, sender} => {
-                                if let Err(_) = sender.send(self.stop_job(job, owner)) {
+                            RuntimeCommand::Stop{ job, principal, sender} => {
+                                if sender.send(self.stop_job(job, principal)).is_err() {
+                                    log::error!("unable to send response back to client for job {}", job);
+                                };
+                            },
+                            RuntimeCommand::Status { job, principal, sender} => {
+                                if sender.send(self.send_status(job, principal)).is_err() {
                                     log::error!("unable to send response back to client for job {}", job);
                                 };
                             },
-                            RuntimeCommand::Status { job, owner, sender} => {
-                                if let Err(_) = sender.send(self.send_status(job, owner)) {
+                            RuntimeCommand::FetchLogs{job, principal, from_seq, sender, result} => {
+                                if result.send(self.send_logs(job, principal, from_seq, sender)).is_err() {
                                     log::error!("unable to send response back to client for job {}", job);
                                 };
                             },
-                            RuntimeCommand::FetchLogs{job, owner, sender} => {
-                                if let Err(_) = self.send_logs(job, owner, sender) {
+                            RuntimeCommand::OpenStdin { job, principal, sender } => {
+                                if sender.send(self.open_stdin(job, principal)).is_err() {
+                                    log::error!("unable to send response back to client for job {}", job);
+                                };
+                            },
+                            RuntimeCommand::CloseStdin { job, principal, sender } => {
+                                if sender.send(self.close_stdin(job, principal)).is_err() {
+                                    log::error!("unable to send response back to client for job {}", job);
+                                };
+                            },
+                            RuntimeCommand::Wait { job, principal, sender } => {
+                                self.wait_job(job, principal, sender);
+                            },
+                            RuntimeCommand::Watch { principal, filter, sender } => {
+                                self.watch_jobs(principal, filter, sender);
+                            },
+                            RuntimeCommand::ResizeTerminal { job, principal, rows, cols, sender } => {
+                                if sender.send(self.resize_terminal(job, principal, rows, cols)).is_err() {
+                                    log::error!("unable to send response back to client for job {}", job);
+                                };
+                            },
+                            RuntimeCommand::Usage { principal, user, sender } => {
+                                if sender.send(self.quota_usage(principal, user)).is_err() {
+                                    log::error!("unable to send response back to client");
+                                };
+                            },
+                            RuntimeCommand::Schedule { principal, request, when, overlap, sender } => {
+                                if sender.send(self.create_schedule(principal, request, when, overlap)).is_err() {
+                                    log::error!("unable to send response back to client");
+                                };
+                            },
+                            RuntimeCommand::ListSchedules { principal, sender } => {
+                                if sender.send(Ok(self.list_schedules(principal))).is_err() {
+                                    log::error!("unable to send response back to client");
+                                };
+                            },
+                            RuntimeCommand::UpdateSchedule { principal, schedule, change, sender } => {
+                                if sender.send(self.update_schedule(principal, schedule, change)).is_err() {
+                                    log::error!("unable to send response back to client for schedule {}", schedule);
+                                };
+                            },
+                            RuntimeCommand::StartWorkflow { principal, steps, sender } => {
+                                if sender.send(self.start_workflow(principal, steps)).is_err() {
+                                    log::error!("unable to send response back to client");
+                                };
+                            },
+                            RuntimeCommand::StartBatch { principal, requests, atomic, sender } => {
+                                if sender.send(self.start_batch(principal, requests, atomic)).is_err() {
+                                    log::error!("unable to send response back to client");
+                                };
+                            },
+                            RuntimeCommand::StopBatch { principal, batch, sender } => {
+                                if sender.send(self.stop_batch(batch, principal)).is_err() {
+                                    log::error!("unable to send response back to client for batch {}", batch);
+                                };
+                            },
+                            RuntimeCommand::BatchJobs { principal, batch, sender } => {
+                                if sender.send(self.batch_jobs(batch, &principal, Operation::Status)).is_err() {
+                                    log::error!("unable to send response back to client for batch {}", batch);
+                                };
+                            },
+                            RuntimeCommand::StopGroup { principal, group, sender } => {
+                                if sender.send(self.stop_group(&group, principal)).is_err() {
+                                    log::error!("unable to send response back to client for group {}", group);
+                                };
+                            },
+                            RuntimeCommand::SignalGroup { principal, group, signal, sender } => {
+                                if sender.send(self.signal_group(&group, principal, signal)).is_err() {
+                                    log::error!("unable to send response back to client for group {}", group);
+                                };
+                            },
+                            RuntimeCommand::GroupStatus { principal, group, sender } => {
+                                if sender.send(self.group_status(&group, &principal)).is_err() {
+                                    log::error!("unable to send response back to client for group {}", group);
+                                };
+                            },
+                            RuntimeCommand::ListGroups { principal, sender } => {
+                                if sender.send(self.list_groups(&principal)).is_err() {
+                                    log::error!("unable to send response back to client");
+                                };
+                            },
+                            RuntimeCommand::Delete { job, principal, sender } => {
+                                if sender.send(self.delete_job(job, principal)).is_err() {
                                     log::error!("unable to send response back to client for job {}", job);
                                 };
                             },
+                            RuntimeCommand::Prune { principal, older_than, all_users, sender } => {
+                                if sender.send(self.prune_jobs(principal, older_than, all_users)).is_err() {
+                                    log::error!("unable to send response back to client");
+                                };
+                            },
                         }
                     },
                     Some(event) = self.event_rx.recv() => {
                         match event {
                             RuntimeEvent::JobExit { job, status} => {
                                 if let Ok(job_instance) = self.get_job(job) {
+                                    let oom = job_instance.was_oom_killed();
+                                    let mut kind = None;
                                     if let Some(exit_code) = status.code() {
                                         job_instance.finished(exit_code);
+                                        kind = Some(JobEventKind::Exited { exit_code });
                                     };
 
                                     if let Some(signal) = status.signal() {
-                                        job_instance.killed(signal as i32);
-
+                                        job_instance.killed(signal);
+                                        kind = Some(JobEventKind::Killed { signal });
                                     };
+
+                                    if oom {
+                                        self.publish(job, JobEventKind::Oom);
+                                    }
+                                    if let Some(kind) = kind {
+                                        self.publish(job, kind);
+                                    }
                                 }
-                                self.peers.remove(&job);
+                                // the output of the shim goes only once the logs are written out
+                                self.close_logs(job);
+                                self.remove_shim(job);
+                                self.job_done(job);
                             },
                             RuntimeEvent::JobKill { job } => {
                                 if let Ok(job_instance) = self.get_job(job) {
                                     job_instance.killed(SIGKILL);
+                                    self.publish(job, JobEventKind::Killed { signal: SIGKILL });
                                 }
-                                self.peers.remove(&job);
+                                self.close_logs(job);
+                                self.remove_shim(job);
+                                self.job_done(job);
                             },
                             RuntimeEvent::JobStart { job, pid } => {
                                 if let Ok(job_instance) = self.get_job(job) {
                                     job_instance.started(pid);
+                                    self.publish(job, JobEventKind::Started { pid });
                                 }
                             },
+                            RuntimeEvent::JobSpawnFailed { job, reason } => {
+                                self.spawn_failed(job, reason);
+                                self.close_logs(job);
+                                self.job_done(job);
+                            },
                             RuntimeEvent::LogCreated { job , record } => {
                                 self.store_logs(job, record);
                             },
                         };
                     },
+                    _ = schedule_interval.tick() => {
+                        self.run_due_schedules();
+                    },
+                    _ = prune_interval.tick() => {
+                        self.apply_retention();
+                        if let Err(err) = self.log_store.prune() {
+                            log::error!("unable to prune logs: {}", err);
+                        }
+                        for (job, job_instance) in &self.jobs {
+                            job_instance.report_written(self.log_store.written_until(*job));
+                        }
+                    },
                 }
             }
         });
     }
 
+    fn publish(&self, job: JobId, kind: JobEventKind) {
+        if let Some(job_instance) = self.jobs.get(&job) {
+            self.record(job_instance, &kind);
+            // sending fails only when nobody is watching
+            let _ = self.lifecycle_tx.send(job_instance.event(kind));
+        }
+    }
+
+    // Every event that changes the status of a job goes into the journal
+    fn record(&self, job_instance: &Job, kind: &JobEventKind) {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+        let job = job_instance.uuid;
+        let ended = Some(journal::millis(
+            job_instance.ended.unwrap_or_else(SystemTime::now),
+        ));
+        let entry = match kind {
+            JobEventKind::Created => JournalEntry::Created {
+                job,
+                owner: job_instance.owner.clone(),
+                command: job_instance.command.clone(),
+                labels: job_instance.labels.as_ref().clone(),
+                limits: job_instance.limits.clone(),
+                pool: job_instance.pool.clone(),
+                logs: self.log_store.location(job),
+            },
+            JobEventKind::Started { pid } => JournalEntry::Started { job, pid: *pid },
+            JobEventKind::Exited { exit_code } => JournalEntry::Exited {
+                job,
+                exit_code: *exit_code,
+                ended,
+            },
+            JobEventKind::Killed { signal } => JournalEntry::Killed {
+                job,
+                signal: *signal,
+                ended,
+            },
+            JobEventKind::Cancelled { reason } => JournalEntry::Cancelled {
+                job,
+                reason: reason.clone(),
+                ended,
+            },
+            JobEventKind::Oom => return,
+        };
+        // the job keeps running, it's only forgotten after a restart
+        if let Err(err) = journal.record(&entry) {
+            log::error!("unable to record job {} in the journal: {}", job, err);
+        }
+    }
+
+    fn close_logs(&mut self, job: JobId) {
+        if let Err(err) = self.log_store.close(job) {
+            log::error!("unable to close logs of job {}: {}", job, err);
+        }
+    }
+
+    fn remove_shim(&self, job: JobId) {
+        if let Some(shims) = &self.shims {
+            if let Err(err) = shims.remove(job) {
+                log::warn!("unable to remove shim state of job {}: {}", job, err);
+            }
+        }
+    }
+
+    fn watch_jobs(&self, principal: Principal, filter: WatchFilter, sender: WatchSender) {
+        let events = self.lifecycle_tx.subscribe();
+        let authorizer = self.authorizer.clone();
+        tokio::spawn(async move {
+            Self::forward_events(events, sender, move |event| {
+                let scope = if event.owner == principal.name {
+                    Scope::Own
+                } else {
+                    Scope::Any
+                };
+                authorizer.authorize(&principal, Action::new(Operation::List, scope))
+                    && filter.matches(event)
+            })
+            .await
+        });
+    }
+
+    async fn forward_events<F: Fn(&JobEvent) -> bool>(
+        mut events: broadcast::Receiver<JobEvent>,
+        sender: WatchSender,
+        accept: F,
+    ) {
+        loop {
+            // a watcher that went away is noticed even when no events come
+            let received = tokio::select! {
+                received = events.recv() => received,
+                _ = sender.closed() => return,
+            };
+            let message = match received {
+                Ok(event) if accept(&event) => WatchMessage::Event(event),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => WatchMessage::Lagged { missed },
+                Err(RecvError::Closed) => return,
+            };
+            if sender.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+
     fn get_job(&mut self, job: JobId) -> Result<&mut Job, RuntimeError> {
         if let Some(job_instance) = self.jobs.get_mut(&job) {
             Ok(job_instance)
@@ -278,188 +1166,2130 @@ impl JobRuntime {
         }
     }
 
+    // Reads the output of the process and reports its lifecycle to the runtime.
+    // Sending into the bounded event channel waits when the runtime is busy,
+    // so a job producing output faster than we can store it gets blocked on its pipes.
     async fn handle_job(
         job: JobId,
         mut child: Child,
+        mut stdout: Box<dyn AsyncRead + Unpin + Send>,
+        mut stderr: Box<dyn AsyncRead + Unpin + Send>,
         mut kill_switch: oneshot::Receiver<()>,
-        event_tx: UnboundedSender<RuntimeEvent>,
+        event_tx: Sender<RuntimeEvent>,
     ) {
-        let mut stdout = child.stdout.take().expect(&format!(
-            "can't get access to stdout fd from child for job: {}",
-            job
-        ));
-        let mut stderr = child.stderr.take().expect(&format!(
-            "can't get access to stderr fd from child for job: {}",
-            job
-        ));
-
         if let Some(pid) = child.id() {
             event_tx
                 .send(RuntimeEvent::JobStart {
                     job,
                     pid: pid as i32,
                 })
+                .await
                 .expect(RUNTIME_EVENT_ERROR_MSG);
         };
 
         let mut stdout_buf = BytesMut::with_capacity(LOG_SIZE);
         let mut stderr_buf = BytesMut::with_capacity(LOG_SIZE);
+        let mut stdout_open = true;
+        let mut stderr_open = true;
+        let mut kill_switch_done = false;
+        let mut killed = false;
+        let mut exit_status = None;
+        let drain_deadline = tokio::time::sleep(PIPE_DRAIN_TIMEOUT);
+        tokio::pin!(drain_deadline);
+
+        // keep reading until the process exited and its output is fully consumed
+        while exit_status.is_none() || stdout_open || stderr_open {
+            stdout_buf.reserve(LOG_SIZE);
+            stderr_buf.reserve(LOG_SIZE);
 
-        loop {
             tokio::select! {
-                Ok(_) = stdout.read_buf(&mut stdout_buf)=> {
-                    let data = Bytes::copy_from_slice(&stdout_buf);
-                    event_tx.send(RuntimeEvent::LogCreated { job, record: LogRecord::Stdout(data)} ).expect(RUNTIME_EVENT_ERROR_MSG);
-                    stdout_buf.clear();
-                },
-                Ok(_) = stderr.read_buf(&mut stderr_buf) => {
-                    let data = Bytes::copy_from_slice(&stderr_buf);
-                    event_tx.send(RuntimeEvent::LogCreated { job, record: LogRecord::Stderr(data)} ).expect(RUNTIME_EVENT_ERROR_MSG);
-                    stderr_buf.clear();
+                res = stdout.read_buf(&mut stdout_buf), if stdout_open => {
+                    match res {
+                        Ok(n) if n > 0 => {
+                            let record = LogRecord::Stdout(stdout_buf.split().freeze());
+                            event_tx.send(RuntimeEvent::LogCreated { job, record }).await.expect(RUNTIME_EVENT_ERROR_MSG);
+                        },
+                        _ => stdout_open = false,
+                    }
                 },
-                Ok(status) = child.wait() => {
-                    event_tx.send(RuntimeEvent::JobExit{ job, status} ).expect(RUNTIME_EVENT_ERROR_MSG);
-                    break;
+                res = stderr.read_buf(&mut stderr_buf), if stderr_open => {
+                    match res {
+                        Ok(n) if n > 0 => {
+                            let record = LogRecord::Stderr(stderr_buf.split().freeze());
+                            event_tx.send(RuntimeEvent::LogCreated { job, record }).await.expect(RUNTIME_EVENT_ERROR_MSG);
+                        },
+                        _ => stderr_open = false,
+                    }
                 },
-                _ = &mut kill_switch => {
-                    match child.kill().await {
-                        Ok(_) => {
-                            event_tx.send(RuntimeEvent::JobKill { job } ).expect(RUNTIME_EVENT_ERROR_MSG);
+                res = child.wait(), if exit_status.is_none() => {
+                    match res {
+                        Ok(status) => {
+                            exit_status = Some(status);
+                            drain_deadline.as_mut().reset(tokio::time::Instant::now() + PIPE_DRAIN_TIMEOUT);
                         },
                         Err(err) => {
-                            log::error!("unable to kill process for job: {} | {}", job, err);
-                        }
+                            log::error!("unable to wait for process of job: {} | {}", job, err);
+                            return;
+                        },
                     }
+                },
+                _ = &mut drain_deadline, if exit_status.is_some() => {
+                    log::warn!("output of job {} is still open after the process exited", job);
                     break;
-                }
+                },
+                res = &mut kill_switch, if !kill_switch_done => {
+                    kill_switch_done = true;
+                    // the sender is dropped without a value when nobody asked to stop the job
+                    if res.is_ok() {
+                        match child.start_kill() {
+                            Ok(_) => killed = true,
+                            Err(err) => log::error!("unable to kill process for job: {} | {}", job, err),
+                        }
+                    }
+                },
             };
         }
 
-        // Check for any unsend data in stdout and stderr
-        // This should be refactored into proper function
-        if let Ok(_) = stdout.read_buf(&mut stdout_buf).await {
-            let data = Bytes::copy_from_slice(&stdout_buf);
-            event_tx.send(RuntimeEvent::LogCreated { job, record: LogRecord::Stdout(data)} ).expect(RUNTIME_EVENT_ERROR_MSG);
-            stdout_buf.clear();
-        }
+        let event = match exit_status {
+            Some(_) if killed => RuntimeEvent::JobKill { job },
+            Some(status) => RuntimeEvent::JobExit { job, status },
+            None => return,
+        };
+        event_tx.send(event).await.expect(RUNTIME_EVENT_ERROR_MSG);
+    }
 
-        if let Ok(_) = stderr.read_buf(&mut stderr_buf).await {
-            let data = Bytes::copy_from_slice(&stderr_buf);
-            event_tx.send(RuntimeEvent::LogCreated { job, record: LogRecord::Stderr(data)} ).expect(RUNTIME_EVENT_ERROR_MSG);
-            stdout_buf.clear();
-        }
+    // Follows a job run by a shim through the files the shim writes.
+    // Records with a lower sequence number than from_seq were stored before the daemon restarted.
+    async fn handle_shim_job(
+        job: JobId,
+        mut shim: ShimJob,
+        from_seq: u64,
+        written_until: watch::Receiver<Option<u64>>,
+        mut kill_switch: oneshot::Receiver<()>,
+        event_tx: Sender<RuntimeEvent>,
+    ) {
+        let mut kill_switch_done = false;
+        let mut killed = false;
+        let status = loop {
+            // the shim writes the status after all of the output and exits after the status
+            let written_until = *written_until.borrow();
+            let polled = tokio::task::spawn_blocking(move || {
+                let state = shim.poll(written_until);
+                (shim, state)
+            })
+            .await;
+            let state = match polled {
+                Ok((polled_shim, state)) => {
+                    shim = polled_shim;
+                    state
+                }
+                Err(err) => {
+                    log::error!("unable to read the state of job: {} | {}", job, err);
+                    return;
+                }
+            };
+            let shim::ShimState {
+                entries,
+                status,
+                alive,
+            } = match state {
+                Ok(state) => state,
+                Err(err) => {
+                    log::error!("unable to read the state of job: {} | {}", job, err);
+                    return;
+                }
+            };
+            for entry in entries.into_iter().filter(|entry| entry.seq >= from_seq) {
+                let record = entry.record;
+                event_tx
+                    .send(RuntimeEvent::LogCreated { job, record })
+                    .await
+                    .expect(RUNTIME_EVENT_ERROR_MSG);
+            }
+            match status {
+                Some(status) => break status,
+                // the shim was killed, e.g. together with the job by the OOM killer
+                None if !alive => {
+                    log::warn!("shim of job {} exited without the exit status", job);
+                    break ExitStatus::from_raw(SIGKILL);
+                }
+                None => {}
+            }
 
+            tokio::select! {
+                _ = tokio::time::sleep(shim::POLL_INTERVAL) => {},
+                res = &mut kill_switch, if !kill_switch_done => {
+                    kill_switch_done = true;
+                    // the sender is dropped without a value when nobody asked to stop the job
+                    if res.is_ok() {
+                        match shim.kill() {
+                            Ok(()) => killed = true,
+                            Err(err) => log::error!("unable to kill process for job: {} | {}", job, err),
+                        }
+                    }
+                },
+            }
+        };
 
+        let event = if killed {
+            RuntimeEvent::JobKill { job }
+        } else {
+            RuntimeEvent::JobExit { job, status }
+        };
+        event_tx.send(event).await.expect(RUNTIME_EVENT_ERROR_MSG);
     }
 
-    fn check_access_permissions(&self, job: JobId, owner: &Owner) -> bool {
-        if let Some(job_instance) = self.jobs.get(&job) {
-            if (owner == ADMIN_ROLE) || (job_instance.owner == *owner) {
-                return true;
-            };
+    // Jobs of other users need a permission for any job.
+    // A job that doesn't exist is reported as unauthorized, so nobody can probe for job ids.
+    fn authorize(
+        &self,
+        job: JobId,
+        principal: &Principal,
+        operation: Operation,
+    ) -> Result<(), RuntimeError> {
+        let scope = match self.jobs.get(&job) {
+            Some(job_instance) if job_instance.owner == principal.name => Scope::Own,
+            Some(_) => Scope::Any,
+            None => return Err(RuntimeError::Unauthorized),
+        };
+        if self
+            .authorizer
+            .authorize(principal, Action::new(operation, scope))
+        {
+            Ok(())
+        } else {
+            Err(RuntimeError::Unauthorized)
         }
-        false
     }
 
     fn store_logs(&mut self, job: JobId, record: LogRecord) {
         if let Some(job_instance) = self.jobs.get_mut(&job) {
-            if let Some(peers) = self.peers.get_mut(&job) {
-                // send message and remove clients that has closed the channel
-                peers.retain(|e| {
-                    if let Err(_) = e.sender.send(record.clone()) {
-                        false
-                    } else {
-                        true
-                    }
-                });
+            let entry = job_instance.next_log_entry(record);
+            if let Err(err) = self.log_store.append(job, &entry) {
+                log::error!("unable to store logs for job {}: {}", job, err);
+            }
+            job_instance.report_written(self.log_store.written_until(job));
+            if let Some(log_tx) = &job_instance.log_tx {
+                // a single send no matter how many followers there are,
+                // it fails only when nobody is following the job which is fine
+                let _ = log_tx.send(entry);
             };
-            job_instance.logs.push(record);
         }
     }
 
     fn send_logs(
         &mut self,
         job: JobId,
-        owner: Owner,
+        principal: Principal,
+        from_seq: u64,
         sender: LogSender,
     ) -> Result<(), RuntimeError> {
-        if !self.check_access_permissions(job, &owner) {
-            return Err(RuntimeError::Unauthorized);
-        };
+        self.authorize(job, &principal, Operation::Logs)?;
 
+        // subscribing and reading the history happen in the same step of the event loop,
+        // so the follower gets every record exactly once
         let job_instance = self.get_job(job)?;
-        for each in &job_instance.logs {
-            if let Err(e) = sender.send(each.clone()) {
-                log::error!("error while sending logs back to the clinet. {}", e);
-                break;
+        let live = job_instance
+            .log_tx
+            .as_ref()
+            .map(|log_tx| log_tx.subscribe());
+        let from_seq = if from_seq == LOG_SEQ_LATEST {
+            job_instance.next_seq
+        } else {
+            from_seq
+        };
+        // the history ends where the live records start
+        let end_seq = job_instance.next_seq;
+        let history = self.log_store.read(job, from_seq)?;
+        // history can be longer than the client buffer, so it's sent outside of the event loop
+        tokio::spawn(Self::forward_logs(
+            from_seq.max(end_seq),
+            history,
+            end_seq,
+            live,
+            sender,
+            self.slow_consumer_policy,
+        ));
+        Ok(())
+    }
+
+    async fn forward_logs(
+        from_seq: u64,
+        history: LogEntries,
+        end_seq: u64,
+        live: Option<broadcast::Receiver<LogEntry>>,
+        sender: LogSender,
+        policy: SlowConsumerPolicy,
+    ) {
+        // the store reads the history from disk with blocking calls
+        let history_sender = sender.clone();
+        let sent = tokio::task::spawn_blocking(move || {
+            for entry in history {
+                let entry = match entry {
+                    Ok(entry) if entry.seq >= end_seq => break,
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        log::error!("unable to read logs: {}", err);
+                        break;
+                    }
+                };
+                if history_sender
+                    .blocking_send(LogMessage::Entry(entry))
+                    .is_err()
+                {
+                    return false;
+                }
+            }
+            true
+        })
+        .await;
+        if !matches!(sent, Ok(true)) {
+            return;
+        }
+
+        let mut live = match live {
+            Some(live) => live,
+            None => return,
+        };
+        loop {
+            let message = match live.recv().await {
+                Ok(entry) if entry.seq < from_seq => continue,
+                Ok(entry) => LogMessage::Entry(entry),
+                Err(RecvError::Lagged(missed)) => match policy {
+                    SlowConsumerPolicy::Lag => LogMessage::Lagged { missed },
+                    SlowConsumerPolicy::Disconnect => {
+                        let _ = sender.send(LogMessage::Disconnected { missed }).await;
+                        return;
+                    }
+                },
+                Err(RecvError::Closed) => return,
             };
+            if sender.send(message).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn stop_job(&mut self, job: JobId, principal: Principal) -> Result<(), RuntimeError> {
+        self.authorize(job, &principal, Operation::Stop)?;
+        let job_instance = self.get_job(job)?;
+        // a pending job is waiting in the queue or for its dependencies
+        if job_instance.queued.is_some() {
+            job_instance.queued = None;
+            job_instance.dependencies.clear();
+            job_instance.killed(SIGKILL);
+            self.scheduler.dequeue(job);
+            self.publish(job, JobEventKind::Killed { signal: SIGKILL });
+            self.job_done(job);
+            return Ok(());
         }
-        match job_instance.status {
-            JobStatus::Pending | JobStatus::Running { .. } => {
-                let peers = self.peers.entry(job).or_insert(HashSet::new());
-                peers.insert(LogClient::new(sender.clone()));
+        if let Some(rx) = self.get_job(job)?.kill_switch.take() {
+            // the receiver is gone when the job has already finished
+            if rx.send(()).is_err() {
+                log::warn!("job {} has already finished", job);
             }
-            JobStatus::Finished { .. } | JobStatus::Killed { .. } => {}
+        };
+        Ok(())
+    }
+
+    fn delete_job(&mut self, job: JobId, principal: Principal) -> Result<(), RuntimeError> {
+        self.authorize(job, &principal, Operation::Delete)?;
+        if !self.get_job(job)?.is_done() {
+            return Err(RuntimeError::JobNotEnded);
         }
+        self.purge(job);
         Ok(())
     }
 
-    fn stop_job(&mut self, job: JobId, owner: Owner) -> Result<(), RuntimeError> {
-        if !self.check_access_permissions(job, &owner) {
+    fn prune_jobs(
+        &mut self,
+        principal: Principal,
+        older_than: Option<Duration>,
+        all_users: bool,
+    ) -> Result<Vec<JobId>, RuntimeError> {
+        let scope = if all_users { Scope::Any } else { Scope::Own };
+        if !self
+            .authorizer
+            .authorize(&principal, Action::new(Operation::Delete, scope))
+        {
             return Err(RuntimeError::Unauthorized);
+        }
+        let now = SystemTime::now();
+        let jobs: Vec<JobId> = self
+            .finished_jobs()
+            .into_iter()
+            .filter(|job| all_users || job.owner == principal.name)
+            .filter(|job| match older_than {
+                Some(older_than) => now
+                    .duration_since(job.ended)
+                    .map(|age| age > older_than)
+                    .unwrap_or(false),
+                None => true,
+            })
+            .map(|job| job.job)
+            .collect();
+        for job in &jobs {
+            self.purge(*job);
+        }
+        Ok(jobs)
+    }
+
+    fn finished_jobs(&self) -> Vec<FinishedJob> {
+        self.jobs
+            .values()
+            .filter(|job| job.is_done())
+            .filter_map(|job| {
+                job.ended.map(|ended| FinishedJob {
+                    job: job.uuid,
+                    owner: job.owner.clone(),
+                    ended,
+                    failed: !job.succeeded(),
+                })
+            })
+            .collect()
+    }
+
+    fn apply_retention(&mut self) {
+        if !self.retention.is_enabled() {
+            return;
+        }
+        let expired = self
+            .retention
+            .expired(self.finished_jobs(), SystemTime::now());
+        if !expired.is_empty() {
+            log::info!("deleting {} jobs past their retention", expired.len());
+        }
+        for job in expired {
+            self.purge(job);
+        }
+    }
+
+    // Forgets an ended job and removes everything it left behind: logs, cgroup, shim state,
+    // its place in batches and groups, and eventually its entries in the journal
+    fn purge(&mut self, job: JobId) {
+        let job_instance = match self.jobs.remove(&job) {
+            Some(job_instance) => job_instance,
+            None => return,
         };
-        if let Some(rx) = self.get_job(job)?.kill_switch.take() {
-            rx.send(()).expect("killing already dead job");
+        if let Err(err) = self.log_store.remove(job) {
+            log::warn!("unable to remove logs of job {}: {}", job, err);
+        }
+        if let Some(cgroup) = job_instance.cgroup {
+            if let Err(err) = cgroup.remove() {
+                log::warn!("unable to remove cgroup of job {}: {}", job, err);
+            }
+        }
+        self.remove_shim(job);
+        self.dependants.remove(&job);
+
+        if let Some(batch) = job_instance
+            .labels
+            .get(batch::BATCH_LABEL)
+            .and_then(|batch| batch.parse().ok())
+        {
+            if let Some(jobs) = self.batches.get_mut(&batch) {
+                jobs.retain(|each| *each != job);
+                if jobs.is_empty() {
+                    self.batches.remove(&batch);
+                }
+            }
+        }
+        if let Some(name) = job_instance.labels.get(group::GROUP_LABEL) {
+            self.leave_group(job, name);
+        }
+
+        if let Some(journal) = &self.journal {
+            if let Err(err) = journal.record(&JournalEntry::Deleted { job }) {
+                log::error!("unable to record job {} in the journal: {}", job, err);
+            }
+        }
+    }
+
+    fn send_status(
+        &mut self,
+        job: JobId,
+        principal: Principal,
+    ) -> Result<JobStatusResponse, RuntimeError> {
+        self.authorize(job, &principal, Operation::Status)?;
+        let mut response = self.get_job(job)?.status_response();
+        response.queue_position = self.scheduler.position(job);
+        Ok(response)
+    }
+
+    fn wait_job(&mut self, job: JobId, principal: Principal, sender: WaitSender) {
+        if let Err(err) = self.authorize(job, &principal, Operation::Status) {
+            let _ = sender.send(Err(err));
+            return;
         };
+        match self.get_job(job) {
+            Ok(job_instance) if job_instance.is_done() => {
+                let _ = sender.send(Ok(job_instance.status_response()));
+            }
+            Ok(job_instance) => job_instance.waiters.push(sender),
+            Err(err) => {
+                let _ = sender.send(Err(err));
+            }
+        }
+    }
+
+    fn open_stdin(
+        &mut self,
+        job: JobId,
+        principal: Principal,
+    ) -> Result<StdinWriter, RuntimeError> {
+        self.authorize(job, &principal, Operation::Stdin)?;
+        self.get_job(job)?
+            .stdin
+            .clone()
+            .ok_or(RuntimeError::StdinNotOpen)
+    }
+
+    fn close_stdin(&mut self, job: JobId, principal: Principal) -> Result<(), RuntimeError> {
+        self.authorize(job, &principal, Operation::Stdin)?;
+        self.get_job(job)?
+            .stdin
+            .take()
+            .map(|_| ())
+            .ok_or(RuntimeError::StdinNotOpen)
+    }
+
+    fn resize_terminal(
+        &mut self,
+        job: JobId,
+        principal: Principal,
+        rows: u16,
+        cols: u16,
+    ) -> Result<(), RuntimeError> {
+        self.authorize(job, &principal, Operation::Stdin)?;
+        match &self.get_job(job)?.pty {
+            Some(pty) => Ok(pty.resize(rows, cols)?),
+            None => Err(RuntimeError::NotATerminal),
+        }
+    }
+
+    async fn write_stdin<W: AsyncWrite + Unpin>(job: JobId, mut stdin: W, mut rx: Receiver<Bytes>) {
+        while let Some(data) = rx.recv().await {
+            if let Err(err) = stdin.write_all(&data).await {
+                log::warn!("unable to write to stdin of job: {} | {}", job, err);
+                break;
+            }
+        }
+        // dropping the pipe closes it, so the process sees EOF
+    }
+
+    // Jobs that haven't finished yet and starts within the rate window count against the quota
+    fn usage_of(&mut self, user: &str) -> Usage {
+        let mut usage = Usage::default();
+        for job in self.jobs.values() {
+            if job.owner == user && !job.is_done() {
+                usage.jobs += 1;
+                usage.memory = usage
+                    .memory
+                    .saturating_add(job.limits.memory_max.unwrap_or_default());
+                usage.cpu = usage
+                    .cpu
+                    .saturating_add(u64::from(job.limits.cpu_max.unwrap_or_default()));
+            }
+        }
+        if let Some(activity) = self.activity.get_mut(user) {
+            while let Some(start) = activity.starts.front() {
+                if start.elapsed() < START_RATE_WINDOW {
+                    break;
+                }
+                activity.starts.pop_front();
+            }
+            usage.starts_last_minute = activity.starts.len() as u64;
+        }
+        usage
+    }
+
+    fn admit(
+        &mut self,
+        principal: &Principal,
+        limits: &ResourceLimits,
+    ) -> Result<(), RuntimeError> {
+        let roles = self.authorizer.roles(principal);
+        let quota = self.quotas.quota_of(&principal.name, &roles);
+        let usage = self.usage_of(&principal.name);
+        quota
+            .admit(&usage, limits)
+            .map_err(RuntimeError::QuotaExceeded)?;
+
+        let activity = self.activity.entry(principal.name.clone()).or_default();
+        activity.roles = roles;
+        activity.starts.push_back(Instant::now());
         Ok(())
     }
 
-    fn send_status(&mut self, job: JobId, owner: Owner) -> Result<JobStatusResponse, RuntimeError> {
-        if !self.check_access_permissions(job, &owner) {
+    // Roles of other users come from their last start, certificates of users
+    // that haven't started anything yet are unknown, so only the policy counts for them
+    fn quota_usage(
+        &mut self,
+        principal: Principal,
+        user: Option<Owner>,
+    ) -> Result<QuotaUsage, RuntimeError> {
+        let user = user.unwrap_or_else(|| principal.name.clone());
+        let scope = if user == principal.name {
+            Scope::Own
+        } else {
+            Scope::Any
+        };
+        if !self
+            .authorizer
+            .authorize(&principal, Action::new(Operation::Usage, scope))
+        {
             return Err(RuntimeError::Unauthorized);
+        }
+
+        let roles = match self.activity.get(&user) {
+            Some(activity) => activity.roles.clone(),
+            None if user == principal.name => self.authorizer.roles(&principal),
+            None => self.authorizer.roles(&Principal::new(user.clone(), vec![])),
         };
-        Ok(JobStatusResponse {
-            job,
-            owner,
-            status: self.get_job(job)?.status.clone(),
+        Ok(QuotaUsage {
+            quota: self.quotas.quota_of(&user, &roles),
+            usage: self.usage_of(&user),
+            user,
         })
     }
 
+    // Authorizes and admits a new job without starting it.
+    // Dependencies that have already ended are settled right away.
+    fn prepare_job(
+        &mut self,
+        principal: &Principal,
+        mut request: JobRequest,
+    ) -> Result<Job, RuntimeError> {
+        if !self
+            .authorizer
+            .authorize(principal, Action::new(Operation::Start, Scope::Own))
+        {
+            return Err(RuntimeError::Unauthorized);
+        }
+        if let Some(pool) = &request.pool {
+            if !self.scheduler.has_pool(pool) {
+                return Err(RuntimeError::UnknownPool(pool.clone()));
+            }
+        }
+        // waiting for a job reveals when it ends, so it needs the same permission as its status
+        for dependency in &request.dependencies {
+            self.authorize(dependency.job, principal, Operation::Status)?;
+        }
+        if let Some(group) = &request.group {
+            group::validate_name(group).map_err(RuntimeError::InvalidGroup)?;
+            // joining a group of somebody else is like starting a job on its behalf
+            if self.groups.contains_key(group) {
+                self.authorize_group(group, principal, Operation::Start)?;
+            }
+        }
+        if !self
+            .authorizer
+            .authorize(principal, Action::new(Operation::Priority, Scope::Any))
+        {
+            request.priority = request
+                .priority
+                .clamp(*USER_PRIORITIES.start(), *USER_PRIORITIES.end());
+        }
+        self.quotas.apply_default_limits(&mut request.limits);
+        self.admit(principal, &request.limits)?;
+        if let Some(group) = request.group.clone() {
+            self.join_group(group, principal, request.group_limits.take())?;
+        }
+
+        if let Some(group) = &request.group {
+            request
+                .labels
+                .insert(String::from(group::GROUP_LABEL), group.clone());
+        }
+        let (mut job, kill_switch) =
+            Job::new(principal.name.clone(), std::mem::take(&mut request.labels));
+        job.limits = request.limits.clone();
+        job.pool = request.pool.clone();
+        job.command = std::iter::once(request.path.clone())
+            .chain(request.args.iter().cloned())
+            .collect();
+        // stdin can be written while the job is pending
+        let stdin = if request.stdin || request.tty {
+            let (stdin_tx, stdin_rx) = mpsc::channel(STDIN_CHANNEL_SIZE);
+            job.stdin = Some(stdin_tx);
+            Some(stdin_rx)
+        } else {
+            None
+        };
+        let dependencies = std::mem::take(&mut request.dependencies);
+        job.queued = Some(QueuedStart {
+            request,
+            kill_switch,
+            stdin,
+        });
+
+        for dependency in dependencies {
+            match self.jobs.get(&dependency.job) {
+                Some(before) if before.is_done() => {
+                    if dependency.condition == DependencyCondition::Success && !before.succeeded() {
+                        job.cancelled(format!(
+                            "dependency {} did not succeed",
+                            dependency.job.to_simple()
+                        ));
+                        break;
+                    }
+                }
+                _ => {
+                    self.dependants
+                        .entry(dependency.job)
+                        .or_default()
+                        .push(job.uuid);
+                    job.dependencies.push(dependency);
+                }
+            }
+        }
+        Ok(job)
+    }
+
+    fn insert_job(&mut self, job: Job) {
+        let id = job.uuid;
+        self.jobs.insert(id, job);
+        self.announce(id);
+    }
+
+    // Adds a job that is already in the table to its group and publishes its creation.
+    // Jobs join their group only here, so groups never list jobs that failed to start.
+    fn announce(&mut self, job: JobId) {
+        let job_instance = match self.jobs.get(&job) {
+            Some(job_instance) => job_instance,
+            None => return,
+        };
+        let cancelled = match &job_instance.status {
+            JobStatus::Cancelled { reason } => Some(reason.clone()),
+            _ => None,
+        };
+        if let Some(group) = job_instance
+            .labels
+            .get(group::GROUP_LABEL)
+            .and_then(|group| self.groups.get_mut(group))
+        {
+            group.jobs.push(job);
+        }
+        self.publish(job, JobEventKind::Created);
+        if let Some(reason) = cancelled {
+            self.publish(job, JobEventKind::Cancelled { reason });
+        }
+    }
+
+    // Forgets jobs that were prepared but never announced, as if they were never submitted,
+    // so jobs of a rejected workflow leave no trace in events, groups or the rate of starts
+    fn discard(&mut self, jobs: &[JobId]) {
+        for job in jobs.iter().rev() {
+            let job_instance = match self.jobs.remove(job) {
+                Some(job_instance) => job_instance,
+                None => continue,
+            };
+            for dependency in &job_instance.dependencies {
+                if let Some(dependants) = self.dependants.get_mut(&dependency.job) {
+                    dependants.retain(|dependant| dependant != job);
+                    if dependants.is_empty() {
+                        self.dependants.remove(&dependency.job);
+                    }
+                }
+            }
+            if let Some(group) = job_instance.labels.get(group::GROUP_LABEL) {
+                self.leave_group(*job, group);
+            }
+            // the starts of the rejected jobs are the last ones of their owner
+            if let Some(activity) = self.activity.get_mut(&job_instance.owner) {
+                activity.starts.pop_back();
+            }
+        }
+    }
+
+    // Puts a pending job without dependencies into the queue
+    fn release(&mut self, job: JobId) {
+        if let Some(job_instance) = self.jobs.get(&job) {
+            if let Some(queued) = &job_instance.queued {
+                if job_instance.dependencies.is_empty() {
+                    self.scheduler.enqueue(
+                        job,
+                        &job_instance.owner,
+                        queued.request.priority,
+                        queued.request.pool.as_deref(),
+                    );
+                }
+            }
+        }
+    }
+
     fn start_job(
         &mut self,
-        path: String,
-        args: Vec<String>,
-        owner: String,
-        sender: StartSender,
-        limits: ResourceLimits,
-    ) -> Result<(), RuntimeError> {
-        let (job, kill_switch) = Job::new(owner);
-        let cmd = Command::new(path)
-            .args(args)
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .stdin(Stdio::null())
-            .spawn()?;
-
-        let ret = job.uuid.clone();
-
-        if let Some(runtime_cgroup) = &self.cgroup {
-            let job_cgroup = Cgroup::new_relative_to(&runtime_cgroup, &ret.to_string())?;
-            job_cgroup.apply_limits(limits)?;
-            job_cgroup.add_task(&cmd)?;
+        principal: Principal,
+        request: JobRequest,
+    ) -> Result<JobId, RuntimeError> {
+        let mut job = self.prepare_job(&principal, request)?;
+        let ret = job.uuid;
+        let pool = job.pool.clone();
+
+        // a job that can run right away reports errors of spawning it to the caller,
+        // one cancelled by a failed dependency doesn't run at all
+        if job.dependencies.is_empty()
+            && job.queued.is_some()
+            && !job.is_done()
+            && self.scheduler.try_run(ret, &job.owner, pool.as_deref())
+        {
+            if let Some(queued) = job.queued.take() {
+                if let Err(err) =
+                    self.spawn(&mut job, queued.request, queued.kill_switch, queued.stdin)
+                {
+                    self.scheduler.finished(ret);
+                    // a group created for the job goes away with it
+                    if let Some(group) = job.labels.get(group::GROUP_LABEL) {
+                        self.leave_group(ret, group);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+
+        self.insert_job(job);
+        self.release(ret);
+        Ok(ret)
+    }
+
+    fn start_workflow(
+        &mut self,
+        principal: Principal,
+        steps: Vec<WorkflowStep>,
+    ) -> Result<StartedWorkflow, RuntimeError> {
+        let order = workflow::step_order(&steps).map_err(RuntimeError::InvalidWorkflow)?;
+        let workflow = Uuid::new_v4();
+        let mut steps: Vec<Option<WorkflowStep>> = steps.into_iter().map(Some).collect();
+        let mut jobs: HashMap<String, JobId> = HashMap::new();
+        let mut created = vec![];
+
+        for index in order {
+            let step = match steps[index].take() {
+                Some(step) => step,
+                None => continue,
+            };
+            let mut request = step.request;
+            request
+                .labels
+                .insert(String::from(workflow::WORKFLOW_LABEL), workflow.to_string());
+            request
+                .labels
+                .insert(String::from(workflow::STEP_LABEL), step.name.clone());
+            // steps are created in order, so the ones this step depends on already exist
+            request
+                .dependencies
+                .extend(step.after.iter().filter_map(|dependency| {
+                    jobs.get(&dependency.step).map(|job| Dependency {
+                        job: *job,
+                        condition: dependency.condition,
+                    })
+                }));
+
+            // steps are announced once all of them are admitted
+            match self.prepare_job(&principal, request) {
+                Ok(job) => {
+                    jobs.insert(step.name, job.uuid);
+                    created.push(job.uuid);
+                    self.jobs.insert(job.uuid, job);
+                }
+                Err(err) => {
+                    // nothing has been released yet, so none of the steps ran
+                    self.discard(&created);
+                    return Err(err);
+                }
+            }
+        }
+
+        for job in &created {
+            self.announce(*job);
+            self.release(*job);
+        }
+        for job in self.dispatch() {
+            self.job_done(job);
+        }
+        Ok(StartedWorkflow {
+            id: workflow,
+            jobs: jobs.into_iter().collect(),
+        })
+    }
+
+    fn start_batch(
+        &mut self,
+        principal: Principal,
+        requests: Vec<JobRequest>,
+        atomic: bool,
+    ) -> Result<StartedBatch, RuntimeError> {
+        if requests.is_empty() {
+            return Err(RuntimeError::InvalidBatch(String::from(
+                "batch has no jobs",
+            )));
+        }
+        if requests.len() > batch::MAX_BATCH_SIZE {
+            return Err(RuntimeError::InvalidBatch(format!(
+                "batch has {} jobs, at most {} are allowed",
+                requests.len(),
+                batch::MAX_BATCH_SIZE
+            )));
+        }
+        let id = Uuid::new_v4();
+        let requests = requests.into_iter().map(|mut request| {
+            request
+                .labels
+                .insert(String::from(batch::BATCH_LABEL), id.to_string());
+            request
+        });
+
+        let jobs = if atomic {
+            // the same as a workflow without dependencies, nothing runs before every job is admitted
+            let mut created = vec![];
+            for request in requests {
+                match self.prepare_job(&principal, request) {
+                    Ok(job) => {
+                        created.push(job.uuid);
+                        self.jobs.insert(job.uuid, job);
+                    }
+                    Err(err) => {
+                        self.discard(&created);
+                        return Err(err);
+                    }
+                }
+            }
+            for job in &created {
+                self.announce(*job);
+                self.release(*job);
+            }
+            for job in self.dispatch() {
+                self.job_done(job);
+            }
+            created.into_iter().map(Ok).collect()
+        } else {
+            requests
+                .map(|request| self.start_job(principal.clone(), request))
+                .collect::<Vec<_>>()
         };
 
-        tokio::spawn(Self::handle_job(
-            job.uuid.clone(),
-            cmd,
-            kill_switch,
-            self.event_tx.clone(),
-        ));
+        let started = jobs.iter().filter_map(|job| job.as_ref().ok()).copied();
+        self.batches.insert(id, started.collect());
+        Ok(StartedBatch { id, jobs })
+    }
+
+    // Jobs of a batch if the principal may do the operation with all of them
+    fn batch_jobs(
+        &mut self,
+        batch: BatchId,
+        principal: &Principal,
+        operation: Operation,
+    ) -> Result<Vec<JobId>, RuntimeError> {
+        let jobs = match self.batches.get(&batch) {
+            Some(jobs) => jobs.clone(),
+            None => return Err(RuntimeError::Unauthorized),
+        };
+        for job in &jobs {
+            self.authorize(*job, principal, operation)?;
+        }
+        Ok(jobs)
+    }
+
+    fn stop_batch(&mut self, batch: BatchId, principal: Principal) -> Result<(), RuntimeError> {
+        for job in self.batch_jobs(batch, &principal, Operation::Stop)? {
+            if !self.get_job(job)?.is_done() {
+                self.stop_job(job, principal.clone())?;
+            }
+        }
+        Ok(())
+    }
 
-        self.jobs.insert(job.uuid, job);
-        if let Err(_) = sender.send(ret) {
-            log::error!("start_job can't send back response to client");
+    fn authorize_group(
+        &self,
+        group: &str,
+        principal: &Principal,
+        operation: Operation,
+    ) -> Result<(), RuntimeError> {
+        let scope = match self.groups.get(group) {
+            Some(group) if group.owner == principal.name => Scope::Own,
+            Some(_) => Scope::Any,
+            None => return Err(RuntimeError::Unauthorized),
         };
+        if self
+            .authorizer
+            .authorize(principal, Action::new(operation, scope))
+        {
+            Ok(())
+        } else {
+            Err(RuntimeError::Unauthorized)
+        }
+    }
+
+    // Creates the group on the first job and replaces its limits when they are given
+    fn join_group(
+        &mut self,
+        name: String,
+        principal: &Principal,
+        limits: Option<ResourceLimits>,
+    ) -> Result<(), RuntimeError> {
+        if !self.groups.contains_key(&name) {
+            let mut group = Group::new(name.clone(), principal.name.clone());
+            group.limits = limits.clone().unwrap_or_default();
+            if let Some(runtime_cgroup) = &self.cgroup {
+                group.create_cgroup(runtime_cgroup)?;
+            }
+            self.groups.insert(name, group);
+        } else if let Some(group) = self.groups.get_mut(&name) {
+            if let Some(limits) = limits {
+                group.set_limits(limits)?;
+            }
+            // groups restored from the journal get their cgroup back with the first new job
+            if let (None, Some(runtime_cgroup)) = (group.cgroup(), &self.cgroup) {
+                group.create_cgroup(runtime_cgroup)?;
+            }
+        }
         Ok(())
     }
+
+    // Removes the job from the group and the group once it has no jobs left,
+    // e.g. when the job that created it couldn't start
+    fn leave_group(&mut self, job: JobId, name: &str) {
+        if let Some(group) = self.groups.get_mut(name) {
+            group.jobs.retain(|each| *each != job);
+            if group.jobs.is_empty() {
+                if let Some(cgroup) = group.take_cgroup() {
+                    if let Err(err) = cgroup.remove() {
+                        log::warn!("unable to remove cgroup of group {}: {}", name, err);
+                    }
+                }
+                self.groups.remove(name);
+            }
+        }
+    }
+
+    fn group_jobs(
+        &self,
+        group: &str,
+        principal: &Principal,
+        operation: Operation,
+    ) -> Result<Vec<JobId>, RuntimeError> {
+        self.authorize_group(group, principal, operation)?;
+        Ok(self
+            .groups
+            .get(group)
+            .map(|group| group.jobs.clone())
+            .unwrap_or_default())
+    }
+
+    fn stop_group(&mut self, group: &str, principal: Principal) -> Result<(), RuntimeError> {
+        for job in self.group_jobs(group, &principal, Operation::Stop)? {
+            // jobs deleted in the meantime are skipped
+            let done = match self.jobs.get(&job) {
+                Some(job_instance) => job_instance.is_done(),
+                None => continue,
+            };
+            if !done {
+                self.stop_job(job, principal.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Pending jobs are left alone, they get no signal once they start
+    fn signal_group(
+        &mut self,
+        group: &str,
+        principal: Principal,
+        signal: i32,
+    ) -> Result<(), RuntimeError> {
+        if signal <= 0 || signal > libc::SIGRTMAX() {
+            return Err(RuntimeError::InvalidSignal(signal));
+        }
+        for job in self.group_jobs(group, &principal, Operation::Signal)? {
+            let status = self.jobs.get(&job).map(|job_instance| &job_instance.status);
+            if let Some(JobStatus::Running { pid }) = status {
+                if unsafe { libc::kill(*pid, signal) } != 0 {
+                    let err = std::io::Error::last_os_error();
+                    // the process may have just exited and the event is still on its way
+                    if err.raw_os_error() != Some(libc::ESRCH) {
+                        return Err(err.into());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn group_status(
+        &self,
+        group: &str,
+        principal: &Principal,
+    ) -> Result<GroupStatus, RuntimeError> {
+        self.authorize_group(group, principal, Operation::Status)?;
+        self.groups
+            .get(group)
+            .map(|group| self.group_status_of(group))
+            .ok_or(RuntimeError::Unauthorized)
+    }
+
+    fn group_status_of(&self, group: &Group) -> GroupStatus {
+        let jobs = group
+            .jobs
+            .iter()
+            .filter_map(|job| self.jobs.get(job))
+            .map(|job| {
+                let mut response = job.status_response();
+                response.queue_position = self.scheduler.position(job.uuid);
+                response
+            })
+            .collect();
+        GroupStatus {
+            name: group.name.clone(),
+            owner: group.owner.clone(),
+            limits: group.limits.clone(),
+            jobs,
+        }
+    }
+
+    fn list_groups(&self, principal: &Principal) -> Vec<GroupStatus> {
+        let mut groups: Vec<GroupStatus> = self
+            .groups
+            .values()
+            .filter(|group| {
+                let scope = if group.owner == principal.name {
+                    Scope::Own
+                } else {
+                    Scope::Any
+                };
+                self.authorizer
+                    .authorize(principal, Action::new(Operation::List, scope))
+            })
+            .map(|group| self.group_status_of(group))
+            .collect();
+        groups.sort_by(|a, b| a.name.cmp(&b.name));
+        groups
+    }
+
+    fn cancel(&mut self, job: JobId, reason: String) {
+        if let Some(job_instance) = self.jobs.get_mut(&job) {
+            if job_instance.is_done() {
+                return;
+            }
+            job_instance.cancelled(reason.clone());
+            self.scheduler.dequeue(job);
+            self.publish(job, JobEventKind::Cancelled { reason });
+        }
+    }
+
+    // Frees the slot of a job that ended, settles the jobs depending on it
+    // and starts whatever can run now
+    fn job_done(&mut self, job: JobId) {
+        let mut ended = vec![job];
+        while !ended.is_empty() {
+            while let Some(job) = ended.pop() {
+                self.scheduler.finished(job);
+                ended.extend(self.settle_dependants(job));
+            }
+            // jobs that can't be started end right away too
+            ended = self.dispatch();
+        }
+    }
+
+    // Releases jobs whose last dependency ended and cancels the ones whose dependency failed.
+    // Returns the cancelled jobs, their own dependants have to be settled next.
+    fn settle_dependants(&mut self, done: JobId) -> Vec<JobId> {
+        let succeeded = match self.jobs.get(&done) {
+            Some(job_instance) => job_instance.succeeded(),
+            None => false,
+        };
+        let mut cancelled = vec![];
+        for job in self.dependants.remove(&done).unwrap_or_default() {
+            let job_instance = match self.jobs.get_mut(&job) {
+                Some(job_instance) if !job_instance.is_done() => job_instance,
+                _ => continue,
+            };
+            let mut failed = false;
+            job_instance.dependencies.retain(|dependency| {
+                if dependency.job != done {
+                    return true;
+                }
+                failed |= dependency.condition == DependencyCondition::Success && !succeeded;
+                false
+            });
+            if failed {
+                self.cancel(
+                    job,
+                    format!("dependency {} did not succeed", done.to_simple()),
+                );
+                cancelled.push(job);
+            } else {
+                self.release(job);
+            }
+        }
+        cancelled
+    }
+
+    // Starts queued jobs while there are free slots and returns the ones that failed to start
+    fn dispatch(&mut self) -> Vec<JobId> {
+        let mut failed = vec![];
+        while let Some(job) = self.scheduler.pick_next() {
+            let mut job_instance = match self.jobs.remove(&job) {
+                Some(job_instance) => job_instance,
+                None => {
+                    self.scheduler.finished(job);
+                    continue;
+                }
+            };
+            let result = match job_instance.queued.take() {
+                Some(queued) => self.spawn(
+                    &mut job_instance,
+                    queued.request,
+                    queued.kill_switch,
+                    queued.stdin,
+                ),
+                None => Ok(()),
+            };
+            self.jobs.insert(job, job_instance);
+
+            if let Err(err) = result {
+                self.spawn_failed(job, error_reason(err));
+                failed.push(job);
+            }
+        }
+        failed
+    }
+
+    // Errors can't be reported to the caller anymore, so they end up in the logs of the job
+    fn spawn_failed(&mut self, job: JobId, reason: String) {
+        log::error!("unable to start job {}: {}", job, reason);
+        let message = format!("unable to start job: {}\n", reason);
+        self.store_logs(job, LogRecord::Stderr(Bytes::from(message)));
+        if let Ok(job_instance) = self.get_job(job) {
+            job_instance.finished(SPAWN_FAILED_EXIT_CODE);
+        }
+        self.publish(
+            job,
+            JobEventKind::Exited {
+                exit_code: SPAWN_FAILED_EXIT_CODE,
+            },
+        );
+    }
+
+    fn authorize_schedule(
+        &self,
+        schedule: ScheduleId,
+        principal: &Principal,
+    ) -> Result<(), RuntimeError> {
+        let scope = match self.schedules.get(schedule) {
+            Some(schedule) if schedule.owner == principal.name => Scope::Own,
+            Some(_) => Scope::Any,
+            None => return Err(RuntimeError::Unauthorized),
+        };
+        if self
+            .authorizer
+            .authorize(principal, Action::new(Operation::Schedule, scope))
+        {
+            Ok(())
+        } else {
+            Err(RuntimeError::Unauthorized)
+        }
+    }
+
+    fn create_schedule(
+        &mut self,
+        principal: Principal,
+        request: JobRequest,
+        when: When,
+        overlap: OverlapPolicy,
+    ) -> Result<ScheduleId, RuntimeError> {
+        for operation in [Operation::Schedule, Operation::Start] {
+            if !self
+                .authorizer
+                .authorize(&principal, Action::new(operation, Scope::Own))
+            {
+                return Err(RuntimeError::Unauthorized);
+            }
+        }
+        if let Some(pool) = &request.pool {
+            if !self.scheduler.has_pool(pool) {
+                return Err(RuntimeError::UnknownPool(pool.clone()));
+            }
+        }
+        let job =
+            schedule::JobTemplate::try_from(request).map_err(RuntimeError::InvalidSchedule)?;
+        let mut schedule = Schedule::new(principal.name, job, when);
+        schedule.overlap = overlap;
+        if schedule.next_run.is_none() {
+            return Err(RuntimeError::InvalidSchedule(String::from(
+                "cron expression never matches",
+            )));
+        }
+
+        let id = schedule.id;
+        self.schedules.insert(schedule);
+        if let Err(err) = self.schedules.save() {
+            self.schedules.remove(id);
+            return Err(err.into());
+        }
+        Ok(id)
+    }
+
+    fn list_schedules(&self, principal: Principal) -> Vec<Schedule> {
+        self.schedules
+            .iter()
+            .filter(|schedule| {
+                let scope = if schedule.owner == principal.name {
+                    Scope::Own
+                } else {
+                    Scope::Any
+                };
+                self.authorizer
+                    .authorize(&principal, Action::new(Operation::List, scope))
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn update_schedule(
+        &mut self,
+        principal: Principal,
+        schedule: ScheduleId,
+        change: ScheduleChange,
+    ) -> Result<(), RuntimeError> {
+        self.authorize_schedule(schedule, &principal)?;
+        match change {
+            ScheduleChange::Delete => {
+                self.schedules.remove(schedule);
+            }
+            ScheduleChange::Pause | ScheduleChange::Resume => {
+                if let Some(schedule) = self.schedules.get_mut(schedule) {
+                    schedule.paused = change == ScheduleChange::Pause;
+                    // runs missed while paused are not made up
+                    if let When::Cron(_) = schedule.when {
+                        schedule.next_run = schedule.next_run_after(chrono::Utc::now());
+                    }
+                    schedule.queued_run = false;
+                }
+            }
+        }
+        Ok(self.schedules.save()?)
+    }
+
+    // Starts the runs that are due as the owner of the schedule, so they go through
+    // the same authorization, quota and queue as jobs started by the owner itself
+    fn run_due_schedules(&mut self) {
+        let now = chrono::Utc::now();
+        let mut changed = false;
+        for id in self.schedules.due(now.timestamp()) {
+            let schedule = match self.schedules.get(id) {
+                Some(schedule) => schedule,
+                None => continue,
+            };
+            let previous_running = match schedule.last_job.and_then(|job| self.jobs.get(&job)) {
+                Some(job) => !job.is_done(),
+                None => false,
+            };
+            let run_is_due = matches!(schedule.next_run, Some(next) if next <= now.timestamp());
+            if !run_is_due && previous_running {
+                // a queued run keeps waiting for the previous one
+                continue;
+            }
+            let launch = match (previous_running, schedule.overlap) {
+                (false, _) | (true, OverlapPolicy::Allow) => true,
+                (true, OverlapPolicy::Skip) => {
+                    log::info!(
+                        "skipping run of schedule {}, the previous run is still going",
+                        id
+                    );
+                    false
+                }
+                (true, OverlapPolicy::Queue) => false,
+            };
+            // the certificate of the owner isn't around, so its roles come from the policy as it is now
+            let principal = Principal::new(schedule.owner.clone(), vec![]);
+            let request = schedule.job_request();
+
+            let result = if launch {
+                Some(self.start_job(principal, request))
+            } else {
+                None
+            };
+            if let Some(schedule) = self.schedules.get_mut(id) {
+                if run_is_due {
+                    schedule.next_run = schedule.next_run_after(now);
+                }
+                schedule.queued_run = !launch && schedule.overlap == OverlapPolicy::Queue;
+                match result {
+                    Some(Ok(job)) => {
+                        schedule.last_job = Some(job);
+                        schedule.last_error = None;
+                    }
+                    Some(Err(err)) => {
+                        let reason = error_reason(err);
+                        log::warn!("unable to start run of schedule {}: {}", id, reason);
+                        schedule.last_error = Some(reason);
+                    }
+                    None => {}
+                }
+            }
+            changed = true;
+        }
+        if changed {
+            if let Err(err) = self.schedules.save() {
+                log::error!("unable to save schedules: {}", err);
+            }
+        }
+    }
+
+    fn spawn(
+        &self,
+        job: &mut Job,
+        request: JobRequest,
+        kill_switch: oneshot::Receiver<()>,
+        stdin_rx: Option<Receiver<Bytes>>,
+    ) -> Result<(), RuntimeError> {
+        if let Some(shims) = &self.shims {
+            // input of such jobs comes through the daemon, so they can't outlive it anyway
+            if !request.stdin && !request.tty {
+                return self.spawn_with_shim(shims, job, request, kill_switch);
+            }
+        }
+
+        let mut command = Command::new(&request.path);
+        command.args(&request.args);
+        command.envs(&request.env);
+
+        let pty = if request.tty {
+            let pty = Pty::open()?;
+            command
+                .stdin(pty.slave_stdio()?)
+                .stdout(pty.slave_stdio()?)
+                .stderr(pty.slave_stdio()?);
+            unsafe {
+                command.pre_exec(pty::make_controlling_terminal);
+            }
+            Some(pty)
+        } else {
+            let stdin = if request.stdin {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            };
+            command
+                .stdin(stdin)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            None
+        };
+        let mut cmd = command.spawn()?;
+        // the child has its own copies of the slave fds now
+        drop(command);
+
+        let ret = job.uuid;
+
+        if let Some(parent_cgroup) = self.parent_cgroup(&request) {
+            let job_cgroup = Cgroup::new_relative_to(parent_cgroup, &ret.to_string())?;
+            job_cgroup.apply_limits(request.limits)?;
+            job_cgroup.add_task(&cmd)?;
+            job.cgroup = Some(job_cgroup);
+        };
+
+        let (stdout, stderr): (
+            Box<dyn AsyncRead + Unpin + Send>,
+            Box<dyn AsyncRead + Unpin + Send>,
+        ) = match pty {
+            Some(pty) => {
+                let master = pty.into_master()?;
+                job.pty = Some(master.clone());
+                if let Some(stdin_rx) = stdin_rx {
+                    tokio::spawn(Self::write_stdin(job.uuid, master.clone(), stdin_rx));
+                }
+                // terminal merges both streams into one
+                (Box::new(master), Box::new(tokio::io::empty()))
+            }
+            None => {
+                if let (Some(child_stdin), Some(stdin_rx)) = (cmd.stdin.take(), stdin_rx) {
+                    tokio::spawn(Self::write_stdin(job.uuid, child_stdin, stdin_rx));
+                }
+                let stdout = cmd.stdout.take().unwrap_or_else(|| {
+                    panic!("can't get access to stdout fd from child for job: {}", ret)
+                });
+                let stderr = cmd.stderr.take().unwrap_or_else(|| {
+                    panic!("can't get access to stderr fd from child for job: {}", ret)
+                });
+                (Box::new(stdout), Box::new(stderr))
+            }
+        };
+
+        tokio::spawn(Self::handle_job(
+            job.uuid,
+            cmd,
+            stdout,
+            stderr,
+            kill_switch,
+            self.event_tx.clone(),
+        ));
+
+        Ok(())
+    }
+
+    fn spawn_with_shim(
+        &self,
+        shims: &Shims,
+        job: &mut Job,
+        request: JobRequest,
+        kill_switch: oneshot::Receiver<()>,
+    ) -> Result<(), RuntimeError> {
+        // created up front, the shim moves into it before it starts the job
+        let job_cgroup = match self.parent_cgroup(&request) {
+            Some(parent_cgroup) => {
+                let job_cgroup = Cgroup::new_relative_to(parent_cgroup, &job.uuid.to_string())?;
+                job_cgroup.apply_limits(request.limits.clone())?;
+                Some(job_cgroup)
+            }
+            None => None,
+        };
+        let start = shims.spawn(job.uuid, &request, job_cgroup.as_ref())?;
+        job.cgroup = job_cgroup;
+        let (written_tx, written_rx) = watch::channel(None);
+        job.written_tx = Some(written_tx);
+
+        // the shim answers once the job runs, errors of starting it end the job like a failed dispatch
+        let (job, event_tx) = (job.uuid, self.event_tx.clone());
+        tokio::spawn(async move {
+            let started = match start.handshake().await {
+                Ok((pid, dir)) => tokio::task::spawn_blocking(move || ShimJob::open(dir))
+                    .await
+                    .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+                    .map(|shim| (pid, shim)),
+                Err(err) => Err(err),
+            };
+            match started {
+                Ok((pid, shim)) => {
+                    event_tx
+                        .send(RuntimeEvent::JobStart { job, pid })
+                        .await
+                        .expect(RUNTIME_EVENT_ERROR_MSG);
+                    Self::handle_shim_job(job, shim, 0, written_rx, kill_switch, event_tx).await
+                }
+                Err(err) => {
+                    let reason = err.to_string();
+                    event_tx
+                        .send(RuntimeEvent::JobSpawnFailed { job, reason })
+                        .await
+                        .expect(RUNTIME_EVENT_ERROR_MSG);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // cgroups of jobs of a group are nested in the cgroup of the group, which holds the shared limits
+    fn parent_cgroup(&self, request: &JobRequest) -> Option<&Cgroup> {
+        match request
+            .group
+            .as_ref()
+            .and_then(|group| self.groups.get(group))
+        {
+            Some(group) => group.cgroup(),
+            None => self.cgroup.as_ref(),
+        }
+    }
+}
+
+// Io errors say nothing on their own, their source is what the user needs to see
+fn error_reason(err: RuntimeError) -> String {
+    match err {
+        RuntimeError::Io(err) => err.to_string(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn send_logs_resumes_from_sequence_number() {
+        let (mut runtime, _) = JobRuntime::new();
+        let (mut job, _kill_switch) = Job::new(String::from("user"), Labels::new());
+        job.finished(0);
+        let uuid = job.uuid;
+        runtime.jobs.insert(uuid, job);
+        for each in ["a", "b", "c"] {
+            runtime.store_logs(uuid, LogRecord::Stdout(Bytes::from(each)));
+        }
+
+        let (sender, mut rx) = mpsc::channel(LOG_CHANNEL_SIZE);
+        runtime
+            .send_logs(uuid, Principal::new("user", vec![]), 1, sender)
+            .unwrap();
+
+        let mut seqs = vec![];
+        while let Some(message) = rx.recv().await {
+            match message {
+                LogMessage::Entry(entry) => seqs.push(entry.seq),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }
+        assert_eq!(seqs, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn waiters_are_woken_when_job_finishes() {
+        let (mut runtime, _) = JobRuntime::new();
+        let (job, _kill_switch) = Job::new(String::from("user"), Labels::new());
+        let uuid = job.uuid;
+        runtime.jobs.insert(uuid, job);
+
+        let (sender, mut rx) = oneshot::channel();
+        runtime.wait_job(uuid, Principal::new("user", vec![]), sender);
+        assert!(rx.try_recv().is_err());
+
+        runtime.get_job(uuid).unwrap().finished(3);
+        let response = rx.await.unwrap().unwrap();
+        assert!(matches!(
+            response.status,
+            JobStatus::Finished { exit_code: 3 }
+        ));
+
+        // waiting for a job that is already done returns right away
+        let (sender, rx) = oneshot::channel();
+        runtime.wait_job(uuid, Principal::new("user", vec![]), sender);
+        assert!(rx.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn reattached_job_keeps_its_pool_slot() {
+        let state = std::env::temp_dir().join(format!("jobruntime-pool-{}", Uuid::new_v4()));
+        let journal = state.join("journal");
+        let scheduler = || SchedulerConfig {
+            pools: HashMap::from([(String::from("gpu"), 1)]),
+            ..Default::default()
+        };
+        let pooled = || JobRequest {
+            path: String::from("true"),
+            pool: Some(String::from("gpu")),
+            ..Default::default()
+        };
+        let user = Principal::new("user", vec![]);
+        std::fs::create_dir_all(&state).unwrap();
+
+        let job = {
+            let (runtime, _) = JobRuntime::new();
+            let mut runtime = runtime
+                .with_scheduler(scheduler())
+                .with_journal(journal.clone())
+                .unwrap();
+            let job = runtime.start_job(user.clone(), pooled()).unwrap();
+            // as the event loop does once the process is spawned
+            runtime.get_job(job).unwrap().started(42);
+            runtime.publish(job, JobEventKind::Started { pid: 42 });
+            job
+        };
+        // the job went on running under a shim while the daemon was away
+        let shims = Shims::new(PathBuf::from("jobshim"), state.join("shims")).unwrap();
+        let shim_dir = state.join("shims").join(job.to_string());
+        std::fs::create_dir(&shim_dir).unwrap();
+        std::fs::write(shim_dir.join("shim.pid"), std::process::id().to_string()).unwrap();
+
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime
+            .with_scheduler(scheduler())
+            .with_shims(shims)
+            .with_journal(journal)
+            .unwrap();
+        assert!(matches!(
+            runtime.jobs[&job].status,
+            JobStatus::Running { .. }
+        ));
+        let next = runtime.start_job(user, pooled()).unwrap();
+        assert!(matches!(runtime.jobs[&next].status, JobStatus::Pending));
+        std::fs::remove_dir_all(state).unwrap();
+    }
+
+    // Stores the records the shims send, the way the event loop does
+    async fn store_shim_output(runtime: &mut JobRuntime, count: usize) {
+        let mut stored = 0;
+        while stored < count {
+            if let Some(RuntimeEvent::LogCreated { job, record }) = runtime.event_rx.recv().await {
+                runtime.store_logs(job, record);
+                stored += 1;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn shim_output_is_read_again_when_the_daemon_dies_before_writing_it() {
+        let state = std::env::temp_dir().join(format!("jobruntime-shim-logs-{}", Uuid::new_v4()));
+        let job = Uuid::new_v4();
+        std::fs::create_dir_all(&state).unwrap();
+        {
+            let (journal, _) = Journal::open(state.join("journal")).unwrap();
+            let created = JournalEntry::Created {
+                job,
+                owner: String::from("user"),
+                command: vec![String::from("true")],
+                labels: Labels::new(),
+                limits: ResourceLimits::default(),
+                pool: None,
+                logs: None,
+            };
+            journal.record(&created).unwrap();
+            journal
+                .record(&JournalEntry::Started { job, pid: 42 })
+                .unwrap();
+        }
+        // the shim has moved on to a second segment of the output
+        let shim_dir = state.join("shims").join(job.to_string());
+        std::fs::create_dir_all(&shim_dir).unwrap();
+        std::fs::write(shim_dir.join("shim.pid"), std::process::id().to_string()).unwrap();
+        for (segment, records) in [(0, vec![(0, "a"), (1, "b")]), (1, vec![(2, "c")])] {
+            let encoded: Vec<u8> = records
+                .into_iter()
+                .flat_map(|(seq, data)| {
+                    logstore::encode_entry(&LogEntry {
+                        seq,
+                        record: LogRecord::Stdout(Bytes::from(data)),
+                    })
+                })
+                .collect();
+            std::fs::write(shim::segment_path(&shim_dir, segment), encoded).unwrap();
+        }
+        let daemon = || {
+            let logs = logstore::FileLogStore::new(state.join("logs"), Default::default());
+            let shims = Shims::new(PathBuf::from("jobshim"), state.join("shims")).unwrap();
+            let (runtime, _) = JobRuntime::new();
+            runtime
+                .with_log_store(logs.unwrap())
+                .with_shims(shims)
+                .with_journal(state.join("journal"))
+                .unwrap()
+        };
+
+        // the daemon dies with the records still in its buffers
+        let mut runtime = daemon();
+        store_shim_output(&mut runtime, 3).await;
+        assert!(shim::segment_path(&shim_dir, 0).exists());
+        std::mem::forget(runtime);
+
+        let mut runtime = daemon();
+        store_shim_output(&mut runtime, 3).await;
+        let entries: Vec<LogEntry> = runtime
+            .log_store
+            .read(job, 0)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        let output: Vec<(u64, &[u8])> = entries
+            .iter()
+            .map(|entry| match &entry.record {
+                LogRecord::Stdout(data) | LogRecord::Stderr(data) => (entry.seq, data.as_ref()),
+            })
+            .collect();
+        assert_eq!(output, vec![(0, &b"a"[..]), (1, &b"b"[..]), (2, &b"c"[..])]);
+        std::fs::remove_dir_all(state).unwrap();
+    }
+
+    #[test]
+    fn watch_filter_matches_jobs_owner_and_labels() {
+        let (job, _kill_switch) = Job::new(
+            String::from("user"),
+            Labels::from([(String::from("team"), String::from("db"))]),
+        );
+        let event = job.event(JobEventKind::Created);
+
+        assert!(WatchFilter::default().matches(&event));
+        assert!(WatchFilter {
+            jobs: vec![job.uuid],
+            owner: Some(String::from("user")),
+            labels: Labels::from([(String::from("team"), String::from("db"))]),
+        }
+        .matches(&event));
+        assert!(!WatchFilter {
+            jobs: vec![Uuid::new_v4()],
+            ..Default::default()
+        }
+        .matches(&event));
+        assert!(!WatchFilter {
+            labels: Labels::from([(String::from("team"), String::from("web"))]),
+            ..Default::default()
+        }
+        .matches(&event));
+    }
+
+    async fn collect_forwarded(live_size: usize, policy: SlowConsumerPolicy) -> Vec<LogMessage> {
+        let (log_tx, live) = broadcast::channel(live_size);
+        for seq in 0..4 {
+            let record = LogRecord::Stdout(Bytes::from("a"));
+            log_tx.send(LogEntry { seq, record }).unwrap();
+        }
+        drop(log_tx);
+
+        let (sender, mut rx) = mpsc::channel(LOG_CHANNEL_SIZE);
+        let history: LogEntries = Box::new(std::iter::empty());
+        JobRuntime::forward_logs(0, history, 0, Some(live), sender, policy).await;
+        std::iter::from_fn(|| rx.try_recv().ok()).collect()
+    }
+
+    #[tokio::test]
+    async fn idle_watcher_is_dropped_when_the_client_goes_away() {
+        let (lifecycle_tx, events) = broadcast::channel(WATCH_CHANNEL_SIZE);
+        let (sender, rx) = mpsc::channel(WATCH_CHANNEL_SIZE);
+        let forward = tokio::spawn(JobRuntime::forward_events(events, sender, |_| true));
+        drop(rx);
+        tokio::time::timeout(Duration::from_secs(1), forward)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(lifecycle_tx.receiver_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn slow_follower_is_lagged_or_disconnected() {
+        let messages = collect_forwarded(2, SlowConsumerPolicy::Lag).await;
+        assert!(matches!(messages[0], LogMessage::Lagged { missed: 2 }));
+        assert_eq!(messages.len(), 3);
+
+        let messages = collect_forwarded(2, SlowConsumerPolicy::Disconnect).await;
+        assert!(matches!(
+            messages[..],
+            [LogMessage::Disconnected { missed: 2 }]
+        ));
+    }
+
+    #[test]
+    fn admission_counts_unfinished_jobs_of_the_owner() {
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime.with_quotas(QuotaPolicy {
+            default: Some(quota::Quota {
+                max_jobs: Some(1),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let user = Principal::new("user", vec![]);
+        let (job, _kill_switch) = Job::new(String::from("user"), Labels::new());
+        let uuid = job.uuid;
+        runtime.jobs.insert(uuid, job);
+
+        assert!(matches!(
+            runtime.admit(&user, &ResourceLimits::default()),
+            Err(RuntimeError::QuotaExceeded(_))
+        ));
+        runtime.jobs.get_mut(&uuid).unwrap().finished(0);
+        assert!(runtime.admit(&user, &ResourceLimits::default()).is_ok());
+
+        let usage = runtime.quota_usage(user, None).unwrap();
+        assert_eq!(usage.usage.jobs, 0);
+        assert_eq!(usage.usage.starts_last_minute, 1);
+    }
+
+    #[test]
+    fn stopped_step_cancels_the_steps_that_need_it() {
+        let (runtime, _) = JobRuntime::new();
+        // without free slots nothing gets spawned
+        let mut runtime = runtime.with_scheduler(SchedulerConfig {
+            max_running: Some(0),
+            ..Default::default()
+        });
+        let step = |name: &str, after: &[(&str, DependencyCondition)]| WorkflowStep {
+            name: String::from(name),
+            request: JobRequest {
+                path: String::from("true"),
+                ..Default::default()
+            },
+            after: after
+                .iter()
+                .map(|(step, condition)| workflow::StepDependency {
+                    step: String::from(*step),
+                    condition: *condition,
+                })
+                .collect(),
+        };
+        let steps = vec![
+            step("deploy", &[("test", DependencyCondition::Success)]),
+            step("report", &[("test", DependencyCondition::Finished)]),
+            step("test", &[("build", DependencyCondition::Success)]),
+            step("build", &[]),
+        ];
+        let user = Principal::new("user", vec![]);
+        let workflow = runtime.start_workflow(user.clone(), steps).unwrap();
+        let jobs: HashMap<String, JobId> = workflow.jobs.into_iter().collect();
+
+        assert_eq!(runtime.scheduler.position(jobs["build"]), Some(1));
+        assert_eq!(runtime.jobs[&jobs["test"]].dependencies.len(), 1);
+
+        runtime.stop_job(jobs["build"], user).unwrap();
+        for name in ["test", "deploy"] {
+            assert!(matches!(
+                runtime.jobs[&jobs[name]].status,
+                JobStatus::Cancelled { .. }
+            ));
+        }
+        assert!(runtime.jobs[&jobs["report"]].dependencies.is_empty());
+        assert_eq!(runtime.scheduler.position(jobs["report"]), Some(1));
+    }
+
+    #[test]
+    fn job_cancelled_by_a_failed_dependency_takes_no_slot() {
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime.with_scheduler(SchedulerConfig {
+            max_running: Some(1),
+            ..Default::default()
+        });
+        let (mut failed, _kill_switch) = Job::new(String::from("user"), Labels::new());
+        failed.finished(1);
+        let failed_id = failed.uuid;
+        runtime.jobs.insert(failed_id, failed);
+
+        let user = Principal::new("user", vec![]);
+        let request = JobRequest {
+            path: String::from("true"),
+            dependencies: vec![Dependency {
+                job: failed_id,
+                condition: DependencyCondition::Success,
+            }],
+            ..Default::default()
+        };
+        let job = runtime.start_job(user, request).unwrap();
+
+        assert!(matches!(
+            runtime.jobs[&job].status,
+            JobStatus::Cancelled { .. }
+        ));
+        assert!(runtime.scheduler.try_run(Uuid::new_v4(), "user", None));
+    }
+
+    #[test]
+    fn rejected_workflow_leaves_no_jobs_behind() {
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime.with_scheduler(SchedulerConfig {
+            max_running: Some(0),
+            ..Default::default()
+        });
+        let mut events = runtime.lifecycle_tx.subscribe();
+        let step = |name: &str, pool: Option<&str>| WorkflowStep {
+            name: String::from(name),
+            request: JobRequest {
+                path: String::from("true"),
+                pool: pool.map(String::from),
+                ..Default::default()
+            },
+            after: vec![],
+        };
+        let steps = vec![step("build", None), step("test", Some("missing"))];
+        let user = Principal::new("user", vec![]);
+
+        assert!(matches!(
+            runtime.start_workflow(user.clone(), steps),
+            Err(RuntimeError::UnknownPool(_))
+        ));
+        assert!(runtime.jobs.is_empty());
+        assert!(events.try_recv().is_err());
+        let usage = runtime.quota_usage(user, None).unwrap();
+        assert_eq!(usage.usage.starts_last_minute, 0);
+    }
+
+    #[test]
+    fn rejected_atomic_batch_leaves_no_jobs_behind() {
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime.with_scheduler(SchedulerConfig {
+            max_running: Some(0),
+            ..Default::default()
+        });
+        let request = |pool: Option<&str>| JobRequest {
+            path: String::from("true"),
+            pool: pool.map(String::from),
+            ..Default::default()
+        };
+        let user = Principal::new("user", vec![]);
+        let requests = vec![request(None), request(None), request(Some("missing"))];
+
+        assert!(matches!(
+            runtime.start_batch(user, requests, true),
+            Err(RuntimeError::UnknownPool(_))
+        ));
+        assert!(runtime.jobs.is_empty());
+        assert!(runtime.batches.is_empty());
+    }
+
+    fn group_request(group: &str) -> JobRequest {
+        JobRequest {
+            path: String::from("true"),
+            group: Some(String::from(group)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn stopping_a_group_skips_jobs_that_are_gone() {
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime.with_scheduler(SchedulerConfig {
+            max_running: Some(0),
+            ..Default::default()
+        });
+        let user = Principal::new("user", vec![]);
+        let job = runtime
+            .start_job(user.clone(), group_request("build"))
+            .unwrap();
+        assert_eq!(runtime.groups["build"].jobs, vec![job]);
+        runtime
+            .groups
+            .get_mut("build")
+            .unwrap()
+            .jobs
+            .push(Uuid::new_v4());
+
+        runtime.stop_group("build", user).unwrap();
+        assert!(matches!(
+            runtime.jobs[&job].status,
+            JobStatus::Killed { .. }
+        ));
+    }
+
+    #[test]
+    fn signal_reaches_running_jobs_of_the_group() {
+        let (mut runtime, _) = JobRuntime::new();
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let labels = Labels::from([(String::from(group::GROUP_LABEL), String::from("build"))]);
+        let (mut job, _kill_switch) = Job::new(String::from("user"), labels);
+        job.status = JobStatus::Running {
+            pid: child.id() as i32,
+        };
+        let mut group = Group::new(String::from("build"), String::from("user"));
+        group.jobs = vec![job.uuid, Uuid::new_v4()];
+        runtime.groups.insert(group.name.clone(), group);
+        runtime.jobs.insert(job.uuid, job);
+
+        let user = Principal::new("user", vec![]);
+        assert!(matches!(
+            runtime.signal_group("build", user.clone(), 0),
+            Err(RuntimeError::InvalidSignal(0))
+        ));
+        runtime.signal_group("build", user, libc::SIGKILL).unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+
+    #[test]
+    fn signalling_a_group_needs_the_signal_permission() {
+        let policy = r#"
+            [roles.signaller]
+            permissions = ["start", "signal"]
+            [roles.stopper]
+            permissions = ["start", "stop"]
+        "#;
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime
+            .with_authorizer(toml::from_str::<Rbac>(policy).unwrap())
+            .with_scheduler(SchedulerConfig {
+                max_running: Some(0),
+                ..Default::default()
+            });
+        let signaller = Principal::new("alice", vec![String::from("signaller")]);
+        let stopper = Principal::new("alice", vec![String::from("stopper")]);
+        runtime
+            .start_job(signaller.clone(), group_request("build"))
+            .unwrap();
+
+        runtime
+            .signal_group("build", signaller.clone(), libc::SIGTERM)
+            .unwrap();
+        assert!(matches!(
+            runtime.stop_group("build", signaller),
+            Err(RuntimeError::Unauthorized)
+        ));
+        assert!(matches!(
+            runtime.signal_group("build", stopper.clone(), libc::SIGTERM),
+            Err(RuntimeError::Unauthorized)
+        ));
+        runtime.stop_group("build", stopper).unwrap();
+    }
+
+    #[test]
+    fn groups_of_other_users_need_the_any_scope() {
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime.with_scheduler(SchedulerConfig {
+            max_running: Some(0),
+            ..Default::default()
+        });
+        let alice = Principal::new("alice", vec![]);
+        let bob = Principal::new("bob", vec![]);
+        let admin = Principal::new("admin", vec![String::from(auth::ADMIN_ROLE)]);
+        let job = runtime.start_job(alice, group_request("build")).unwrap();
+
+        assert!(matches!(
+            runtime.start_job(bob.clone(), group_request("build")),
+            Err(RuntimeError::Unauthorized)
+        ));
+        assert!(matches!(
+            runtime.signal_group("build", bob.clone(), libc::SIGTERM),
+            Err(RuntimeError::Unauthorized)
+        ));
+        assert!(matches!(
+            runtime.stop_group("build", bob),
+            Err(RuntimeError::Unauthorized)
+        ));
+        assert!(matches!(runtime.jobs[&job].status, JobStatus::Pending));
+
+        runtime.stop_group("build", admin).unwrap();
+        assert!(matches!(
+            runtime.jobs[&job].status,
+            JobStatus::Killed { .. }
+        ));
+    }
+
+    #[test]
+    fn priority_outside_of_the_user_range_needs_a_permission() {
+        let (runtime, _) = JobRuntime::new();
+        let mut runtime = runtime.with_scheduler(SchedulerConfig {
+            max_running: Some(0),
+            ..Default::default()
+        });
+        let request = |priority| JobRequest {
+            path: String::from("true"),
+            priority,
+            ..Default::default()
+        };
+        let user = Principal::new("user", vec![]);
+        let admin = Principal::new("admin", vec![String::from(auth::ADMIN_ROLE)]);
+        let first = runtime.start_job(user, request(1000)).unwrap();
+        let second = runtime.start_job(admin, request(11)).unwrap();
+
+        assert_eq!(runtime.scheduler.position(second), Some(1));
+        assert_eq!(runtime.scheduler.position(first), Some(2));
+    }
+
+    #[test]
+    fn only_ended_jobs_are_deleted_with_their_logs() {
+        let (mut runtime, _) = JobRuntime::new();
+        let user = Principal::new("user", vec![]);
+        let mut jobs = vec![];
+        for exit_code in [None, Some(0), Some(1)] {
+            let (mut job, _kill_switch) = Job::new(String::from("user"), Labels::new());
+            if let Some(exit_code) = exit_code {
+                job.finished(exit_code);
+            }
+            jobs.push(job.uuid);
+            runtime.jobs.insert(job.uuid, job);
+            runtime.store_logs(jobs[jobs.len() - 1], LogRecord::Stdout(Bytes::from("a")));
+        }
+
+        assert!(matches!(
+            runtime.delete_job(jobs[0], user.clone()),
+            Err(RuntimeError::JobNotEnded)
+        ));
+        runtime.delete_job(jobs[1], user.clone()).unwrap();
+        assert!(!runtime.jobs.contains_key(&jobs[1]));
+        assert!(runtime.log_store.read(jobs[1], 0).unwrap().next().is_none());
+
+        let pruned = runtime
+            .prune_jobs(user, Some(Duration::from_secs(60)), false)
+            .unwrap();
+        assert!(pruned.is_empty());
+        let pruned = runtime
+            .prune_jobs(Principal::new("other", vec![]), None, false)
+            .unwrap();
+        assert!(pruned.is_empty());
+        assert_eq!(runtime.jobs.len(), 2);
+    }
 }
@@ -1,3 +1,4 @@
+use serde::{Deserialize, Serialize};
 use std::fs;
 use std::io;
 use std::path::PathBuf;
@@ -5,7 +6,8 @@ use tokio::process::Child;
 
 pub const DEFAULT_CGROUP_ROOT: &str = "/sys/fs/cgroup";
 
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ResourceLimits {
     pub memory_high: Option<u64>,
     pub memory_max: Option<u64>,
@@ -41,7 +43,10 @@ impl Cgroup {
     pub fn new_relative_to(cgroup: &Self, name: &str) -> io::Result<Self> {
         let mut root = cgroup.root.clone();
         root.push(name);
-        fs::create_dir(root.as_path())?;
+        // group cgroups outlive restarts of the daemon
+        if !root.as_path().exists() {
+            fs::create_dir(root.as_path())?;
+        }
         Ok(Self { root })
     }
 
@@ -60,10 +65,19 @@ impl Cgroup {
                 return Err(io::Error::new(io::ErrorKind::PermissionDenied, ""));
             }
         };
+        self.add_pid(pid)
+    }
+
+    pub fn add_pid(&self, pid: u32) -> io::Result<()> {
         fs::write(self.root.as_path().join("cgroup.procs"), pid.to_string())?;
         Ok(())
     }
 
+    // Only works once all processes of the cgroup are gone
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_dir(self.root)
+    }
+
     pub fn apply_limits(&self, limits: ResourceLimits) -> io::Result<()> {
         if limits.memory_high.is_some() || limits.memory_max.is_some() {
             if let Some(memory_high) = limits.memory_high {
@@ -94,6 +108,17 @@ impl Cgroup {
         Ok(())
     }
 
+    // Number of processes in the cgroup killed by the OOM killer
+    pub fn oom_kill_count(&self) -> io::Result<u64> {
+        let events = fs::read_to_string(self.root.as_path().join("memory.events"))?;
+        let count = events
+            .lines()
+            .filter_map(|line| line.strip_prefix("oom_kill "))
+            .find_map(|value| value.trim().parse().ok())
+            .unwrap_or(0);
+        Ok(count)
+    }
+
     fn apply_limit(&self, limit: &str, value: &str) -> io::Result<()> {
         fs::write(self.root.as_path().join(limit), value)?;
         Ok(())
@@ -0,0 +1,740 @@
+use crate::{JobId, LogEntry, LogRecord};
+use bytes::{Bytes, BytesMut};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+const SEGMENT_EXTENSION: &str = "log";
+const COMPRESSED_SEGMENT_EXTENSION: &str = "gz";
+// seq (u64) + stream (u8) + data length (u32)
+const RECORD_HEADER_SIZE: usize = 8 + 1 + 4;
+const STDOUT_TAG: u8 = 1;
+const STDERR_TAG: u8 = 2;
+pub const DEFAULT_MAX_JOB_BYTES: u64 = 16 * 1024 * 1024;
+
+// Records of a job read lazily, so the history doesn't have to fit in memory
+pub type LogEntries = Box<dyn Iterator<Item = io::Result<LogEntry>> + Send>;
+
+// Storage for the output of the jobs.
+// The runtime appends every record produced by a job and reads them back
+// whenever a client asks for the job logs.
+pub trait LogStore: Send {
+    fn append(&mut self, job: JobId, entry: &LogEntry) -> io::Result<()>;
+
+    // Returns all retained records of the job with a sequence number >= from_seq.
+    // Records that were already evicted are silently skipped.
+    // The records are read when the iterator is consumed, which can block, so not in the event loop.
+    fn read(&mut self, job: JobId, from_seq: u64) -> io::Result<LogEntries>;
+
+    // Sequence number of the last record kept for the job, found without reading all of them
+    fn last_seq(&mut self, job: JobId) -> io::Result<Option<u64>>;
+
+    fn remove(&mut self, job: JobId) -> io::Result<()>;
+
+    // Called when the job has ended, nothing is appended to its logs anymore
+    fn close(&mut self, _job: JobId) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Where the records of the job are kept, None when they don't outlive the daemon
+    fn location(&self, _job: JobId) -> Option<PathBuf> {
+        None
+    }
+
+    // Records of the running job with a lower sequence number are written out and survive
+    // the daemon dying, None when nothing does
+    fn written_until(&self, _job: JobId) -> Option<u64> {
+        None
+    }
+
+    // Called periodically by the runtime to drop data that is past its retention period
+    // and to write out buffered records.
+    fn prune(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn record_data(record: &LogRecord) -> &Bytes {
+    match record {
+        LogRecord::Stdout(data) | LogRecord::Stderr(data) => data,
+    }
+}
+
+#[derive(Default)]
+struct RingBuffer {
+    entries: VecDeque<LogEntry>,
+    size: usize,
+}
+
+// Keeps the most recent logs of every job in memory.
+// When a job exceeds its byte cap, the oldest records are evicted.
+pub struct MemoryLogStore {
+    max_job_bytes: usize,
+    logs: HashMap<JobId, RingBuffer>,
+}
+
+impl MemoryLogStore {
+    pub fn new(max_job_bytes: usize) -> Self {
+        Self {
+            max_job_bytes,
+            logs: HashMap::new(),
+        }
+    }
+}
+
+impl LogStore for MemoryLogStore {
+    fn append(&mut self, job: JobId, entry: &LogEntry) -> io::Result<()> {
+        let buffer = self.logs.entry(job).or_default();
+        buffer.size += record_data(&entry.record).len();
+        buffer.entries.push_back(entry.clone());
+
+        while buffer.size > self.max_job_bytes {
+            match buffer.entries.pop_front() {
+                Some(evicted) => buffer.size -= record_data(&evicted.record).len(),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, job: JobId, from_seq: u64) -> io::Result<LogEntries> {
+        // records share their data, so the copy is cheap
+        let entries: Vec<LogEntry> = self
+            .logs
+            .get(&job)
+            .map(|buffer| {
+                buffer
+                    .entries
+                    .iter()
+                    .filter(|entry| entry.seq >= from_seq)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(Box::new(entries.into_iter().map(Ok)))
+    }
+
+    fn last_seq(&mut self, job: JobId) -> io::Result<Option<u64>> {
+        Ok(self
+            .logs
+            .get(&job)
+            .and_then(|buffer| buffer.entries.back())
+            .map(|entry| entry.seq))
+    }
+
+    fn remove(&mut self, job: JobId) -> io::Result<()> {
+        self.logs.remove(&job);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileLogStoreConfig {
+    // size after which the current segment is closed and a new one is started
+    pub segment_bytes: u64,
+    // maximum size of all segments of a single job, the oldest segments are removed first
+    pub max_job_bytes: u64,
+    // compress closed segments with gzip
+    pub compress: bool,
+    // segments that weren't modified for that long are removed
+    pub retention: Option<Duration>,
+}
+
+impl Default for FileLogStoreConfig {
+    fn default() -> Self {
+        Self {
+            segment_bytes: 8 * 1024 * 1024,
+            max_job_bytes: DEFAULT_MAX_JOB_BYTES,
+            compress: false,
+            retention: None,
+        }
+    }
+}
+
+struct ActiveSegment {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    // sequence number after the last record appended
+    next_seq: u64,
+}
+
+impl ActiveSegment {
+    // Returns the sequence number records are written out until
+    fn flush(&mut self) -> io::Result<u64> {
+        self.file.flush()?;
+        Ok(self.next_seq)
+    }
+}
+
+struct Segment {
+    first_seq: u64,
+    path: PathBuf,
+    compressed: bool,
+}
+
+// Stores logs on disk, each job in its own directory split into segments:
+//
+//   <root>/<job>/<first seq in segment>.log[.gz]
+//
+// Every record is written as a small header (seq, stream, length) followed by the data.
+// Closed segments are compressed by a thread of their own, so appending never waits for gzip.
+// The uncompressed segment stays until its compressed copy is complete.
+pub struct FileLogStore {
+    root: PathBuf,
+    config: FileLogStoreConfig,
+    active: HashMap<JobId, ActiveSegment>,
+    // sequence number the records of running jobs are written out until
+    written: HashMap<JobId, u64>,
+    segments_to_compress: Option<mpsc::Sender<PathBuf>>,
+    compressor: Option<JoinHandle<()>>,
+}
+
+impl FileLogStore {
+    pub fn new<P: AsRef<Path>>(root: P, config: FileLogStoreConfig) -> io::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        let (segments_to_compress, compressor) = if config.compress {
+            let (tx, rx) = mpsc::channel();
+            let compressor = thread::Builder::new()
+                .name(String::from("log-compressor"))
+                .spawn(move || compress_segments(rx))?;
+            (Some(tx), Some(compressor))
+        } else {
+            (None, None)
+        };
+        Ok(Self {
+            root,
+            config,
+            active: HashMap::new(),
+            written: HashMap::new(),
+            segments_to_compress,
+            compressor,
+        })
+    }
+
+    fn job_dir(&self, job: JobId) -> PathBuf {
+        self.root.join(job.to_string())
+    }
+
+    // Returns segments of the job ordered by their first sequence number
+    fn segments(&self, job: JobId) -> io::Result<Vec<Segment>> {
+        let dir = self.job_dir(job);
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(name) => name,
+                None => continue,
+            };
+            let (stem, compressed) = match strip_extension(name, COMPRESSED_SEGMENT_EXTENSION) {
+                Some(stem) => (stem, true),
+                None => (name, false),
+            };
+            let first_seq = match strip_extension(stem, SEGMENT_EXTENSION)
+                .and_then(|seq| seq.parse::<u64>().ok())
+            {
+                Some(seq) => seq,
+                None => continue,
+            };
+            segments.push(Segment {
+                first_seq,
+                path,
+                compressed,
+            });
+        }
+        // a segment that is being compressed is read from the uncompressed file
+        segments.sort_by_key(|segment| (segment.first_seq, segment.compressed));
+        segments.dedup_by_key(|segment| segment.first_seq);
+        Ok(segments)
+    }
+
+    fn open_segment(&mut self, job: JobId, first_seq: u64) -> io::Result<&mut ActiveSegment> {
+        let dir = self.job_dir(job);
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(format!("{:020}.{}", first_seq, SEGMENT_EXTENSION));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let mut size = file.metadata()?.len();
+        // a record cut short by a crash would swallow the start of the next one
+        if size > 0 {
+            size = complete_length(&path)?;
+            file.set_len(size)?;
+        }
+        let file = BufWriter::new(file);
+        self.active.insert(
+            job,
+            ActiveSegment {
+                path,
+                file,
+                size,
+                next_seq: first_seq,
+            },
+        );
+        Ok(self
+            .active
+            .get_mut(&job)
+            .expect("segment was just inserted"))
+    }
+
+    fn rotate(&mut self, job: JobId) -> io::Result<()> {
+        if let Some(mut segment) = self.active.remove(&job) {
+            self.written.insert(job, segment.flush()?);
+            if let Some(segments_to_compress) = &self.segments_to_compress {
+                if segments_to_compress.send(segment.path).is_err() {
+                    log::error!("log compressor has stopped, segments are kept uncompressed");
+                }
+            }
+        }
+
+        // enforce the per job size limit by removing the oldest closed segments
+        let segments = self.segments(job)?;
+        let mut sizes = Vec::with_capacity(segments.len());
+        for segment in &segments {
+            // the segment may have just been replaced by its compressed copy
+            sizes.push(ignore_not_found(fs::metadata(&segment.path))?.map_or(0, |meta| meta.len()));
+        }
+        let mut total: u64 = sizes.iter().sum();
+        for (segment, size) in segments.iter().zip(sizes) {
+            if total <= self.config.max_job_bytes {
+                break;
+            }
+            remove_segment(segment)?;
+            total -= size;
+        }
+        Ok(())
+    }
+}
+
+impl LogStore for FileLogStore {
+    fn append(&mut self, job: JobId, entry: &LogEntry) -> io::Result<()> {
+        let encoded = encode_entry(entry);
+
+        let needs_rotation = match self.active.get(&job) {
+            Some(segment) => segment.size + encoded.len() as u64 > self.config.segment_bytes,
+            None => false,
+        };
+        if needs_rotation {
+            self.rotate(job)?;
+        }
+
+        let segment = match self.active.get_mut(&job) {
+            Some(segment) => segment,
+            None => self.open_segment(job, entry.seq)?,
+        };
+        segment.file.write_all(&encoded)?;
+        segment.size += encoded.len() as u64;
+        segment.next_seq = entry.seq + 1;
+        Ok(())
+    }
+
+    fn read(&mut self, job: JobId, from_seq: u64) -> io::Result<LogEntries> {
+        if let Some(segment) = self.active.get_mut(&job) {
+            self.written.insert(job, segment.flush()?);
+        }
+        let segments = self.segments(job)?;
+        // skip segments that end before the requested sequence number
+        let first = segments
+            .iter()
+            .rposition(|segment| segment.first_seq <= from_seq)
+            .unwrap_or(0);
+        let reader = SegmentReader {
+            segments: segments.into_iter().skip(first).collect(),
+            current: None,
+        };
+        Ok(Box::new(reader.filter(move |entry| match entry {
+            Ok(entry) => entry.seq >= from_seq,
+            Err(_) => true,
+        })))
+    }
+
+    // Only the newest segment is read, unless a crash left it without a complete record
+    fn last_seq(&mut self, job: JobId) -> io::Result<Option<u64>> {
+        if let Some(segment) = self.active.get_mut(&job) {
+            self.written.insert(job, segment.flush()?);
+        }
+        for segment in self.segments(job)?.into_iter().rev() {
+            let reader = SegmentReader {
+                segments: VecDeque::from([segment]),
+                current: None,
+            };
+            let mut last = None;
+            for entry in reader {
+                last = Some(entry?.seq);
+            }
+            if last.is_some() {
+                return Ok(last);
+            }
+        }
+        Ok(None)
+    }
+
+    fn close(&mut self, job: JobId) -> io::Result<()> {
+        self.written.remove(&job);
+        match self.active.remove(&job) {
+            Some(mut segment) => segment.flush().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn location(&self, job: JobId) -> Option<PathBuf> {
+        Some(self.job_dir(job))
+    }
+
+    fn written_until(&self, job: JobId) -> Option<u64> {
+        self.written.get(&job).copied()
+    }
+
+    fn remove(&mut self, job: JobId) -> io::Result<()> {
+        self.active.remove(&job);
+        self.written.remove(&job);
+        let dir = self.job_dir(job);
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    fn prune(&mut self) -> io::Result<()> {
+        for (job, segment) in self.active.iter_mut() {
+            self.written.insert(*job, segment.flush()?);
+        }
+        let retention = match self.config.retention {
+            Some(retention) => retention,
+            None => return Ok(()),
+        };
+        let now = SystemTime::now();
+
+        for dir in fs::read_dir(&self.root)? {
+            let dir = dir?;
+            let job = match dir.file_name().to_str().and_then(|name| name.parse().ok()) {
+                Some(job) => job,
+                None => continue,
+            };
+
+            for segment in self.segments(job)? {
+                let modified = match ignore_not_found(fs::metadata(&segment.path))? {
+                    Some(metadata) => metadata.modified()?,
+                    None => continue,
+                };
+                let expired = now
+                    .duration_since(modified)
+                    .map(|age| age > retention)
+                    .unwrap_or(false);
+                if !expired {
+                    continue;
+                }
+
+                if let Some(active) = self.active.get(&job) {
+                    if active.path == segment.path {
+                        self.active.remove(&job);
+                    }
+                }
+                remove_segment(&segment)?;
+            }
+
+            if fs::read_dir(dir.path())?.next().is_none() {
+                fs::remove_dir(dir.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn strip_extension<'a>(name: &'a str, extension: &str) -> Option<&'a str> {
+    name.strip_suffix(extension)?.strip_suffix('.')
+}
+
+// Segments compressed before the store is dropped are complete
+impl Drop for FileLogStore {
+    fn drop(&mut self) {
+        self.segments_to_compress.take();
+        if let Some(compressor) = self.compressor.take() {
+            let _ = compressor.join();
+        }
+    }
+}
+
+fn ignore_not_found<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn compressed_path(path: &Path) -> PathBuf {
+    let mut compressed_path = path.as_os_str().to_owned();
+    compressed_path.push(".");
+    compressed_path.push(COMPRESSED_SEGMENT_EXTENSION);
+    PathBuf::from(compressed_path)
+}
+
+// Removes the segment, together with the compressed copy that may be taking its place
+fn remove_segment(segment: &Segment) -> io::Result<()> {
+    ignore_not_found(fs::remove_file(&segment.path))?;
+    if !segment.compressed {
+        ignore_not_found(fs::remove_file(compressed_path(&segment.path)))?;
+    }
+    Ok(())
+}
+
+fn compress_segments(paths: mpsc::Receiver<PathBuf>) {
+    while let Ok(path) = paths.recv() {
+        if let Err(err) = compress_segment(&path) {
+            log::error!("unable to compress log segment {}: {}", path.display(), err);
+        }
+    }
+}
+
+// The copy gets its final name only once it's complete, readers use the uncompressed segment until then
+fn compress_segment(path: &Path) -> io::Result<()> {
+    let compressed_path = compressed_path(path);
+    let mut partial_path = compressed_path.clone().into_os_string();
+    partial_path.push(".tmp");
+
+    let mut input = match ignore_not_found(File::open(path))? {
+        Some(input) => input,
+        // removed by the size limit or with its job in the meantime
+        None => return Ok(()),
+    };
+    let mut encoder = GzEncoder::new(File::create(&partial_path)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&partial_path, &compressed_path)?;
+    if ignore_not_found(fs::remove_file(path))?.is_none() {
+        // the segment was removed while it was compressed, so is its copy
+        ignore_not_found(fs::remove_file(&compressed_path))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn encode_entry(entry: &LogEntry) -> Vec<u8> {
+    let (tag, data) = match &entry.record {
+        LogRecord::Stdout(data) => (STDOUT_TAG, data),
+        LogRecord::Stderr(data) => (STDERR_TAG, data),
+    };
+    let mut buf = Vec::with_capacity(RECORD_HEADER_SIZE + data.len());
+    buf.extend_from_slice(&entry.seq.to_le_bytes());
+    buf.push(tag);
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+    buf
+}
+
+// Takes the first complete record off the buffer, None when the rest of it wasn't written yet
+pub(crate) fn split_entry(buf: &mut BytesMut) -> io::Result<Option<LogEntry>> {
+    if buf.len() < RECORD_HEADER_SIZE {
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(buf[9..13].try_into().unwrap()) as usize;
+    if buf.len() < RECORD_HEADER_SIZE + len {
+        return Ok(None);
+    }
+    let header = buf.split_to(RECORD_HEADER_SIZE);
+    let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let data = buf.split_to(len).freeze();
+    let record = match header[8] {
+        STDOUT_TAG => LogRecord::Stdout(data),
+        STDERR_TAG => LogRecord::Stderr(data),
+        tag => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown log stream tag: {}", tag),
+            ))
+        }
+    };
+    Ok(Some(LogEntry { seq, record }))
+}
+
+// Reads the segments one after another, opening each only when the previous one is used up
+struct SegmentReader {
+    segments: VecDeque<Segment>,
+    current: Option<Box<dyn Read + Send>>,
+}
+
+impl Iterator for SegmentReader {
+    type Item = io::Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(reader) = &mut self.current {
+                match decode_entry(reader) {
+                    Ok(Some(entry)) => return Some(Ok(entry)),
+                    Ok(None) => self.current = None,
+                    Err(err) => {
+                        self.current = None;
+                        return Some(Err(err));
+                    }
+                }
+            }
+
+            let mut segment = self.segments.pop_front()?;
+            let mut file = File::open(&segment.path);
+            // compressed in the meantime
+            if !segment.compressed
+                && matches!(&file, Err(err) if err.kind() == io::ErrorKind::NotFound)
+            {
+                segment.compressed = true;
+                file = File::open(compressed_path(&segment.path));
+            }
+            let file = match file {
+                Ok(file) => BufReader::new(file),
+                // removed by rotation or retention in the meantime
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Some(Err(err)),
+            };
+            self.current = Some(if segment.compressed {
+                Box::new(GzDecoder::new(file))
+            } else {
+                Box::new(file)
+            });
+        }
+    }
+}
+
+// Length of the complete records at the start of an uncompressed segment
+fn complete_length(path: &Path) -> io::Result<u64> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut length = 0;
+    while let Some(entry) = decode_entry(&mut reader)? {
+        length += (RECORD_HEADER_SIZE + record_data(&entry.record).len()) as u64;
+    }
+    Ok(length)
+}
+
+// Reads the next record, None at the end of the segment
+fn decode_entry<R: Read>(reader: &mut R) -> io::Result<Option<LogEntry>> {
+    let mut header = [0u8; RECORD_HEADER_SIZE];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let seq = u64::from_le_bytes(header[0..8].try_into().unwrap());
+    let len = u32::from_le_bytes(header[9..13].try_into().unwrap()) as usize;
+    let mut data = vec![0u8; len];
+    match reader.read_exact(&mut data) {
+        Ok(()) => {}
+        // the daemon died in the middle of writing the record
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let record = match header[8] {
+        STDOUT_TAG => LogRecord::Stdout(Bytes::from(data)),
+        STDERR_TAG => LogRecord::Stderr(Bytes::from(data)),
+        tag => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown log stream tag: {}", tag),
+            ))
+        }
+    };
+    Ok(Some(LogEntry { seq, record }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    fn entry(seq: u64, data: &'static str) -> LogEntry {
+        LogEntry {
+            seq,
+            record: LogRecord::Stdout(Bytes::from(data)),
+        }
+    }
+
+    fn seqs(entries: LogEntries) -> Vec<u64> {
+        entries.map(|entry| entry.unwrap().seq).collect()
+    }
+
+    #[test]
+    fn memory_store_evicts_oldest_records_over_the_cap() {
+        let job = Uuid::new_v4();
+        let mut store = MemoryLogStore::new(8);
+        for seq in 0..4 {
+            store.append(job, &entry(seq, "abcd")).unwrap();
+        }
+        assert_eq!(seqs(store.read(job, 0).unwrap()), vec![2, 3]);
+        assert_eq!(seqs(store.read(job, 3).unwrap()), vec![3]);
+        assert_eq!(store.last_seq(job).unwrap(), Some(3));
+        assert_eq!(store.last_seq(Uuid::new_v4()).unwrap(), None);
+    }
+
+    #[test]
+    fn file_store_rotates_compresses_and_limits_segments() {
+        let root = std::env::temp_dir().join(format!("jobruntime-logs-{}", Uuid::new_v4()));
+        let config = FileLogStoreConfig {
+            segment_bytes: 2 * (RECORD_HEADER_SIZE as u64 + 4),
+            max_job_bytes: 1024,
+            compress: true,
+            retention: None,
+        };
+        let job = Uuid::new_v4();
+        let mut store = FileLogStore::new(&root, config.clone()).unwrap();
+        for seq in 0..5 {
+            store.append(job, &entry(seq, "abcd")).unwrap();
+        }
+        // records are written out with every segment that is closed and when they are read
+        assert_eq!(store.written_until(job), Some(4));
+        // whether or not the compressor is done yet
+        assert_eq!(seqs(store.read(job, 0).unwrap()), vec![0, 1, 2, 3, 4]);
+        assert_eq!(store.written_until(job), Some(5));
+
+        // dropping the store waits for the compressor
+        drop(store);
+        let mut store = FileLogStore::new(&root, config).unwrap();
+        let segments = store.segments(job).unwrap();
+        assert_eq!(segments.len(), 3);
+        assert!(segments[0].compressed && segments[1].compressed);
+        assert!(!segments[2].compressed);
+        assert_eq!(seqs(store.read(job, 0).unwrap()), vec![0, 1, 2, 3, 4]);
+        assert_eq!(seqs(store.read(job, 3).unwrap()), vec![3, 4]);
+        assert_eq!(store.last_seq(job).unwrap(), Some(4));
+        // a record cut short by a crash starts a segment of its own
+        store.close(job).unwrap();
+        let partial = store
+            .job_dir(job)
+            .join(format!("{:020}.{}", 5, SEGMENT_EXTENSION));
+        fs::write(partial, &encode_entry(&entry(5, "abcd"))[..4]).unwrap();
+        assert_eq!(store.last_seq(job).unwrap(), Some(4));
+        // and is cut off before the record is written again
+        store.append(job, &entry(5, "abcd")).unwrap();
+        store.append(job, &entry(6, "efgh")).unwrap();
+        let entries: Vec<LogEntry> = store.read(job, 5).unwrap().map(Result::unwrap).collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].seq, 5);
+        assert_eq!(record_data(&entries[1].record), &Bytes::from("efgh"));
+
+        // a segment whose compressed copy is complete but which wasn't removed yet is read once
+        let segment = store.segments(job).unwrap().remove(2).path;
+        let mut encoder = GzEncoder::new(
+            File::create(compressed_path(&segment)).unwrap(),
+            Compression::default(),
+        );
+        io::copy(&mut File::open(&segment).unwrap(), &mut encoder).unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(seqs(store.read(job, 0).unwrap()), vec![0, 1, 2, 3, 4, 5, 6]);
+        // and from the copy once it's gone
+        let reader = store.read(job, 0).unwrap();
+        fs::remove_file(&segment).unwrap();
+        assert_eq!(seqs(reader), vec![0, 1, 2, 3, 4, 5, 6]);
+
+        store.remove(job).unwrap();
+        assert!(store.read(job, 0).unwrap().next().is_none());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+use crate::limits::ResourceLimits;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Limits on what a single user may run at the same time, missing values are unlimited
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    // jobs that haven't finished yet
+    pub max_jobs: Option<u64>,
+    // sum of memory_max of unfinished jobs, in bytes
+    pub max_memory: Option<u64>,
+    // sum of cpu_max of unfinished jobs, in microseconds per 100ms period,
+    // e.g. 200000 allows two full CPUs
+    pub max_cpu: Option<u64>,
+    pub max_starts_per_minute: Option<u64>,
+}
+
+fn most_generous(left: Option<u64>, right: Option<u64>) -> Option<u64> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left.max(right)),
+        _ => None,
+    }
+}
+
+impl Quota {
+    fn most_generous(&self, other: &Quota) -> Quota {
+        Quota {
+            max_jobs: most_generous(self.max_jobs, other.max_jobs),
+            max_memory: most_generous(self.max_memory, other.max_memory),
+            max_cpu: most_generous(self.max_cpu, other.max_cpu),
+            max_starts_per_minute: most_generous(
+                self.max_starts_per_minute,
+                other.max_starts_per_minute,
+            ),
+        }
+    }
+
+    // Explains which part of the quota a new job with these limits would exceed.
+    // Jobs without a memory or cpu limit can't be admitted when that resource is limited.
+    pub fn admit(&self, usage: &Usage, limits: &ResourceLimits) -> Result<(), String> {
+        if let Some(max_jobs) = self.max_jobs {
+            if usage.jobs >= max_jobs {
+                return Err(format!(
+                    "{} jobs are running, the quota allows {}",
+                    usage.jobs, max_jobs
+                ));
+            }
+        }
+        if let Some(max_starts) = self.max_starts_per_minute {
+            if usage.starts_last_minute >= max_starts {
+                return Err(format!(
+                    "{} jobs were started in the last minute, the quota allows {}",
+                    usage.starts_last_minute, max_starts
+                ));
+            }
+        }
+        if let Some(max_memory) = self.max_memory {
+            match limits.memory_max {
+                None => return Err(String::from("memory quota requires a memory_max limit")),
+                // a sum that overflows is over any quota
+                Some(memory) if usage.memory.saturating_add(memory) > max_memory => {
+                    return Err(format!(
+                        "memory_max of {} bytes on top of {} bytes in use exceeds the quota of {} bytes",
+                        memory, usage.memory, max_memory
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        if let Some(max_cpu) = self.max_cpu {
+            match limits.cpu_max {
+                None => return Err(String::from("cpu quota requires a cpu_max limit")),
+                Some(cpu) if usage.cpu.saturating_add(u64::from(cpu)) > max_cpu => {
+                    return Err(format!(
+                        "cpu_max of {} on top of {} in use exceeds the quota of {}",
+                        cpu, usage.cpu, max_cpu
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Resources taken by the unfinished jobs of a user
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub jobs: u64,
+    pub memory: u64,
+    pub cpu: u64,
+    pub starts_last_minute: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotaUsage {
+    pub user: String,
+    pub usage: Usage,
+    pub quota: Quota,
+}
+
+// Quotas and default limits of the daemon.
+// A quota bound to the user wins, otherwise the most generous quota of its roles applies,
+// otherwise the default one. Without any of them the user is unlimited.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaPolicy {
+    // applied to every limit that the request leaves out
+    #[serde(default)]
+    pub default_limits: ResourceLimits,
+    #[serde(default)]
+    pub default: Option<Quota>,
+    #[serde(default)]
+    pub roles: HashMap<String, Quota>,
+    #[serde(default)]
+    pub users: HashMap<String, Quota>,
+}
+
+impl QuotaPolicy {
+    pub fn quota_of(&self, user: &str, roles: &[String]) -> Quota {
+        if let Some(quota) = self.users.get(user) {
+            return quota.clone();
+        }
+        roles
+            .iter()
+            .filter_map(|role| self.roles.get(role))
+            .fold(None, |acc: Option<Quota>, quota| match acc {
+                Some(acc) => Some(acc.most_generous(quota)),
+                None => Some(quota.clone()),
+            })
+            .or_else(|| self.default.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn apply_default_limits(&self, limits: &mut ResourceLimits) {
+        let defaults = &self.default_limits;
+        limits.memory_high = limits.memory_high.or(defaults.memory_high);
+        limits.memory_max = limits.memory_max.or(defaults.memory_max);
+        limits.cpu_max = limits.cpu_max.or(defaults.cpu_max);
+        limits.cpu_weight = limits.cpu_weight.or(defaults.cpu_weight);
+        limits.io_weight = limits.io_weight.or(defaults.io_weight);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn user_quota_wins_over_most_generous_role() {
+        let policy: QuotaPolicy = toml::from_str(
+            r#"
+            [default_limits]
+            memory_max = 1000
+
+            [default]
+            max_jobs = 1
+
+            [roles.batch]
+            max_jobs = 5
+            max_memory = 10000
+
+            [roles.ci]
+            max_jobs = 3
+
+            [users.alice]
+            max_jobs = 2
+            "#,
+        )
+        .unwrap();
+
+        let roles = vec![String::from("batch"), String::from("ci")];
+        let quota = policy.quota_of("bob", &roles);
+        assert_eq!(quota.max_jobs, Some(5));
+        assert_eq!(quota.max_memory, None);
+        assert_eq!(policy.quota_of("alice", &roles).max_jobs, Some(2));
+        assert_eq!(policy.quota_of("carol", &[]).max_jobs, Some(1));
+
+        let mut limits = ResourceLimits::default();
+        policy.apply_default_limits(&mut limits);
+        assert_eq!(limits.memory_max, Some(1000));
+    }
+
+    #[test]
+    fn admit_explains_exceeded_quota() {
+        let quota = Quota {
+            max_jobs: Some(2),
+            max_memory: Some(1000),
+            ..Default::default()
+        };
+        let usage = Usage {
+            jobs: 1,
+            memory: 600,
+            ..Default::default()
+        };
+        let limits = |memory_max| ResourceLimits {
+            memory_max,
+            ..Default::default()
+        };
+
+        assert!(quota.admit(&usage, &limits(Some(400))).is_ok());
+        assert!(quota.admit(&usage, &limits(Some(500))).is_err());
+        assert!(quota.admit(&usage, &limits(Some(u64::MAX))).is_err());
+        assert_eq!(
+            quota.admit(&usage, &limits(None)),
+            Err(String::from("memory quota requires a memory_max limit"))
+        );
+
+        let usage = Usage { jobs: 2, ..usage };
+        assert_eq!(
+            quota.admit(&usage, &limits(Some(1))),
+            Err(String::from("2 jobs are running, the quota allows 2"))
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{JobId, Owner};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+// How long finished jobs are kept, without a TTL and a count they are kept until somebody deletes them
+#[derive(Debug, Clone, Default)]
+pub struct RetentionPolicy {
+    // finished jobs that ended longer ago are deleted
+    pub ttl: Option<Duration>,
+    // only that many of the most recent finished jobs of every user are kept
+    pub max_per_user: Option<usize>,
+    // the most recent failures of every user are kept past the TTL and the count, so they can be looked into
+    pub keep_failures: usize,
+}
+
+// Finished job as seen by the retention policy
+#[derive(Debug, Clone)]
+pub struct FinishedJob {
+    pub job: JobId,
+    pub owner: Owner,
+    pub ended: SystemTime,
+    pub failed: bool,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.ttl.is_some() || self.max_per_user.is_some()
+    }
+
+    // Jobs the policy doesn't keep anymore
+    pub fn expired(&self, jobs: Vec<FinishedJob>, now: SystemTime) -> Vec<JobId> {
+        let mut by_owner: HashMap<Owner, Vec<FinishedJob>> = HashMap::new();
+        for job in jobs {
+            by_owner.entry(job.owner.clone()).or_default().push(job);
+        }
+
+        let mut expired = vec![];
+        for mut jobs in by_owner.into_values() {
+            // jobs that ended at the same time are kept in the same order every time
+            jobs.sort_by_key(|job| (Reverse(job.ended), job.job));
+            let mut failures = 0;
+            for (index, job) in jobs.into_iter().enumerate() {
+                if job.failed {
+                    failures += 1;
+                    if failures <= self.keep_failures {
+                        continue;
+                    }
+                }
+                let too_old = match (self.ttl, now.duration_since(job.ended)) {
+                    (Some(ttl), Ok(age)) => age > ttl,
+                    _ => false,
+                };
+                let too_many = match self.max_per_user {
+                    Some(max) => index >= max,
+                    None => false,
+                };
+                if too_old || too_many {
+                    expired.push(job.job);
+                }
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn recent_failures_outlive_ttl_and_count() {
+        let now = SystemTime::now();
+        let job = |owner: &str, age: u64, failed| FinishedJob {
+            job: Uuid::new_v4(),
+            owner: String::from(owner),
+            ended: now - Duration::from_secs(age),
+            failed,
+        };
+        let jobs = vec![
+            job("alice", 10, false),
+            job("alice", 20, false),
+            job("alice", 30, true),
+            job("alice", 40, true),
+            job("alice", 5000, false),
+            job("bob", 5000, false),
+        ];
+        let ids: Vec<JobId> = jobs.iter().map(|job| job.job).collect();
+        let policy = RetentionPolicy {
+            ttl: Some(Duration::from_secs(3600)),
+            max_per_user: Some(2),
+            keep_failures: 1,
+        };
+
+        let mut expired = policy.expired(jobs, now);
+        expired.sort();
+        let mut wanted = vec![ids[3], ids[4], ids[5]];
+        wanted.sort();
+        assert_eq!(expired, wanted);
+        assert!(!RetentionPolicy::default().is_enabled());
+    }
+}
@@ -0,0 +1,520 @@
+use crate::limits::ResourceLimits;
+use crate::{JobId, JobRequest, Labels, Owner};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use uuid::Uuid;
+
+pub type ScheduleId = Uuid;
+
+// Label put on every job launched by a schedule, its value is the schedule id
+pub const SCHEDULE_LABEL: &str = "jobruntime/schedule";
+
+// Upper bound of steps when looking for the next run, enough for expressions that match
+// once in several years, e.g. February 29th on a Monday
+const CRON_MAX_STEPS: usize = 100_000;
+
+// Standard five field cron expression: minute, hour, day of month, month, day of week.
+// Fields accept *, numbers, ranges, lists and steps, e.g. "*/15 9-17 * * 1-5".
+// Sunday is 0 or 7. Times are in UTC.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // with both day fields restricted a day matching any of them is enough
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .map_err(|_| format!("invalid step: {}", part))?;
+                if step == 0 {
+                    return Err(format!("invalid step: {}", part));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let number = |value: &str| -> Result<u32, String> {
+            match value.parse() {
+                Ok(value) if (min..=max).contains(&value) => Ok(value),
+                _ => Err(format!("{} is not in range {}-{}", value, min, max)),
+            }
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (number(start)?, number(end)?),
+                // a single value with a step runs from it to the end of the range
+                None if step > 1 => (number(range)?, max),
+                None => (number(range)?, number(range)?),
+            },
+        };
+        if start > end {
+            return Err(format!("invalid range: {}", range));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn matches(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+// Whether a parsed field matches every value of its range, however it was written
+fn matches_all(bits: u64, min: u32, max: u32) -> bool {
+    (min..=max).all(|value| matches(bits, value))
+}
+
+impl FromStr for CronSchedule {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields = match expression.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields: Vec<&str> = fields.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!(
+                "expected 5 fields in cron expression, got {}",
+                fields.len()
+            ));
+        }
+        let days = parse_field(fields[2], 1, 31)?;
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if matches(weekdays, 7) {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            expression: expression.trim().to_string(),
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: matches_all(days, 1, 31),
+            any_weekday: matches_all(weekdays, 0, 6),
+        })
+    }
+}
+
+impl TryFrom<String> for CronSchedule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<CronSchedule> for String {
+    fn from(cron: CronSchedule) -> Self {
+        cron.expression
+    }
+}
+
+impl CronSchedule {
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    fn day_matches(&self, time: &DateTime<Utc>) -> bool {
+        let day = matches(self.days, time.day());
+        let weekday = matches(self.weekdays, time.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    // First time after the given one that matches the expression
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let midnight = |date: NaiveDate| {
+            date.and_hms_opt(0, 0, 0)
+                .map(|naive| Utc.from_utc_datetime(&naive))
+        };
+        let mut time = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        for _ in 0..CRON_MAX_STEPS {
+            if !matches(self.months, time.month()) {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = midnight(NaiveDate::from_ymd_opt(year, month, 1)?)?;
+            } else if !self.day_matches(&time) {
+                time = midnight(time.naive_utc().date().succ_opt()?)?;
+            } else if !matches(self.hours, time.hour()) {
+                time = time.with_minute(0)? + Duration::hours(1);
+            } else if !matches(self.minutes, time.minute()) {
+                time = time + Duration::minutes(1);
+            } else {
+                return Some(time);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum When {
+    Cron(CronSchedule),
+    // seconds since the Unix epoch
+    At(i64),
+}
+
+// What to do when a run is due while the previous one is still going
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverlapPolicy {
+    // drop the run
+    #[default]
+    Skip,
+    // start it once the previous run ends, runs due in the meantime are merged into one
+    Queue,
+    // start it anyway
+    Allow,
+}
+
+// Job started on every run, stdin and terminals are not supported as nobody is attached
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobTemplate {
+    pub path: String,
+    pub args: Vec<String>,
+    pub limits: ResourceLimits,
+    pub labels: Labels,
+    pub priority: i32,
+    pub pool: Option<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl TryFrom<JobRequest> for JobTemplate {
+    type Error = String;
+
+    fn try_from(request: JobRequest) -> Result<Self, Self::Error> {
+        if request.stdin || request.tty {
+            return Err(String::from(
+                "scheduled jobs can't have stdin or a terminal",
+            ));
+        }
+        if !request.dependencies.is_empty() {
+            return Err(String::from("scheduled jobs can't depend on other jobs"));
+        }
+        if request.group_limits.is_some() {
+            return Err(String::from(
+                "scheduled jobs can't set limits of their group",
+            ));
+        }
+        Ok(Self {
+            path: request.path,
+            args: request.args,
+            limits: request.limits,
+            labels: request.labels,
+            priority: request.priority,
+            pool: request.pool,
+            env: request.env,
+            group: request.group,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: ScheduleId,
+    pub owner: Owner,
+    pub job: JobTemplate,
+    pub when: When,
+    #[serde(default)]
+    pub overlap: OverlapPolicy,
+    #[serde(default)]
+    pub paused: bool,
+    // seconds since the Unix epoch, none once a one-time schedule has run
+    pub next_run: Option<i64>,
+    pub last_job: Option<JobId>,
+    // why the last run couldn't be started
+    pub last_error: Option<String>,
+    // a run is waiting for the previous one to end
+    #[serde(default)]
+    pub queued_run: bool,
+}
+
+impl Schedule {
+    pub fn new(owner: Owner, job: JobTemplate, when: When) -> Self {
+        let mut schedule = Self {
+            id: Uuid::new_v4(),
+            owner,
+            job,
+            when,
+            overlap: OverlapPolicy::default(),
+            paused: false,
+            next_run: None,
+            last_job: None,
+            last_error: None,
+            queued_run: false,
+        };
+        schedule.next_run = match &schedule.when {
+            When::Cron(_) => schedule.next_run_after(Utc::now()),
+            When::At(time) => Some(*time),
+        };
+        schedule
+    }
+
+    pub fn next_run_after(&self, time: DateTime<Utc>) -> Option<i64> {
+        match &self.when {
+            When::Cron(cron) => cron.next_after(time).map(|time| time.timestamp()),
+            When::At(_) => None,
+        }
+    }
+
+    // Request of the next run, its jobs can be found by the schedule label
+    pub fn job_request(&self) -> JobRequest {
+        let mut labels = self.job.labels.clone();
+        labels.insert(String::from(SCHEDULE_LABEL), self.id.to_string());
+        JobRequest {
+            path: self.job.path.clone(),
+            args: self.job.args.clone(),
+            limits: self.job.limits.clone(),
+            labels,
+            priority: self.job.priority,
+            pool: self.job.pool.clone(),
+            env: self.job.env.clone(),
+            group: self.job.group.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+// Schedules of the runtime, written to a JSON file after every change when a path is given
+#[derive(Debug, Default)]
+pub struct Schedules {
+    writer: Option<ScheduleWriter>,
+    schedules: HashMap<ScheduleId, Schedule>,
+}
+
+// Writes the schedule file on a thread of its own, so the runtime never waits for the disk.
+// Snapshots are written in the order they were taken, the ones already outdated are skipped.
+#[derive(Debug)]
+struct ScheduleWriter {
+    snapshots: Option<mpsc::Sender<Vec<u8>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ScheduleWriter {
+    fn start(path: PathBuf) -> io::Result<Self> {
+        let (snapshots, rx) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(String::from("schedules"))
+            .spawn(move || write_snapshots(path, rx))?;
+        Ok(Self {
+            snapshots: Some(snapshots),
+            thread: Some(thread),
+        })
+    }
+}
+
+// The last snapshot is written before the daemon stops
+impl Drop for ScheduleWriter {
+    fn drop(&mut self) {
+        self.snapshots.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_snapshots(path: PathBuf, snapshots: mpsc::Receiver<Vec<u8>>) {
+    while let Ok(mut content) = snapshots.recv() {
+        while let Ok(newer) = snapshots.try_recv() {
+            content = newer;
+        }
+        if let Err(err) = replace_file(&path, &content) {
+            log::error!("unable to save schedules to {}: {}", path.display(), err);
+        }
+    }
+}
+
+// Replaces the file at once, so a crash never leaves a half written file behind
+fn replace_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+impl Schedules {
+    // Cron schedules continue from now, runs missed while the daemon was down are not made up.
+    // One-time schedules that were due in the meantime run right away.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let schedules: Vec<Schedule> = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        let now = Utc::now();
+        let schedules = schedules
+            .into_iter()
+            .map(|mut schedule| {
+                if let When::Cron(_) = schedule.when {
+                    schedule.next_run = schedule.next_run_after(now);
+                }
+                (schedule.id, schedule)
+            })
+            .collect();
+        Ok(Self {
+            writer: Some(ScheduleWriter::start(path)?),
+            schedules,
+        })
+    }
+
+    // Hands a snapshot to the writer, errors of writing it are only logged
+    pub fn save(&self) -> io::Result<()> {
+        let snapshots = match &self.writer {
+            Some(writer) => &writer.snapshots,
+            None => return Ok(()),
+        };
+        let mut schedules: Vec<&Schedule> = self.schedules.values().collect();
+        schedules.sort_by_key(|schedule| schedule.id);
+        let content = serde_json::to_vec_pretty(&schedules)?;
+        match snapshots {
+            Some(snapshots) if snapshots.send(content).is_ok() => Ok(()),
+            _ => Err(io::Error::other("schedule writer has stopped")),
+        }
+    }
+
+    pub fn insert(&mut self, schedule: Schedule) {
+        self.schedules.insert(schedule.id, schedule);
+    }
+
+    pub fn get(&self, id: ScheduleId) -> Option<&Schedule> {
+        self.schedules.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: ScheduleId) -> Option<&mut Schedule> {
+        self.schedules.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: ScheduleId) -> Option<Schedule> {
+        self.schedules.remove(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Schedule> {
+        self.schedules.values()
+    }
+
+    // Schedules with a run due at the given time or waiting for the previous run to end
+    pub fn due(&self, now: i64) -> Vec<ScheduleId> {
+        self.schedules
+            .values()
+            .filter(|schedule| !schedule.paused)
+            .filter(|schedule| {
+                schedule.queued_run || matches!(schedule.next_run, Some(next) if next <= now)
+            })
+            .map(|schedule| schedule.id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(value: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(value)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn day_fields_are_restricted_unless_they_match_every_day() {
+        // every other day or Mondays, 2022-02-07 is a Monday
+        let cron: CronSchedule = "0 0 */2 * 1".parse().unwrap();
+        assert_eq!(
+            cron.next_after(time("2022-02-05T12:00:00Z")),
+            Some(time("2022-02-07T00:00:00Z"))
+        );
+        assert_eq!(
+            cron.next_after(time("2022-02-07T12:00:00Z")),
+            Some(time("2022-02-09T00:00:00Z"))
+        );
+        // all days of the month written out are the same as *, so only Mondays match
+        let cron: CronSchedule = "0 0 1-31 * 1".parse().unwrap();
+        assert_eq!(
+            cron.next_after(time("2022-02-07T12:00:00Z")),
+            Some(time("2022-02-14T00:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn cron_finds_next_matching_minute() {
+        let cron: CronSchedule = "*/15 9-17 * * 1-5".parse().unwrap();
+        // Friday evening continues on Monday morning
+        assert_eq!(
+            cron.next_after(time("2022-02-04T17:50:00Z")),
+            Some(time("2022-02-07T09:00:00Z"))
+        );
+        assert_eq!(
+            cron.next_after(time("2022-02-07T09:00:00Z")),
+            Some(time("2022-02-07T09:15:00Z"))
+        );
+
+        let leap: CronSchedule = "0 12 29 2 *".parse().unwrap();
+        assert_eq!(
+            leap.next_after(time("2022-03-01T00:00:00Z")),
+            Some(time("2024-02-29T12:00:00Z"))
+        );
+
+        // either day field matches when both are restricted
+        let either: CronSchedule = "0 0 13 * 5".parse().unwrap();
+        assert_eq!(
+            either.next_after(time("2022-02-05T00:00:00Z")),
+            Some(time("2022-02-11T00:00:00Z"))
+        );
+        assert_eq!(
+            "@daily"
+                .parse::<CronSchedule>()
+                .unwrap()
+                .next_after(time("2022-02-05T10:00:00Z")),
+            Some(time("2022-02-06T00:00:00Z"))
+        );
+
+        assert!("* * *".parse::<CronSchedule>().is_err());
+        assert!("60 * * * *".parse::<CronSchedule>().is_err());
+        assert!("*/0 * * * *".parse::<CronSchedule>().is_err());
+    }
+}
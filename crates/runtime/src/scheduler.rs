@@ -0,0 +1,302 @@
+use crate::{JobId, Owner};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::ops::RangeInclusive;
+
+// How many jobs may run at the same time, missing values are unlimited
+#[derive(Debug, Clone, Default)]
+pub struct SchedulerConfig {
+    pub max_running: Option<usize>,
+    // limits of named pools, jobs without a pool only count against max_running
+    pub pools: HashMap<String, usize>,
+}
+
+// Priorities every user may give their jobs, others need the priority:any permission
+pub const USER_PRIORITIES: RangeInclusive<i32> = -10..=10;
+
+#[derive(Debug)]
+struct QueuedJob {
+    job: JobId,
+    pool: Option<String>,
+    // order of submission
+    seq: u64,
+}
+
+#[derive(Debug)]
+struct RunningJob {
+    owner: Owner,
+    pool: Option<String>,
+}
+
+// Waiting jobs of every user in the order they were submitted
+type Level = HashMap<Owner, VecDeque<QueuedJob>>;
+
+// Decides which of the submitted jobs run and which wait in the queue.
+// Jobs with a higher priority go first. Within the same priority users take turns:
+// the next job belongs to the user with the fewest running jobs, or the one that got
+// a slot least recently, and the jobs of a single user keep the order they were submitted in.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    config: SchedulerConfig,
+    // highest priority first
+    queue: BTreeMap<Reverse<i32>, Level>,
+    // priority and owner of every waiting job, to find it in the queue
+    queued: HashMap<JobId, (i32, Owner)>,
+    running: HashMap<JobId, RunningJob>,
+    running_by_owner: HashMap<Owner, u64>,
+    running_by_pool: HashMap<String, usize>,
+    next_seq: u64,
+    // counts slots handed out, remembered per user to know who got one least recently
+    picks: u64,
+    last_pick: HashMap<Owner, u64>,
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn has_pool(&self, pool: &str) -> bool {
+        self.config.pools.contains_key(pool)
+    }
+
+    fn has_free_slot(&self, pool: Option<&str>) -> bool {
+        let global = match self.config.max_running {
+            Some(max) => self.running.len() < max,
+            None => true,
+        };
+        let pool = match pool.and_then(|pool| self.config.pools.get(pool).map(|max| (pool, max))) {
+            Some((pool, max)) => self.running_by_pool.get(pool).copied().unwrap_or(0) < *max,
+            None => true,
+        };
+        global && pool
+    }
+
+    // Takes a slot right away when one is free. Jobs that are already waiting can't use it,
+    // they would have been started as soon as it became free.
+    pub fn try_run(&mut self, job: JobId, owner: &str, pool: Option<&str>) -> bool {
+        if !self.has_free_slot(pool) {
+            return false;
+        }
+        self.picks += 1;
+        self.last_pick.insert(String::from(owner), self.picks);
+        self.resume(job, owner, pool);
+        true
+    }
+
+    // Counts a job that kept running while the daemon restarted, even when it's over the limits now
+    pub fn resume(&mut self, job: JobId, owner: &str, pool: Option<&str>) {
+        *self
+            .running_by_owner
+            .entry(String::from(owner))
+            .or_default() += 1;
+        if let Some(pool) = pool {
+            *self.running_by_pool.entry(String::from(pool)).or_default() += 1;
+        }
+        let running = RunningJob {
+            owner: String::from(owner),
+            pool: pool.map(String::from),
+        };
+        if let Some(previous) = self.running.insert(job, running) {
+            self.release_slot(previous);
+        }
+    }
+
+    pub fn enqueue(&mut self, job: JobId, owner: &str, priority: i32, pool: Option<&str>) {
+        self.queue
+            .entry(Reverse(priority))
+            .or_default()
+            .entry(String::from(owner))
+            .or_default()
+            .push_back(QueuedJob {
+                job,
+                pool: pool.map(String::from),
+                seq: self.next_seq,
+            });
+        self.queued.insert(job, (priority, String::from(owner)));
+        self.next_seq += 1;
+    }
+
+    // Removes a job that was stopped while waiting
+    pub fn dequeue(&mut self, job: JobId) -> bool {
+        let (priority, owner) = match self.queued.remove(&job) {
+            Some(queued) => queued,
+            None => return false,
+        };
+        let level = match self.queue.get_mut(&Reverse(priority)) {
+            Some(level) => level,
+            None => return false,
+        };
+        if let Some(jobs) = level.get_mut(&owner) {
+            jobs.retain(|queued| queued.job != job);
+            if jobs.is_empty() {
+                level.remove(&owner);
+            }
+        }
+        if level.is_empty() {
+            self.queue.remove(&Reverse(priority));
+        }
+        true
+    }
+
+    // Frees the slot of a job that ended
+    pub fn finished(&mut self, job: JobId) {
+        if let Some(running) = self.running.remove(&job) {
+            self.release_slot(running);
+        }
+    }
+
+    fn release_slot(&mut self, running: RunningJob) {
+        if let Some(count) = self.running_by_owner.get_mut(&running.owner) {
+            *count -= 1;
+            if *count == 0 {
+                self.running_by_owner.remove(&running.owner);
+            }
+        }
+        if let Some(count) = running
+            .pool
+            .and_then(|pool| self.running_by_pool.get_mut(&pool))
+        {
+            *count -= 1;
+        }
+    }
+
+    // Jobs of a user within a priority are ordered by their turn: the number of jobs
+    // the user runs plus the ones it has ahead of them in the queue. Ties go to the user
+    // that got a slot least recently, then to the job submitted first.
+    fn turn(&self, owner: &str, index: usize, queued: &QueuedJob) -> (u64, u64, u64) {
+        (
+            self.running_by_owner.get(owner).copied().unwrap_or(0) + index as u64,
+            self.last_pick.get(owner).copied().unwrap_or(0),
+            queued.seq,
+        )
+    }
+
+    // Position of a waiting job starting from 1, as if every pool had a free slot
+    pub fn position(&self, job: JobId) -> Option<usize> {
+        let (priority, owner) = self.queued.get(&job)?;
+        let mut position = 1;
+        for (level_priority, level) in &self.queue {
+            if level_priority.0 != *priority {
+                position += level.values().map(VecDeque::len).sum::<usize>();
+                continue;
+            }
+            let jobs = level.get(owner)?;
+            let index = jobs.iter().position(|queued| queued.job == job)?;
+            let turn = self.turn(owner, index, &jobs[index]);
+            // the turns of a user's jobs grow by one, so only one of them can tie
+            for (other, jobs) in level {
+                let first = self.turn(other, 0, &jobs[0]).0;
+                let ahead = turn.0.saturating_sub(first).min(jobs.len() as u64) as usize;
+                let tie = jobs
+                    .get(ahead)
+                    .filter(|queued| self.turn(other, ahead, queued) < turn);
+                position += ahead + usize::from(tie.is_some());
+            }
+            return Some(position);
+        }
+        None
+    }
+
+    // Picks the next waiting job that has a free slot and counts it as running.
+    // Jobs of a full pool are skipped, so they don't hold back jobs of other pools.
+    pub fn pick_next(&mut self) -> Option<JobId> {
+        if !self.has_free_slot(None) {
+            return None;
+        }
+        let mut picked = None;
+        for (priority, level) in &self.queue {
+            let mut best: Option<((u64, u64, u64), &Owner, usize)> = None;
+            for (owner, jobs) in level {
+                // the first job of the user that can run, the ones after it have later turns
+                let candidate = jobs
+                    .iter()
+                    .enumerate()
+                    .find(|(_, queued)| self.has_free_slot(queued.pool.as_deref()));
+                if let Some((index, queued)) = candidate {
+                    let turn = self.turn(owner, index, queued);
+                    if best.as_ref().is_none_or(|(best, _, _)| turn < *best) {
+                        best = Some((turn, owner, index));
+                    }
+                }
+            }
+            if let Some((_, owner, index)) = best {
+                picked = Some((*priority, owner.clone(), index));
+                break;
+            }
+        }
+
+        let (priority, owner, index) = picked?;
+        let level = self.queue.get_mut(&priority)?;
+        let jobs = level.get_mut(&owner)?;
+        let queued = jobs.remove(index)?;
+        if jobs.is_empty() {
+            level.remove(&owner);
+        }
+        if level.is_empty() {
+            self.queue.remove(&priority);
+        }
+        self.queued.remove(&queued.job);
+        self.try_run(queued.job, &owner, queued.pool.as_deref());
+        Some(queued.job)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn users_take_turns_within_the_same_priority() {
+        let mut scheduler = Scheduler::new(SchedulerConfig {
+            max_running: Some(1),
+            ..Default::default()
+        });
+        let running = Uuid::new_v4();
+        assert!(scheduler.try_run(running, "alice", None));
+
+        let alice: Vec<JobId> = (0..3).map(|_| Uuid::new_v4()).collect();
+        for job in &alice {
+            scheduler.enqueue(*job, "alice", 0, None);
+        }
+        let bob = Uuid::new_v4();
+        scheduler.enqueue(bob, "bob", 0, None);
+        let urgent = Uuid::new_v4();
+        scheduler.enqueue(urgent, "carol", 10, None);
+
+        assert_eq!(scheduler.position(urgent), Some(1));
+        assert_eq!(scheduler.position(bob), Some(2));
+        assert_eq!(scheduler.pick_next(), None);
+
+        scheduler.finished(running);
+        assert_eq!(scheduler.pick_next(), Some(urgent));
+        scheduler.finished(urgent);
+        assert_eq!(scheduler.pick_next(), Some(bob));
+        scheduler.finished(bob);
+        assert_eq!(scheduler.pick_next(), Some(alice[0]));
+        assert_eq!(scheduler.position(alice[2]), Some(2));
+    }
+
+    #[test]
+    fn full_pool_does_not_block_other_jobs() {
+        let mut scheduler = Scheduler::new(SchedulerConfig {
+            max_running: Some(3),
+            pools: HashMap::from([(String::from("gpu"), 1)]),
+        });
+        assert!(scheduler.try_run(Uuid::new_v4(), "alice", Some("gpu")));
+
+        let gpu = Uuid::new_v4();
+        scheduler.enqueue(gpu, "alice", 5, Some("gpu"));
+        let cpu = Uuid::new_v4();
+        scheduler.enqueue(cpu, "alice", 0, None);
+
+        assert_eq!(scheduler.pick_next(), Some(cpu));
+        assert_eq!(scheduler.pick_next(), None);
+        assert!(scheduler.dequeue(gpu));
+        assert!(!scheduler.dequeue(gpu));
+    }
+}
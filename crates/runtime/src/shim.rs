@@ -0,0 +1,669 @@
+use crate::limits::Cgroup;
+use crate::logstore::{encode_entry, split_entry};
+use crate::{JobId, JobRequest, LogEntry, LogRecord, LOG_SIZE, PIPE_DRAIN_TIMEOUT};
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+// Files in the state directory of a job
+const SHIM_PID_FILE: &str = "shim.pid";
+// the output is split into numbered segments, output.0, output.1, ...
+const OUTPUT_FILE: &str = "output";
+const EXIT_FILE: &str = "exit";
+// Size after which the shim starts the next segment of the output, the daemon removes
+// a segment once it has read all of it and the log store has written its records out
+const OUTPUT_SEGMENT_BYTES: u64 = 1024 * 1024;
+// Makes the shim kill the job, the job itself is never signalled by pid as the pid may be reused
+const KILL_SIGNAL: i32 = libc::SIGTERM;
+// Sent by the runtime once the shim is in the cgroup of the job, the shim doesn't start the job without it
+const START_TOKEN: &str = "start";
+// How often the runtime looks for new output and the exit status of a job run by a shim
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Starts jobs under jobshim, a small supervisor that owns the pipes and the exit status of the job.
+// The shim runs in its own session, so the job keeps running when the daemon stops, and leaves
+// everything the daemon needs in a directory per job: its output records and eventually its exit status.
+pub struct Shims {
+    binary: PathBuf,
+    state_dir: PathBuf,
+}
+
+impl Shims {
+    pub fn new(binary: PathBuf, state_dir: PathBuf) -> io::Result<Self> {
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&state_dir)?;
+        Ok(Self { binary, state_dir })
+    }
+
+    fn job_dir(&self, job: JobId) -> PathBuf {
+        self.state_dir.join(job.to_string())
+    }
+
+    pub fn exists(&self, job: JobId) -> bool {
+        self.job_dir(job).exists()
+    }
+
+    // Starts the shim of the job and puts it into the cgroup. The job is started
+    // by the handshake, which waits for the shim, so it runs outside of the event loop.
+    pub fn spawn(
+        &self,
+        job: JobId,
+        request: &JobRequest,
+        cgroup: Option<&Cgroup>,
+    ) -> io::Result<ShimStart> {
+        let dir = self.job_dir(job);
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let result = self.start_shim(&dir, request, cgroup);
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&dir);
+        }
+        result
+    }
+
+    fn start_shim(
+        &self,
+        dir: &Path,
+        request: &JobRequest,
+        cgroup: Option<&Cgroup>,
+    ) -> io::Result<ShimStart> {
+        let shim = tokio::process::Command::new(&self.binary)
+            .arg(dir)
+            .arg("--")
+            .arg(&request.path)
+            .args(&request.args)
+            .envs(&request.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        // without the token the shim exits once its stdin is dropped together with it
+        if let (Some(cgroup), Some(pid)) = (cgroup, shim.id()) {
+            cgroup.add_pid(pid)?;
+        }
+        Ok(ShimStart {
+            dir: dir.to_path_buf(),
+            shim,
+        })
+    }
+
+    pub fn open(&self, job: JobId) -> io::Result<ShimJob> {
+        ShimJob::open(self.job_dir(job))
+    }
+
+    // The state is only needed until the end of the job is recorded
+    pub fn remove(&self, job: JobId) -> io::Result<()> {
+        let dir = self.job_dir(job);
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+}
+
+// A shim that waits for the token to start its job
+pub struct ShimStart {
+    dir: PathBuf,
+    shim: tokio::process::Child,
+}
+
+impl ShimStart {
+    // Tells the shim to start the job and returns the pid of its process
+    // together with the state directory to open the job from
+    pub async fn handshake(mut self) -> io::Result<(i32, PathBuf)> {
+        let result = self.exchange().await;
+        // the shim forks into the background right after answering
+        drop(self.shim.stdin.take());
+        let result = match self.shim.wait().await {
+            Ok(_) => result,
+            Err(err) => result.and(Err(err)),
+        };
+        match result {
+            Ok(pid) => Ok((pid, self.dir)),
+            Err(err) => {
+                let _ = tokio::fs::remove_dir_all(&self.dir).await;
+                Err(err)
+            }
+        }
+    }
+
+    async fn exchange(&mut self) -> io::Result<i32> {
+        if let Some(stdin) = self.shim.stdin.as_mut() {
+            stdin
+                .write_all(format!("{}\n", START_TOKEN).as_bytes())
+                .await?;
+        }
+        let mut line = String::new();
+        if let Some(stdout) = self.shim.stdout.take() {
+            BufReader::new(stdout).read_line(&mut line).await?;
+        }
+        parse_handshake(&line)
+    }
+}
+
+// The shim answers with "pid <pid>" or "error <errno> <message>"
+fn parse_handshake(line: &str) -> io::Result<i32> {
+    let mut parts = line.trim_end().splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("pid"), Some(pid), None) => pid
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        (Some("error"), Some(errno), message) => match errno.parse() {
+            Ok(errno) if errno > 0 => Err(io::Error::from_raw_os_error(errno)),
+            _ => Err(io::Error::other(message.unwrap_or_default().to_string())),
+        },
+        _ => Err(io::Error::other("shim exited without starting the job")),
+    }
+}
+
+pub(crate) fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{}.{}", OUTPUT_FILE, segment))
+}
+
+fn pidfd_open(pid: i32) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn pidfd_send_signal(pidfd: &OwnedFd, signal: i32) -> io::Result<()> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// What the shim has written since the last poll
+pub struct ShimState {
+    pub entries: Vec<LogEntry>,
+    pub status: Option<ExitStatus>,
+    pub alive: bool,
+}
+
+// Output and exit status of a job run by a shim, read while the shim is writing them
+pub struct ShimJob {
+    dir: PathBuf,
+    // refers to the shim itself, so a reused pid is never signalled, none once the shim is gone
+    pidfd: Option<OwnedFd>,
+    // segment of the output being read
+    segment: u64,
+    output: Option<File>,
+    // bytes of a record that isn't complete yet
+    buf: BytesMut,
+    // last record read from the segment being read
+    last_seq: Option<u64>,
+    // segments read completely with their last record, kept until the log store has it
+    read: VecDeque<(u64, Option<u64>)>,
+}
+
+impl ShimJob {
+    // Reading continues with the oldest segment left, the log store has the records of the ones before it
+    pub fn open(dir: PathBuf) -> io::Result<Self> {
+        let shim_pid: i32 = fs::read_to_string(dir.join(SHIM_PID_FILE))?
+            .trim()
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let pidfd = match pidfd_open(shim_pid) {
+            // the shim is known by the state directory on its command line, another process may have its pid by now
+            Ok(pidfd) => match fs::read(format!("/proc/{}/cmdline", shim_pid)) {
+                Ok(cmdline)
+                    if cmdline.split(|byte| *byte == 0).nth(1)
+                        == Some(dir.as_os_str().as_bytes()) =>
+                {
+                    Some(pidfd)
+                }
+                _ => None,
+            },
+            Err(err) if err.raw_os_error() == Some(libc::ESRCH) => None,
+            Err(err) => return Err(err),
+        };
+        let mut segment: Option<u64> = None;
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let first = name
+                .to_str()
+                .and_then(|name| name.strip_prefix(OUTPUT_FILE))
+                .and_then(|suffix| suffix.strip_prefix('.'))
+                .and_then(|number| number.parse().ok());
+            if let Some(first) = first {
+                segment = Some(segment.map_or(first, |segment: u64| segment.min(first)));
+            }
+        }
+        Ok(Self {
+            dir,
+            pidfd,
+            segment: segment.unwrap_or(0),
+            output: None,
+            buf: BytesMut::new(),
+            last_seq: None,
+            read: VecDeque::new(),
+        })
+    }
+
+    // Looks at the exit status before the output, the status is written after all of it.
+    // Segments with records below written_until are kept by the log store and removed.
+    // Reads files, so it's called outside of the event loop.
+    pub fn poll(&mut self, written_until: Option<u64>) -> io::Result<ShimState> {
+        if let Some(written_until) = written_until {
+            self.release(written_until)?;
+        }
+        let alive = self.is_alive();
+        let status = self.exit_status()?;
+        let entries = self.read_entries()?;
+        Ok(ShimState {
+            entries,
+            status,
+            alive,
+        })
+    }
+
+    // Records written since the last call. A segment the shim has moved on from is complete,
+    // once read it only waits for the log store, so the output doesn't grow with the job.
+    // Until then a daemon that dies reads it again after the restart.
+    pub fn read_entries(&mut self) -> io::Result<Vec<LogEntry>> {
+        let mut entries = vec![];
+        loop {
+            if self.output.is_none() {
+                match File::open(segment_path(&self.dir, self.segment)) {
+                    Ok(file) => self.output = Some(file),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(entries),
+                    Err(err) => return Err(err),
+                }
+            }
+            // checked before reading, so nothing is written to the segment after the read
+            let next = segment_path(&self.dir, self.segment + 1);
+            let complete = next.exists();
+            if let Some(output) = self.output.as_mut() {
+                let mut data = Vec::new();
+                output.read_to_end(&mut data)?;
+                self.buf.extend_from_slice(&data);
+            }
+            while let Some(entry) = split_entry(&mut self.buf)? {
+                self.last_seq = Some(entry.seq);
+                entries.push(entry);
+            }
+            if !complete {
+                return Ok(entries);
+            }
+            // records never span segments, so the rest was cut short when a shim died
+            self.buf.clear();
+            self.output = None;
+            self.read.push_back((self.segment, self.last_seq.take()));
+            self.segment += 1;
+        }
+    }
+
+    // Removes the segments read completely whose records are all below written_until
+    pub fn release(&mut self, written_until: u64) -> io::Result<()> {
+        while let Some((segment, last_seq)) = self.read.front().copied() {
+            if last_seq.is_some_and(|last_seq| last_seq >= written_until) {
+                break;
+            }
+            fs::remove_file(segment_path(&self.dir, segment))?;
+            self.read.pop_front();
+        }
+        Ok(())
+    }
+
+    // Written after all of the output, so reading the output once more gets the rest of it
+    pub fn exit_status(&self) -> io::Result<Option<ExitStatus>> {
+        match fs::read_to_string(self.dir.join(EXIT_FILE)) {
+            Ok(raw) => raw
+                .trim()
+                .parse()
+                .map(|raw| Some(ExitStatus::from_raw(raw)))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn is_alive(&self) -> bool {
+        // signal 0 only checks that the process exists
+        match &self.pidfd {
+            Some(pidfd) => match pidfd_send_signal(pidfd, 0) {
+                Ok(()) => true,
+                Err(err) => err.raw_os_error() == Some(libc::EPERM),
+            },
+            None => false,
+        }
+    }
+
+    // Asks the shim to kill the job, it does so only while it hasn't collected the exit status yet
+    pub fn kill(&self) -> io::Result<()> {
+        match &self.pidfd {
+            Some(pidfd) => pidfd_send_signal(pidfd, KILL_SIGNAL),
+            None => Err(io::Error::from_raw_os_error(libc::ESRCH)),
+        }
+    }
+}
+
+// Entry point of the shim: jobshim STATE_DIR -- PATH [ARGS...]
+pub fn run(args: Vec<OsString>) -> i32 {
+    let (dir, path, args) = match args.as_slice() {
+        [_, dir, separator, path, args @ ..] if separator == "--" => {
+            (PathBuf::from(dir), path.clone(), args.to_vec())
+        }
+        _ => {
+            eprintln!("usage: jobshim STATE_DIR -- PATH [ARGS...]");
+            return 2;
+        }
+    };
+    let mut token = String::new();
+    if io::stdin().read_line(&mut token).is_err() || token.trim_end() != START_TOKEN {
+        return 1;
+    }
+
+    // the runtime only waits for the first process, the second one is adopted by init
+    match unsafe { libc::fork() } {
+        -1 => {
+            report_error(&io::Error::last_os_error());
+            return 1;
+        }
+        0 => {}
+        _ => return 0,
+    }
+    // signals sent to the process group of the daemon, e.g. Ctrl-C, don't reach the job
+    unsafe {
+        libc::setsid();
+    }
+    // the kill signal is taken by a thread of its own, threads started later inherit the mask
+    if let Err(err) = set_kill_signal_mask(libc::SIG_BLOCK) {
+        report_error(&err);
+        return 1;
+    }
+    match supervise(&dir, path, args) {
+        Ok(()) => 0,
+        Err(err) => {
+            report_error(&err);
+            1
+        }
+    }
+}
+
+fn report_error(err: &io::Error) {
+    println!("error {} {}", err.raw_os_error().unwrap_or(0), err);
+}
+
+fn set_kill_signal_mask(how: i32) -> io::Result<()> {
+    unsafe {
+        let mut set = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, KILL_SIGNAL);
+        match libc::pthread_sigmask(how, &set, std::ptr::null_mut()) {
+            0 => Ok(()),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+fn supervise(dir: &Path, path: OsString, args: Vec<OsString>) -> io::Result<()> {
+    let output = Output::create(dir.to_path_buf())?;
+    fs::write(dir.join(SHIM_PID_FILE), std::process::id().to_string())?;
+    let mut command = Command::new(path);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    unsafe {
+        command.pre_exec(|| set_kill_signal_mask(libc::SIG_UNBLOCK));
+    }
+    let mut child = command.spawn()?;
+    let pid = child.id() as i32;
+
+    let mut stdout = io::stdout();
+    writeln!(stdout, "pid {}", child.id())?;
+    stdout.flush()?;
+    // the pipe to the daemon may be gone by the time the shim writes anything else
+    detach_stdio()?;
+
+    // set under the lock right before the job is reaped, its pid may be reused after that
+    let reaped = Arc::new(Mutex::new(false));
+    spawn_killer(pid, reaped.clone());
+
+    let output = Arc::new(Mutex::new(output));
+    let (done_tx, done_rx) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        spawn_pump(stdout, LogRecord::Stdout, output.clone(), done_tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        spawn_pump(stderr, LogRecord::Stderr, output, done_tx);
+    }
+
+    // waits for the job to exit without reaping it
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        if unsafe {
+            libc::waitid(
+                libc::P_PID,
+                pid as u32,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        } == 0
+        {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    if let Ok(mut reaped) = reaped.lock() {
+        *reaped = true;
+    }
+    let status = child.wait()?;
+    // processes the job left behind may keep the output open, they aren't waited for forever
+    let deadline = Instant::now() + PIPE_DRAIN_TIMEOUT;
+    for _ in 0..2 {
+        if done_rx
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            .is_err()
+        {
+            break;
+        }
+    }
+
+    // renamed into place, so the runtime never reads a partial status
+    let exit = dir.join(EXIT_FILE);
+    let tmp = exit.with_extension("tmp");
+    fs::write(&tmp, status.into_raw().to_string())?;
+    fs::rename(tmp, exit)
+}
+
+fn detach_stdio() -> io::Result<()> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Kills the job when the daemon asks for it, unless it has already been reaped
+fn spawn_killer(pid: i32, reaped: Arc<Mutex<bool>>) {
+    thread::spawn(move || {
+        let mut set = unsafe { std::mem::zeroed() };
+        let mut signal = 0;
+        unsafe {
+            libc::sigemptyset(&mut set);
+            libc::sigaddset(&mut set, KILL_SIGNAL);
+        }
+        if unsafe { libc::sigwait(&set, &mut signal) } != 0 {
+            return;
+        }
+        if let Ok(reaped) = reaped.lock() {
+            if !*reaped {
+                unsafe {
+                    libc::kill(pid, libc::SIGKILL);
+                }
+            }
+        }
+    });
+}
+
+// Records of the job in segments, the next segment is created once the current one is full
+// and nothing is written to the previous one after that
+struct Output {
+    dir: PathBuf,
+    segment: u64,
+    file: File,
+    size: u64,
+    seq: u64,
+}
+
+impl Output {
+    fn create(dir: PathBuf) -> io::Result<Self> {
+        let file = create_segment(&dir, 0)?;
+        Ok(Self {
+            dir,
+            segment: 0,
+            file,
+            size: 0,
+            seq: 0,
+        })
+    }
+
+    fn write(&mut self, record: LogRecord) -> io::Result<()> {
+        let encoded = encode_entry(&LogEntry {
+            seq: self.seq,
+            record,
+        });
+        if self.size > 0 && self.size + encoded.len() as u64 > OUTPUT_SEGMENT_BYTES {
+            self.file = create_segment(&self.dir, self.segment + 1)?;
+            self.segment += 1;
+            self.size = 0;
+        }
+        self.file.write_all(&encoded)?;
+        self.size += encoded.len() as u64;
+        self.seq += 1;
+        Ok(())
+    }
+}
+
+fn create_segment(dir: &Path, segment: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(segment_path(dir, segment))
+}
+
+// Appends everything read from the stream to the output, one record per read
+fn spawn_pump<R: Read + Send + 'static>(
+    mut reader: R,
+    record: fn(Bytes) -> LogRecord,
+    output: Arc<Mutex<Output>>,
+    done_tx: mpsc::Sender<()>,
+) {
+    thread::spawn(move || {
+        let mut buf = [0u8; LOG_SIZE];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            let mut output = match output.lock() {
+                Ok(output) => output,
+                Err(_) => break,
+            };
+            // the job keeps running even when its output can't be kept
+            let _ = output.write(record(Bytes::copy_from_slice(&buf[..n])));
+        }
+        let _ = done_tx.send(());
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn output_is_read_as_the_shim_writes_it() {
+        let state_dir = std::env::temp_dir().join(format!("jobruntime-shim-{}", Uuid::new_v4()));
+        let shims = Shims::new(PathBuf::from("jobshim"), state_dir.clone()).unwrap();
+        let job = Uuid::new_v4();
+        let dir = shims.job_dir(job);
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join(SHIM_PID_FILE), std::process::id().to_string()).unwrap();
+
+        let encoded: Vec<u8> = ["a", "b", "c"]
+            .iter()
+            .enumerate()
+            .flat_map(|(seq, data)| {
+                encode_entry(&LogEntry {
+                    seq: seq as u64,
+                    record: LogRecord::Stdout(Bytes::from(*data)),
+                })
+            })
+            .collect();
+        let mut output = File::create(segment_path(&dir, 0)).unwrap();
+        // the last record is cut in half
+        output.write_all(&encoded[..encoded.len() - 1]).unwrap();
+
+        let mut shim = shims.open(job).unwrap();
+        // the pid belongs to a process that isn't the shim of the job
+        assert!(!shim.is_alive());
+        assert_eq!(shim.read_entries().unwrap().len(), 2);
+        assert!(shim.exit_status().unwrap().is_none());
+
+        output.write_all(&encoded[encoded.len() - 1..]).unwrap();
+        let mut next = File::create(segment_path(&dir, 1)).unwrap();
+        next.write_all(&encoded[..encoded.len() / 3]).unwrap();
+        fs::write(dir.join(EXIT_FILE), "256").unwrap();
+        let entries = shim.read_entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].seq, 0);
+        assert_eq!(shim.exit_status().unwrap().unwrap().code(), Some(1));
+        // the segment that was read completely is read again after a restart
+        // until the log store has written out all of its records
+        assert_eq!(shims.open(job).unwrap().segment, 0);
+        shim.release(2).unwrap();
+        assert!(segment_path(&dir, 0).exists());
+        shim.poll(Some(3)).unwrap();
+        assert!(!segment_path(&dir, 0).exists());
+        assert_eq!(shims.open(job).unwrap().segment, 1);
+
+        assert_eq!(parse_handshake("pid 42\n").unwrap(), 42);
+        assert_eq!(
+            parse_handshake("error 2 No such file or directory\n")
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotFound
+        );
+        fs::remove_dir_all(state_dir).unwrap();
+    }
+}
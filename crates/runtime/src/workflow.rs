@@ -0,0 +1,135 @@
+use crate::{JobId, JobRequest};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+// Label put on every job of a workflow, its value is the workflow id
+pub const WORKFLOW_LABEL: &str = "jobruntime/workflow";
+// Label with the name of the step the job runs
+pub const STEP_LABEL: &str = "jobruntime/step";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DependencyCondition {
+    // the job exited with code 0, otherwise the dependent job is cancelled
+    #[default]
+    Success,
+    // the job ended in any way, including being killed or cancelled
+    Finished,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dependency {
+    pub job: JobId,
+    pub condition: DependencyCondition,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepDependency {
+    pub step: String,
+    pub condition: DependencyCondition,
+}
+
+#[derive(Debug)]
+pub struct StartedWorkflow {
+    pub id: Uuid,
+    // name of every step and the job running it
+    pub jobs: Vec<(String, JobId)>,
+}
+
+#[derive(Debug, Default)]
+pub struct WorkflowStep {
+    pub name: String,
+    pub request: JobRequest,
+    pub after: Vec<StepDependency>,
+}
+
+// Checks that step names are unique and dependencies refer to existing steps without cycles.
+// Returns indexes of the steps in an order where every step comes after its dependencies.
+pub fn step_order(steps: &[WorkflowStep]) -> Result<Vec<usize>, String> {
+    if steps.is_empty() {
+        return Err(String::from("workflow has no steps"));
+    }
+    let mut indexes = HashMap::new();
+    for (index, step) in steps.iter().enumerate() {
+        if step.name.is_empty() {
+            return Err(format!("step {} has no name", index + 1));
+        }
+        if indexes.insert(step.name.as_str(), index).is_some() {
+            return Err(format!("step {} is defined more than once", step.name));
+        }
+    }
+
+    let mut waiting_for = vec![0; steps.len()];
+    let mut dependants = vec![vec![]; steps.len()];
+    for (index, step) in steps.iter().enumerate() {
+        for dependency in &step.after {
+            let before = *indexes.get(dependency.step.as_str()).ok_or_else(|| {
+                format!(
+                    "step {} depends on unknown step {}",
+                    step.name, dependency.step
+                )
+            })?;
+            waiting_for[index] += 1;
+            dependants[before].push(index);
+        }
+    }
+
+    let mut ready: VecDeque<usize> = (0..steps.len())
+        .filter(|index| waiting_for[*index] == 0)
+        .collect();
+    let mut order = Vec::with_capacity(steps.len());
+    while let Some(index) = ready.pop_front() {
+        order.push(index);
+        for dependant in &dependants[index] {
+            waiting_for[*dependant] -= 1;
+            if waiting_for[*dependant] == 0 {
+                ready.push_back(*dependant);
+            }
+        }
+    }
+    if order.len() != steps.len() {
+        let mut cycle: Vec<&str> = (0..steps.len())
+            .filter(|index| waiting_for[*index] > 0)
+            .map(|index| steps[index].name.as_str())
+            .collect();
+        cycle.sort_unstable();
+        return Err(format!("steps depend on each other: {}", cycle.join(", ")));
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn step(name: &str, after: &[&str]) -> WorkflowStep {
+        WorkflowStep {
+            name: String::from(name),
+            after: after
+                .iter()
+                .map(|step| StepDependency {
+                    step: String::from(*step),
+                    condition: DependencyCondition::Success,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn steps_are_ordered_after_their_dependencies() {
+        let steps = vec![
+            step("test", &["build"]),
+            step("package", &["build", "test"]),
+            step("build", &[]),
+        ];
+        assert_eq!(step_order(&steps), Ok(vec![2, 0, 1]));
+
+        let cycle = vec![step("a", &["b"]), step("b", &["a"]), step("c", &[])];
+        assert_eq!(
+            step_order(&cycle),
+            Err(String::from("steps depend on each other: a, b"))
+        );
+        assert!(step_order(&[step("a", &["missing"])]).is_err());
+        assert!(step_order(&[step("a", &[]), step("a", &[])]).is_err());
+    }
+}
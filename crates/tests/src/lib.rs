@@ -7,6 +7,7 @@ mod test {
     use tonic::transport::{Channel, ClientTlsConfig, Server, ServerTlsConfig};
 
     #[tokio::test]
+    #[allow(clippy::bool_assert_comparison)]
     async fn test_auth_with_different_ca_certs() -> anyhow::Result<()> {
         let server_cert = std::fs::read(concat!(
             env!("CARGO_MANIFEST_DIR"),
@@ -53,7 +54,7 @@ mod test {
                 .unwrap()
                 .add_service(JobRuntimeServer::with_interceptor(
                     runtime,
-                    jobdaemon::tls::intercept_extract_username_from_certificate,
+                    jobdaemon::tls::intercept_extract_principal_from_certificate,
                 ))
                 .serve("127.0.0.1:50050".parse().unwrap())
                 .await